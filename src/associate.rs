@@ -0,0 +1,466 @@
+//! Association of sidecars and editor derivatives with the photo they belong to.
+//!
+//! Photos (RAW and JPEG files) are keyed by their name without the last extension,
+//! so `2024.06.15_001.jpg` keeps its dots. Every other file is matched against the
+//! photos next to it:
+//!
+//! - sidecars (`IMG_1234.ORF.xmp`, `IMG_1234.xmp`, `.dop`, `.pp3`, `.cos`, ...) belong to
+//!   the photo they name, or to the group's RAW file when they name none
+//! - sidecars inside editor subfolders such as `CaptureOne/Settings153/` belong to the
+//!   photos in the folder above and keep that subfolder next to them
+//! - photos whose name is another photo's plus an editor suffix (`IMG_1234-Edit.jpg`,
+//!   `IMG_1234 (1).jpg`) are derivatives of that photo
+//!
+//! Sidecars with no photo next to them are orphans; they are still imported with the
+//! group of their name and listed in the run summary.
+
+use crate::filters::glob_to_regex;
+use crate::{is_jpeg_file, is_raw_file};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Extensions of files that describe a photo rather than being one.
+const SIDECAR_EXTENSIONS: [&str; 10] = [
+    "xmp", "dop", "pp3", "cos", "cot", "cof", "comask", "on1", "arp", "thm",
+];
+/// Suffixes editors append to the name of an exported or virtual copy. Plain numbered
+/// suffixes (`_1`, `-1`) are left to `--derivative-suffix`, as cameras and other tools
+/// name originals that way too.
+const DERIVATIVE_SUFFIXES: [&str; 2] = [r"-Edit(?:-\d+)?", r" \(\d+\)"];
+/// Subfolders editors keep their settings in, relative to the photos.
+const EDITOR_DIRS: [&str; 1] = ["CaptureOne/Settings*"];
+
+#[derive(Debug, Clone)]
+pub struct AssociationRules {
+    sidecar_exts: Vec<String>,
+    derivative_suffixes: Vec<Regex>,
+    editor_dirs: Vec<(usize, Regex)>,
+}
+
+impl AssociationRules {
+    /// Built-in rules extended with the given extensions, suffix regexes and folder globs.
+    pub fn new(
+        sidecar_exts: &[String],
+        derivative_suffixes: &[String],
+        editor_dirs: &[String],
+    ) -> Result<Self, String> {
+        let derivative_suffixes = DERIVATIVE_SUFFIXES
+            .iter()
+            .copied()
+            .chain(derivative_suffixes.iter().map(String::as_str))
+            .map(|suffix| {
+                Regex::new(&format!("(?:{})$", suffix))
+                    .map_err(|e| format!("invalid derivative suffix '{}': {}", suffix, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let editor_dirs = EDITOR_DIRS
+            .iter()
+            .copied()
+            .chain(editor_dirs.iter().map(String::as_str))
+            .map(|glob| {
+                let glob = glob.trim_matches('/');
+                (glob.split('/').count(), glob_to_regex(glob))
+            })
+            .collect();
+        Ok(AssociationRules {
+            sidecar_exts: SIDECAR_EXTENSIONS
+                .iter()
+                .map(|ext| ext.to_string())
+                .chain(
+                    sidecar_exts
+                        .iter()
+                        .map(|ext| ext.trim_start_matches('.').to_lowercase()),
+                )
+                .collect(),
+            derivative_suffixes,
+            editor_dirs,
+        })
+    }
+
+    fn is_sidecar(&self, filename: &str) -> bool {
+        extension(filename).is_some_and(|ext| self.sidecar_exts.contains(&ext.to_lowercase()))
+    }
+
+    /// Folder the photos of a file in an editor subfolder live in, and that subfolder.
+    fn editor_parent(&self, dir: &Path) -> Option<(PathBuf, PathBuf)> {
+        let components: Vec<&std::ffi::OsStr> = dir.iter().collect();
+        self.editor_dirs.iter().find_map(|(depth, glob)| {
+            let split = components.len().checked_sub(*depth)?;
+            let subdir: PathBuf = components[split..].iter().collect();
+            glob.is_match(&subdir.to_string_lossy())
+                .then(|| (components[..split].iter().collect(), subdir))
+        })
+    }
+}
+
+fn is_photo(filename: &str) -> bool {
+    is_raw_file(filename) || is_jpeg_file(filename)
+}
+
+fn extension(filename: &str) -> Option<&str> {
+    filename.rsplit_once('.').map(|(_, ext)| ext)
+}
+
+/// Name of a photo without its extension.
+pub fn photo_base(filename: &str) -> &str {
+    filename.rsplit_once('.').map_or(filename, |(stem, _)| stem)
+}
+
+/// Name of any file without its own extension and, for sidecars such as
+/// `IMG_1234.ORF.xmp`, without the photo extension before it.
+pub fn file_base(filename: &str) -> &str {
+    if is_photo(filename) {
+        return photo_base(filename);
+    }
+    let stem = photo_base(filename);
+    if is_photo(stem) {
+        photo_base(stem)
+    } else {
+        stem
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssociationKind {
+    Sidecar,
+    Derivative,
+}
+
+/// Photo an associated file belongs to and is placed next to.
+#[derive(Debug, Clone)]
+pub struct Association {
+    pub primary: PathBuf,
+    /// Editor subfolder the file is kept in, relative to the photo.
+    pub subdir: Option<PathBuf>,
+    pub kind: AssociationKind,
+}
+
+#[derive(Debug, Default)]
+pub struct Associations {
+    by_file: HashMap<PathBuf, Association>,
+    bases: HashMap<PathBuf, String>,
+    /// Base the files of a group are named with, for groups whose name is not it.
+    name_bases: HashMap<String, String>,
+    pub orphans: Vec<PathBuf>,
+}
+
+impl Associations {
+    pub fn get(&self, file: &Path) -> Option<&Association> {
+        self.by_file.get(file)
+    }
+
+    /// Group the file was put in.
+    pub fn base_of(&self, file: &Path) -> Option<&str> {
+        self.bases.get(file).map(String::as_str)
+    }
+
+    pub fn is_derivative(&self, file: &Path) -> bool {
+        self.get(file)
+            .is_some_and(|a| a.kind == AssociationKind::Derivative)
+    }
+
+    /// Base the files of `group` start with: the group's name, unless the group was
+    /// renamed to tell it apart from another one.
+    pub fn name_base<'a>(&'a self, group: &'a str) -> &'a str {
+        self.name_bases.get(group).map_or(group, String::as_str)
+    }
+
+    /// Moves the files of group `base` in `files` to the renamed group `group`.
+    pub fn rename_group(&mut self, files: &[PathBuf], base: &str, group: &str) {
+        for file in files {
+            self.bases.insert(file.clone(), group.to_string());
+        }
+        self.name_bases
+            .insert(group.to_string(), self.name_base(base).to_string());
+    }
+}
+
+/// Photos of each folder by base name.
+type PhotoIndex<'a> = HashMap<(&'a Path, &'a str), Vec<&'a PathBuf>>;
+
+/// The photo a group's sidecar or derivative is placed next to: RAW before JPEG.
+fn preferred(photos: &[&PathBuf]) -> PathBuf {
+    photos
+        .iter()
+        .find(|p| name_of(p).is_some_and(is_raw_file))
+        .unwrap_or(&photos[0])
+        .to_path_buf()
+}
+
+fn name_of(file: &Path) -> Option<&str> {
+    file.file_name().and_then(|n| n.to_str())
+}
+
+fn dir_of(file: &Path) -> &Path {
+    file.parent().unwrap_or(Path::new(""))
+}
+
+/// Splits `files` into groups keyed by base name and records what belongs to what.
+pub fn associate(
+    files: &[PathBuf],
+    rules: &AssociationRules,
+) -> (HashMap<String, Vec<PathBuf>>, Associations) {
+    let photos: Vec<&PathBuf> = files
+        .iter()
+        .filter(|f| name_of(f).is_some_and(is_photo))
+        .collect();
+    let mut index: PhotoIndex = HashMap::new();
+    for photo in &photos {
+        let name = name_of(photo).unwrap_or_default();
+        index
+            .entry((dir_of(photo), photo_base(name)))
+            .or_default()
+            .push(photo);
+    }
+
+    let mut groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let mut associations = Associations::default();
+    let mut add = |associations: &mut Associations,
+                   base: &str,
+                   file: &PathBuf,
+                   association: Option<Association>| {
+        groups
+            .entry(base.to_string())
+            .or_default()
+            .push(file.clone());
+        associations.bases.insert(file.clone(), base.to_string());
+        if let Some(association) = association {
+            associations.by_file.insert(file.clone(), association);
+        }
+    };
+
+    // Photos: originals keep their own base, derivatives join their original's group
+    let mut photo_groups: HashMap<&PathBuf, String> = HashMap::new();
+    for photo in &photos {
+        let name = name_of(photo).unwrap_or_default();
+        let base = photo_base(name);
+        let original = rules.derivative_suffixes.iter().find_map(|suffix| {
+            let found = suffix.find(base)?;
+            let original_base = &base[..found.start()];
+            let originals = index.get(&(dir_of(photo), original_base))?;
+            (!original_base.is_empty()).then(|| (original_base, preferred(originals)))
+        });
+        match original {
+            Some((original_base, primary)) => {
+                photo_groups.insert(photo, original_base.to_string());
+                add(
+                    &mut associations,
+                    original_base,
+                    photo,
+                    Some(Association {
+                        primary,
+                        subdir: None,
+                        kind: AssociationKind::Derivative,
+                    }),
+                );
+            }
+            None => {
+                photo_groups.insert(photo, base.to_string());
+                add(&mut associations, base, photo, None);
+            }
+        }
+    }
+
+    // Everything else
+    for file in files {
+        let Some(name) = name_of(file) else {
+            continue;
+        };
+        if is_photo(name) {
+            continue;
+        }
+        let base = file_base(name);
+        if !rules.is_sidecar(name) {
+            add(&mut associations, base, file, None);
+            continue;
+        }
+
+        let (dir, subdir) = match rules.editor_parent(dir_of(file)) {
+            Some((dir, subdir)) => (dir, Some(subdir)),
+            None => (dir_of(file).to_path_buf(), None),
+        };
+        // A sidecar naming its photo (NAME.ORF.xmp) belongs to exactly that file
+        let named_photo = dir.join(photo_base(name));
+        let primary = if is_photo(photo_base(name)) {
+            photos
+                .iter()
+                .find(|p| **p == &named_photo)
+                .map(|p| (*p).clone())
+        } else {
+            index.get(&(dir.as_path(), base)).map(|p| preferred(p))
+        };
+        match primary {
+            Some(primary) => {
+                let group = photo_groups
+                    .get(&primary)
+                    .cloned()
+                    .unwrap_or_else(|| base.to_string());
+                add(
+                    &mut associations,
+                    &group,
+                    file,
+                    Some(Association {
+                        primary,
+                        subdir,
+                        kind: AssociationKind::Sidecar,
+                    }),
+                );
+            }
+            None => {
+                associations.orphans.push(file.clone());
+                add(&mut associations, base, file, None);
+            }
+        }
+    }
+    associations.orphans.sort();
+    (groups, associations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn associate_names(names: &[&str], rules: &AssociationRules) -> Associations {
+        let files: Vec<PathBuf> = names
+            .iter()
+            .map(|name| Path::new("card").join(name))
+            .collect();
+        let (groups, associations) = associate(&files, rules);
+        assert_eq!(
+            groups.values().map(Vec::len).sum::<usize>(),
+            files.len(),
+            "every file is in one group"
+        );
+        associations
+    }
+
+    fn group(associations: &Associations, name: &str) -> String {
+        associations
+            .base_of(&Path::new("card").join(name))
+            .unwrap()
+            .to_string()
+    }
+
+    fn primary(associations: &Associations, name: &str) -> Option<PathBuf> {
+        associations
+            .get(&Path::new("card").join(name))
+            .map(|a| a.primary.clone())
+    }
+
+    fn default_rules() -> AssociationRules {
+        AssociationRules::new(&[], &[], &[]).unwrap()
+    }
+
+    #[test]
+    fn dotted_names_keep_their_dots() {
+        let associations = associate_names(
+            &["2024.06.15_001.jpg", "2024.06.15_001.jpg.xmp"],
+            &default_rules(),
+        );
+        assert_eq!(group(&associations, "2024.06.15_001.jpg"), "2024.06.15_001");
+        assert_eq!(
+            primary(&associations, "2024.06.15_001.jpg.xmp"),
+            Some(PathBuf::from("card/2024.06.15_001.jpg"))
+        );
+    }
+
+    #[test]
+    fn edits_are_derivatives_and_numbered_copies_are_opt_in() {
+        let names = [
+            "P6150123.ORF",
+            "P6150123.JPG",
+            "P6150123-Edit.jpg",
+            "P6150123-Edit-2.jpg",
+            "P6150123 (1).jpg",
+            "P6150123_1.jpg",
+        ];
+        let associations = associate_names(&names, &default_rules());
+        for name in [
+            "P6150123-Edit.jpg",
+            "P6150123-Edit-2.jpg",
+            "P6150123 (1).jpg",
+        ] {
+            assert!(
+                associations.is_derivative(&Path::new("card").join(name)),
+                "{}",
+                name
+            );
+            assert_eq!(group(&associations, name), "P6150123");
+            // Placed next to the RAW file
+            assert_eq!(
+                primary(&associations, name),
+                Some(PathBuf::from("card/P6150123.ORF"))
+            );
+        }
+        assert_eq!(group(&associations, "P6150123_1.jpg"), "P6150123_1");
+
+        let rules = AssociationRules::new(&[], &[r"_\d+".to_string()], &[]).unwrap();
+        let associations = associate_names(&names, &rules);
+        assert_eq!(group(&associations, "P6150123_1.jpg"), "P6150123");
+        assert!(associations.is_derivative(Path::new("card/P6150123_1.jpg")));
+    }
+
+    #[test]
+    fn sidecars_go_with_the_photo_they_name() {
+        let associations = associate_names(
+            &[
+                "P6150123.ORF",
+                "P6150123.JPG",
+                "P6150123.ORF.dop",
+                "P6150123.JPG.xmp",
+                "P6150123.xmp",
+                "CaptureOne/Settings153/P6150123.ORF.cos",
+            ],
+            &default_rules(),
+        );
+        assert_eq!(
+            primary(&associations, "P6150123.ORF.dop"),
+            Some(PathBuf::from("card/P6150123.ORF"))
+        );
+        assert_eq!(
+            primary(&associations, "P6150123.JPG.xmp"),
+            Some(PathBuf::from("card/P6150123.JPG"))
+        );
+        // Naming no photo, the sidecar goes with the RAW file
+        assert_eq!(
+            primary(&associations, "P6150123.xmp"),
+            Some(PathBuf::from("card/P6150123.ORF"))
+        );
+
+        let capture_one = associations
+            .get(Path::new("card/CaptureOne/Settings153/P6150123.ORF.cos"))
+            .unwrap();
+        assert_eq!(capture_one.primary, PathBuf::from("card/P6150123.ORF"));
+        assert_eq!(
+            capture_one.subdir,
+            Some(PathBuf::from("CaptureOne/Settings153"))
+        );
+        assert_eq!(capture_one.kind, AssociationKind::Sidecar);
+        assert!(associations.orphans.is_empty());
+    }
+
+    #[test]
+    fn sidecars_without_a_photo_are_orphans() {
+        let associations = associate_names(
+            &[
+                "P6150123.ORF",
+                "P6150124.ORF.xmp",
+                "P6150125.dop",
+                "notes.txt",
+            ],
+            &default_rules(),
+        );
+        assert_eq!(
+            associations.orphans,
+            [
+                PathBuf::from("card/P6150124.ORF.xmp"),
+                PathBuf::from("card/P6150125.dop")
+            ]
+        );
+        assert_eq!(group(&associations, "P6150124.ORF.xmp"), "P6150124");
+        assert!(primary(&associations, "P6150124.ORF.xmp").is_none());
+        // Files that aren't sidecars are never orphans
+        assert_eq!(group(&associations, "notes.txt"), "notes");
+    }
+}
@@ -0,0 +1,646 @@
+//! Shutter count history of every camera body, kept in `.photo_sorter/bodies.json` in
+//! the output directory.
+//!
+//! Every import reads the body's actuation counter from the EXIF it already has and
+//! adds one reading per body: how many shots were imported, when they were taken and
+//! the counter at the first and last of them. `photo_sorter bodies <library>` shows the
+//! history, and flags counters that go backwards, which usually means a replaced
+//! shutter, a reset, or a second body reporting the same serial number. Bodies without
+//! a serial number can't be told apart from others of their model, so their counters
+//! are not tracked.
+
+use crate::filters::tag_string;
+use crate::library::TIME_FORMAT;
+use crate::{CameraKey, get_camera_id, get_exif_capture_time};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Location of the history, relative to the library.
+pub const HISTORY_PATH: &str = ".photo_sorter/bodies.json";
+
+const HISTORY_VERSION: u64 = 1;
+
+/// Actuation counters in the order they are trusted. `ShutterCount` is the mechanical
+/// count on most makes; Fujifilm writes `ImageCount`, Olympus and OM System
+/// `ImageNumber`.
+const COUNTER_TAGS: [&str; 4] = [
+    "ShutterCount",
+    "MechanicalShutterCount",
+    "ImageCount",
+    "ImageNumber",
+];
+
+#[derive(clap::Args)]
+pub struct BodiesArgs {
+    /// Library (output directory of earlier imports)
+    library: PathBuf,
+    /// Print the history as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+/// Counter and the tag it was read from; the number may be followed by text.
+fn read_counter(exif: &Value) -> Option<(&'static str, u64)> {
+    COUNTER_TAGS.iter().find_map(|tag| {
+        let value = tag_string(exif, tag)?;
+        let digits: String = value.chars().take_while(char::is_ascii_digit).collect();
+        Some((*tag, digits.parse().ok()?))
+    })
+}
+
+fn format_time(time: Option<DateTime<Utc>>) -> Option<String> {
+    time.map(|t| t.format(TIME_FORMAT).to_string())
+}
+
+fn parse_time(value: &Value, key: &str) -> Option<DateTime<Utc>> {
+    let text = value.get(key)?.as_str()?;
+    Some(
+        NaiveDateTime::parse_from_str(text, TIME_FORMAT)
+            .ok()?
+            .and_utc(),
+    )
+}
+
+/// A counter lower than the shot taken before it.
+#[derive(Debug, Clone)]
+pub struct Backstep {
+    pub file: String,
+    pub captured: Option<DateTime<Utc>>,
+    pub previous: u64,
+    pub count: u64,
+}
+
+impl Backstep {
+    fn to_json(&self) -> Value {
+        json!({
+            "file": self.file,
+            "captured": format_time(self.captured),
+            "previous": self.previous,
+            "count": self.count,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Backstep {
+            file: value.get("file")?.as_str()?.to_string(),
+            captured: parse_time(value, "captured"),
+            previous: value.get("previous")?.as_u64()?,
+            count: value.get("count")?.as_u64()?,
+        })
+    }
+}
+
+/// The shots of one body imported by one run.
+#[derive(Debug, Clone)]
+pub struct Reading {
+    pub run_id: String,
+    pub shots: usize,
+    pub first_captured: Option<DateTime<Utc>>,
+    pub last_captured: Option<DateTime<Utc>>,
+    /// Counter tag and its value at the first and last shot that has one.
+    pub tag: Option<&'static str>,
+    pub first_count: Option<u64>,
+    pub last_count: Option<u64>,
+    /// Shots of this run whose counter went backwards.
+    pub backsteps: Vec<Backstep>,
+}
+
+impl Reading {
+    /// Actuations between the first and last shot of the run.
+    fn actuations(&self) -> Option<u64> {
+        Some(self.last_count?.saturating_sub(self.first_count?))
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "run_id": self.run_id,
+            "shots": self.shots,
+            "first_captured": format_time(self.first_captured),
+            "last_captured": format_time(self.last_captured),
+            "tag": self.tag,
+            "first_count": self.first_count,
+            "last_count": self.last_count,
+            "backsteps": self.backsteps.iter().map(Backstep::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Reading {
+            run_id: value.get("run_id")?.as_str()?.to_string(),
+            shots: value.get("shots")?.as_u64()? as usize,
+            first_captured: parse_time(value, "first_captured"),
+            last_captured: parse_time(value, "last_captured"),
+            tag: value
+                .get("tag")
+                .and_then(Value::as_str)
+                .and_then(|tag| COUNTER_TAGS.iter().find(|t| **t == tag).copied()),
+            first_count: value.get("first_count").and_then(Value::as_u64),
+            last_count: value.get("last_count").and_then(Value::as_u64),
+            backsteps: value
+                .get("backsteps")
+                .and_then(Value::as_array)
+                .map(|steps| steps.iter().filter_map(Backstep::from_json).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Whether `other` is the same shots imported again.
+    fn same_shots(&self, other: &Reading) -> bool {
+        (
+            self.first_captured,
+            self.last_captured,
+            self.first_count,
+            self.last_count,
+        ) == (
+            other.first_captured,
+            other.last_captured,
+            other.first_count,
+            other.last_count,
+        )
+    }
+}
+
+/// A counter going backwards between two imports.
+#[derive(Debug)]
+struct Reset<'a> {
+    before: &'a Reading,
+    after: &'a Reading,
+}
+
+#[derive(Debug, Default)]
+pub struct BodyHistory {
+    /// Readings by body, as "Model #Serial".
+    pub bodies: BTreeMap<String, Vec<Reading>>,
+}
+
+impl BodyHistory {
+    /// The library's history, empty when it has none or it can't be read.
+    pub fn load(library: &Path) -> Self {
+        let path = library.join(HISTORY_PATH);
+        let Ok(content) = fs::read_to_string(&path) else {
+            return BodyHistory::default();
+        };
+        let value: Value = match serde_json::from_str(&content) {
+            Ok(value) => value,
+            Err(e) => {
+                println!(
+                    "Warning: ignoring unreadable body history {}: {}",
+                    path.display(),
+                    e
+                );
+                return BodyHistory::default();
+            }
+        };
+        let bodies = value
+            .get("bodies")
+            .and_then(Value::as_object)
+            .map(|bodies| {
+                bodies
+                    .iter()
+                    .map(|(body, readings)| {
+                        let readings = readings
+                            .as_array()
+                            .map(|r| r.iter().filter_map(Reading::from_json).collect())
+                            .unwrap_or_default();
+                        (body.clone(), readings)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        BodyHistory { bodies }
+    }
+
+    pub fn save(&self, library: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let path = library.join(HISTORY_PATH);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let history = json!({ "version": HISTORY_VERSION, "bodies": self.to_json() });
+        fs::write(path, serde_json::to_string_pretty(&history)?)?;
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        Value::Object(
+            self.bodies
+                .iter()
+                .map(|(body, readings)| {
+                    let readings: Vec<Value> = readings.iter().map(Reading::to_json).collect();
+                    (body.clone(), Value::Array(readings))
+                })
+                .collect::<Map<_, _>>(),
+        )
+    }
+}
+
+/// Readings of a body in shot order.
+fn by_capture_time(readings: &[Reading]) -> Vec<&Reading> {
+    let mut sorted: Vec<&Reading> = readings.iter().collect();
+    sorted.sort_by_key(|r| r.first_captured);
+    sorted
+}
+
+/// Imports whose counter starts below where the import shot before it ended.
+fn resets(readings: &[Reading]) -> Vec<Reset<'_>> {
+    let sorted: Vec<&Reading> = by_capture_time(readings)
+        .into_iter()
+        .filter(|r| r.first_count.is_some())
+        .collect();
+    sorted
+        .windows(2)
+        .filter(|pair| {
+            // Overlapping imports are the same card read twice, not a reset
+            pair[1].first_captured > pair[0].last_captured
+                && pair[1].first_count < pair[0].last_count
+        })
+        .map(|pair| Reset {
+            before: pair[0],
+            after: pair[1],
+        })
+        .collect()
+}
+
+/// One reading per body from the shots a run imported (one source file and its
+/// group's EXIF per shot).
+fn readings(run_id: &str, shots: &[(&PathBuf, &Value)]) -> BTreeMap<String, Reading> {
+    type Shot<'a> = (
+        Option<DateTime<Utc>>,
+        &'a PathBuf,
+        Option<(&'static str, u64)>,
+    );
+    let mut by_body: BTreeMap<String, Vec<Shot>> = BTreeMap::new();
+    for (file, exif) in shots {
+        if tag_string(exif, "Model").is_none() {
+            continue;
+        }
+        let has_serial = tag_string(exif, "SerialNumber")
+            .or_else(|| tag_string(exif, "InternalSerialNumber"))
+            .is_some();
+        by_body
+            .entry(get_camera_id(exif, file, CameraKey::Serial))
+            .or_default()
+            .push((
+                get_exif_capture_time(exif),
+                *file,
+                read_counter(exif).filter(|_| has_serial),
+            ));
+    }
+
+    by_body
+        .into_iter()
+        .map(|(body, mut shots)| {
+            shots.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+            let counted: Vec<&Shot> = shots.iter().filter(|(_, _, c)| c.is_some()).collect();
+            let count = |shot: &&Shot| shot.2.map(|(_, count)| count);
+            let backsteps = counted
+                .windows(2)
+                .filter_map(|pair| {
+                    let (previous, current) = (count(&pair[0])?, count(&pair[1])?);
+                    (current < previous).then(|| Backstep {
+                        file: pair[1].1.display().to_string(),
+                        captured: pair[1].0,
+                        previous,
+                        count: current,
+                    })
+                })
+                .collect();
+            let reading = Reading {
+                run_id: run_id.to_string(),
+                shots: shots.len(),
+                first_captured: shots.iter().find_map(|s| s.0),
+                last_captured: shots.iter().rev().find_map(|s| s.0),
+                tag: counted.first().and_then(|s| s.2).map(|(tag, _)| tag),
+                first_count: counted.first().and_then(count),
+                last_count: counted.last().and_then(count),
+                backsteps,
+            };
+            (body, reading)
+        })
+        .collect()
+}
+
+/// Adds this run's readings to the library's body history and warns about counters
+/// that went backwards. Returns the readings for the run report.
+pub fn record_import(
+    library: &Path,
+    run_id: &str,
+    shots: &[(&PathBuf, &Value)],
+    dry_run: bool,
+) -> Result<Vec<(String, Reading)>, Box<dyn std::error::Error>> {
+    let new_readings = readings(run_id, shots);
+    if new_readings.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut history = BodyHistory::load(library);
+    for (body, reading) in &new_readings {
+        match (reading.tag, reading.last_count) {
+            (Some(tag), Some(count)) => println!(
+                "{}: {} shot(s), {} at {}{}",
+                body,
+                reading.shots,
+                tag,
+                count,
+                reading
+                    .actuations()
+                    .map(|n| format!(" (+{} in this import)", n))
+                    .unwrap_or_default()
+            ),
+            _ => println!("{}: {} shot(s), no shutter count", body, reading.shots),
+        }
+        for step in &reading.backsteps {
+            println!(
+                "  Warning: counter went back from {} to {} at {}",
+                step.previous, step.count, step.file
+            );
+        }
+
+        let readings = history.bodies.entry(body.clone()).or_default();
+        readings.retain(|existing| !existing.same_shots(reading));
+        readings.push(reading.clone());
+        for reset in resets(readings) {
+            if reset.after.run_id == run_id || reset.before.run_id == run_id {
+                println!(
+                    "  Warning: counter went back from {} ({}) to {} ({})",
+                    reset.before.last_count.unwrap_or_default(),
+                    reset.before.run_id,
+                    reset.after.first_count.unwrap_or_default(),
+                    reset.after.run_id
+                );
+            }
+        }
+    }
+    if dry_run {
+        println!("Would record {} body reading(s).", new_readings.len());
+    } else {
+        history.save(library)?;
+    }
+    Ok(new_readings.into_iter().collect())
+}
+
+fn body_json(readings: &[Reading]) -> Value {
+    let sorted = by_capture_time(readings);
+    let latest = sorted
+        .iter()
+        .rev()
+        .find_map(|r| Some((r.tag?, r.last_count?)));
+    let anomalies: Vec<Value> = sorted
+        .iter()
+        .flat_map(|r| {
+            r.backsteps.iter().map(|step| {
+                json!({
+                    "kind": "within-import",
+                    "run_id": r.run_id,
+                    "file": step.file,
+                    "captured": format_time(step.captured),
+                    "previous": step.previous,
+                    "count": step.count,
+                })
+            })
+        })
+        .chain(resets(readings).iter().map(|reset| {
+            json!({
+                "kind": "between-imports",
+                "run_id": reset.after.run_id,
+                "previous_run_id": reset.before.run_id,
+                "captured": format_time(reset.after.first_captured),
+                "previous": reset.before.last_count,
+                "count": reset.after.first_count,
+            })
+        }))
+        .collect();
+    json!({
+        "shots": readings.iter().map(|r| r.shots).sum::<usize>(),
+        "tag": latest.map(|(tag, _)| tag),
+        "count": latest.map(|(_, count)| count),
+        "readings": sorted.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+        "anomalies": anomalies,
+    })
+}
+
+fn print_body(body: &str, readings: &[Reading]) {
+    let sorted = by_capture_time(readings);
+    let shots: usize = readings.iter().map(|r| r.shots).sum();
+    match sorted
+        .iter()
+        .rev()
+        .find_map(|r| Some((r.tag?, r.last_count?)))
+    {
+        Some((tag, count)) => println!(
+            "\n{}: {} at {}, {} shot(s) imported in {} run(s)",
+            body,
+            tag,
+            count,
+            shots,
+            readings.len()
+        ),
+        None => println!(
+            "\n{}: no shutter count, {} shot(s) imported in {} run(s)",
+            body,
+            shots,
+            readings.len()
+        ),
+    }
+    let date = |time: Option<DateTime<Utc>>| {
+        time.map_or_else(|| "?".to_string(), |t| t.format("%Y-%m-%d").to_string())
+    };
+    let mut previous: Option<u64> = None;
+    for reading in &sorted {
+        let counts = match (reading.first_count, reading.last_count) {
+            (Some(first), Some(last)) => {
+                let since = previous
+                    .filter(|p| first >= *p)
+                    .map(|p| format!(", +{} since the import before", last - p))
+                    .unwrap_or_default();
+                format!("count {}-{}{}", first, last, since)
+            }
+            _ => "no count".to_string(),
+        };
+        println!(
+            "  {} to {}  {:>5} shot(s)  {}  [{}]",
+            date(reading.first_captured),
+            date(reading.last_captured),
+            reading.shots,
+            counts,
+            reading.run_id
+        );
+        previous = reading.last_count.or(previous);
+        for step in &reading.backsteps {
+            println!(
+                "    ! counter went back from {} to {} at {}",
+                step.previous, step.count, step.file
+            );
+        }
+    }
+    for reset in resets(readings) {
+        println!(
+            "  ! counter went back from {} ({}) to {} ({})",
+            reset.before.last_count.unwrap_or_default(),
+            reset.before.run_id,
+            reset.after.first_count.unwrap_or_default(),
+            reset.after.run_id
+        );
+    }
+}
+
+pub fn run(args: &BodiesArgs) -> Result<(), Box<dyn std::error::Error>> {
+    if !args.library.is_dir() {
+        return Err(format!("Library {} is not a directory", args.library.display()).into());
+    }
+    let history = BodyHistory::load(&args.library);
+    if args.json {
+        let bodies: Map<String, Value> = history
+            .bodies
+            .iter()
+            .map(|(body, readings)| (body.clone(), body_json(readings)))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&Value::Object(bodies))?);
+    } else if history.bodies.is_empty() {
+        println!(
+            "No body history in {}; it is recorded by imports into the library.",
+            args.library.display()
+        );
+    } else {
+        for (body, readings) in &history.bodies {
+            print_body(body, readings);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shot(model: &str, serial: Option<&str>, time: &str, count: u64) -> Value {
+        let mut exif = json!({
+            "Model": model,
+            "DateTimeOriginal": time,
+            "ImageNumber": count,
+        });
+        if let Some(serial) = serial {
+            exif["SerialNumber"] = json!(serial);
+        }
+        exif
+    }
+
+    fn read(run_id: &str, shots: &[(PathBuf, Value)]) -> BTreeMap<String, Reading> {
+        let shots: Vec<(&PathBuf, &Value)> =
+            shots.iter().map(|(file, exif)| (file, exif)).collect();
+        readings(run_id, &shots)
+    }
+
+    #[test]
+    fn counter_may_be_followed_by_text() {
+        assert_eq!(
+            read_counter(&json!({ "ShutterCount": "12345 (mechanical)" })),
+            Some(("ShutterCount", 12345))
+        );
+        assert_eq!(
+            read_counter(&json!({ "ImageNumber": 40, "ShutterCount": "n/a", "ImageCount": 7 })),
+            Some(("ImageCount", 7))
+        );
+        assert_eq!(read_counter(&json!({ "ShutterCount": "" })), None);
+    }
+
+    #[test]
+    fn backsteps_within_an_import_are_flagged() {
+        let shots = [
+            ("P3.ORF", "2024:06:15 10:02:00", 102),
+            ("P1.ORF", "2024:06:15 10:00:00", 100),
+            ("P2.ORF", "2024:06:15 10:01:00", 101),
+            ("P4.ORF", "2024:06:15 10:03:00", 5),
+        ]
+        .map(|(file, time, count)| (PathBuf::from(file), shot("OM-1", Some("BHP1"), time, count)));
+        let readings = read("run-1", &shots);
+        let reading = &readings["OM-1 #BHP1"];
+        assert_eq!(reading.shots, 4);
+        assert_eq!(reading.tag, Some("ImageNumber"));
+        assert_eq!(
+            (reading.first_count, reading.last_count),
+            (Some(100), Some(5))
+        );
+        assert_eq!(reading.backsteps.len(), 1);
+        assert_eq!(reading.backsteps[0].file, "P4.ORF");
+        assert_eq!(
+            (reading.backsteps[0].previous, reading.backsteps[0].count),
+            (102, 5)
+        );
+    }
+
+    #[test]
+    fn bodies_without_a_serial_are_not_counted() {
+        let shots = [("A.ORF", 500), ("B.ORF", 10)].map(|(file, count)| {
+            (
+                PathBuf::from(file),
+                shot("OM-1", None, "2024:06:15 10:00:00", count),
+            )
+        });
+        let readings = read("run-1", &shots);
+        let reading = &readings["OM-1"];
+        assert_eq!(reading.shots, 2);
+        assert_eq!((reading.tag, reading.first_count), (None, None));
+        assert!(reading.backsteps.is_empty());
+    }
+
+    fn reading(run_id: &str, first: (&str, u64), last: (&str, u64)) -> Reading {
+        let time = |t: &str| {
+            NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M")
+                .unwrap()
+                .and_utc()
+        };
+        Reading {
+            run_id: run_id.to_string(),
+            shots: 10,
+            first_captured: Some(time(first.0)),
+            last_captured: Some(time(last.0)),
+            tag: Some("ShutterCount"),
+            first_count: Some(first.1),
+            last_count: Some(last.1),
+            backsteps: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn resets_between_imports_are_flagged() {
+        let readings = [
+            reading("run-2", ("2024-06-15 10:00", 50), ("2024-06-16 18:00", 60)),
+            reading(
+                "run-1",
+                ("2024-05-01 10:00", 1000),
+                ("2024-05-03 18:00", 1200),
+            ),
+            reading("run-3", ("2024-07-01 10:00", 61), ("2024-07-02 18:00", 90)),
+        ];
+        let resets = resets(&readings);
+        assert_eq!(resets.len(), 1);
+        assert_eq!(resets[0].before.run_id, "run-1");
+        assert_eq!(resets[0].after.run_id, "run-2");
+    }
+
+    #[test]
+    fn reimporting_a_card_is_no_reset() {
+        let first = reading(
+            "run-1",
+            ("2024-05-01 10:00", 1000),
+            ("2024-05-03 18:00", 1200),
+        );
+        let again = reading(
+            "run-2",
+            ("2024-05-01 10:00", 1000),
+            ("2024-05-03 18:00", 1200),
+        );
+        assert!(first.same_shots(&again));
+
+        // Part of the card read again, with newer shots added, overlaps the first import
+        let more = reading(
+            "run-3",
+            ("2024-05-02 09:00", 1100),
+            ("2024-05-05 18:00", 1300),
+        );
+        assert!(!first.same_shots(&more));
+        assert!(resets(&[first, more]).is_empty());
+    }
+}
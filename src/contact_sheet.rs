@@ -0,0 +1,327 @@
+//! Static HTML contact sheet of one import run, written with `--contact-sheet`.
+//!
+//! The page lists every photo group the run copied or moved, by day and by detected
+//! sequence, with a small thumbnail, camera, lens and exposure, and links to the files
+//! in the library. Thumbnails are embedded in the page, so it is a single file that
+//! can be opened from the output folder or sent around.
+
+use crate::associate::{Associations, file_base};
+use crate::filters::{lens_name, tag_string};
+use crate::thumbnails::{self, escape_path};
+use crate::{SequenceRecord, get_exif_capture_time, is_jpeg_file, is_raw_file};
+use chrono::{DateTime, Utc};
+use image::codecs::jpeg::JpegEncoder;
+use rayon::prelude::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Long edge of the thumbnails embedded in the page.
+const THUMBNAIL_PIXELS: u32 = 320;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;background:#f4f4f4;color:#222}
+h2{border-bottom:1px solid #ccc;padding-bottom:.2em}
+.grid{display:flex;flex-wrap:wrap;gap:12px}
+figure{margin:0;width:240px;background:#fff;padding:8px;border-radius:4px;box-shadow:0 1px 3px #0003}
+figure img,.missing{width:240px;height:180px;object-fit:contain;background:#ddd;display:block}
+figcaption{font-size:12px;margin-top:6px;line-height:1.4;word-wrap:break-word}
+section.sequence{margin:12px 0;padding:8px;border-left:4px solid #888}
+.badge{display:inline-block;padding:1px 6px;border-radius:3px;color:#fff;font-size:12px;background:#666}
+.badge.hdr{background:#c0392b}.badge.burst{background:#2980b9}.badge.focus{background:#27ae60}
+.badge.panorama{background:#8e44ad}.badge.pixel-shift{background:#d35400}";
+
+/// One photo group as shown on the sheet.
+struct Card {
+    base: String,
+    time: Option<DateTime<Utc>>,
+    /// Library files of the group, relative to the output folder.
+    files: Vec<PathBuf>,
+    /// Photo the thumbnail is made from, and the EXIF of its group.
+    photo: Option<PathBuf>,
+    exif: Option<Value>,
+    thumbnail: Option<String>,
+}
+
+/// Sections of one day: loose photos under `None`, then every sequence folder.
+type Day = BTreeMap<Option<String>, Vec<Card>>;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Camera, lens and exposure of a group, e.g. "E-M1 · 12-40mm · 1/250 s f/4.0 ISO 200".
+fn caption(exif: &Value) -> String {
+    let exposure: Vec<String> = [
+        tag_string(exif, "ExposureTime").map(|t| format!("{} s", t)),
+        tag_string(exif, "FNumber").map(|f| format!("f/{}", f)),
+        tag_string(exif, "ISO").map(|iso| format!("ISO {}", iso)),
+        tag_string(exif, "FocalLength"),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    [
+        tag_string(exif, "Model"),
+        lens_name(exif),
+        (!exposure.is_empty()).then(|| exposure.join(" ")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(" · ")
+}
+
+fn thumbnail(photo: &Path, exif: Option<&Value>) -> Option<String> {
+    let image = thumbnails::load(photo, exif.and_then(thumbnails::orientation_from_exif))
+        .ok()?
+        .thumbnail(THUMBNAIL_PIXELS, THUMBNAIL_PIXELS);
+    let mut jpeg = Vec::new();
+    image
+        .to_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 75))
+        .ok()?;
+    Some(format!("data:image/jpeg;base64,{}", base64(&jpeg)))
+}
+
+/// Photo to thumbnail a group with: its JPEG, else its RAW file, else a derivative.
+fn preferred_photo(files: &[(&PathBuf, &PathBuf)], associations: &Associations) -> Option<PathBuf> {
+    let rank = |(source, _): &&(&PathBuf, &PathBuf)| {
+        let name = source.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        match (
+            associations.is_derivative(source),
+            is_jpeg_file(name),
+            is_raw_file(name),
+        ) {
+            (false, true, _) => 0,
+            (false, _, true) => 1,
+            (true, true, _) | (true, _, true) => 2,
+            _ => 3,
+        }
+    };
+    let (_, dest) = files.iter().filter(|f| rank(f) < 3).min_by_key(rank)?;
+    Some(dest.to_path_buf())
+}
+
+fn card_html(card: &Card) -> String {
+    let image = match &card.thumbnail {
+        Some(data) => format!("<img src=\"{}\" alt=\"{}\">", data, escape(&card.base)),
+        None => "<div class=\"missing\"></div>".to_string(),
+    };
+    let links: Vec<String> = card
+        .files
+        .iter()
+        .map(|file| {
+            format!(
+                "<a href=\"{}\">{}</a>",
+                escape(&escape_path(file)),
+                escape(&file.file_name().unwrap_or_default().to_string_lossy())
+            )
+        })
+        .collect();
+    let mut lines = vec![format!(
+        "<strong>{}</strong>{}",
+        escape(&card.base),
+        card.time
+            .map(|t| format!(" {}", t.format("%H:%M:%S")))
+            .unwrap_or_default()
+    )];
+    if let Some(exif) = &card.exif {
+        let caption = caption(exif);
+        if !caption.is_empty() {
+            lines.push(escape(&caption));
+        }
+    }
+    lines.push(links.join(" "));
+    format!(
+        "<figure>{}<figcaption>{}</figcaption></figure>\n",
+        match card.files.first() {
+            Some(first) => format!("<a href=\"{}\">{}</a>", escape(&escape_path(first)), image),
+            None => image,
+        },
+        lines.join("<br>")
+    )
+}
+
+/// Writes `<run id>.html` into the output folder for the files of the final plan.
+pub fn write_contact_sheet(
+    files: &[(PathBuf, PathBuf)],
+    associations: &Associations,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    records: &[SequenceRecord],
+    output_dir: &Path,
+    run_id: &str,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = output_dir.join(format!("{}.html", run_id));
+    if dry_run {
+        println!("Would write contact sheet {}", path.display());
+        return Ok(());
+    }
+    let labels: HashMap<&str, &str> = records
+        .iter()
+        .map(|record| (record.seq_type.folder_name(), record.seq_type.label()))
+        .collect();
+
+    // Files by day, sequence folder and group
+    type Members<'a> = Vec<(&'a PathBuf, &'a PathBuf)>;
+    let mut grouped: BTreeMap<(String, Option<String>, String), Members> = BTreeMap::new();
+    // Files outside the YYYY/MM/DD folders of RAW/ and JPEG/ have no day to go under
+    let mut left_out: Vec<&PathBuf> = Vec::new();
+    for (source, dest) in files {
+        let parts: Vec<String> = ["RAW", "JPEG"]
+            .iter()
+            .find_map(|tree| dest.strip_prefix(output_dir.join(tree)).ok())
+            .map(|relative| {
+                relative
+                    .iter()
+                    .map(|part| part.to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        if parts.len() < 4 {
+            left_out.push(dest);
+            continue;
+        }
+        let day = parts[..3].join("/");
+        let sequence =
+            (parts.len() > 4 && labels.contains_key(parts[3].as_str())).then(|| parts[3].clone());
+        let name = source.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let base = associations
+            .base_of(source)
+            .unwrap_or_else(|| file_base(name))
+            .to_string();
+        grouped
+            .entry((day, sequence, base))
+            .or_default()
+            .push((source, dest));
+    }
+
+    let mut cards: Vec<((String, Option<String>), Card)> = grouped
+        .into_iter()
+        .map(|((day, sequence, base), members)| {
+            let exif = exif_cache.get(&base).map(|(_, exif)| exif.clone());
+            let mut files: Vec<PathBuf> = members
+                .iter()
+                .filter_map(|(_, dest)| dest.strip_prefix(output_dir).ok())
+                .map(Path::to_path_buf)
+                .collect();
+            files.sort();
+            let card = Card {
+                time: exif.as_ref().and_then(get_exif_capture_time),
+                photo: preferred_photo(&members, associations),
+                base,
+                files,
+                exif,
+                thumbnail: None,
+            };
+            ((day, sequence), card)
+        })
+        .collect();
+    cards.par_iter_mut().for_each(|(_, card)| {
+        card.thumbnail = card
+            .photo
+            .as_deref()
+            .and_then(|photo| thumbnail(photo, card.exif.as_ref()));
+    });
+
+    let mut days: BTreeMap<String, Day> = BTreeMap::new();
+    let group_count = cards.len();
+    for ((day, sequence), card) in cards {
+        days.entry(day)
+            .or_default()
+            .entry(sequence)
+            .or_default()
+            .push(card);
+    }
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title><style>{1}</style></head><body>\n<h1>{0}</h1>\n<p>{2} photo group(s) in {3} day(s).</p>\n",
+        escape(run_id),
+        STYLE,
+        group_count,
+        days.len()
+    );
+    for (day, sections) in &mut days {
+        html.push_str(&format!("<h2>{}</h2>\n", escape(day)));
+        for (sequence, cards) in sections {
+            cards.sort_by(|a, b| (a.time, &a.base).cmp(&(b.time, &b.base)));
+            let grid: String = cards.iter().map(card_html).collect();
+            match sequence {
+                None => html.push_str(&format!("<div class=\"grid\">\n{}</div>\n", grid)),
+                Some(folder) => {
+                    let label = labels.get(folder.as_str()).copied().unwrap_or("");
+                    html.push_str(&format!(
+                        "<section class=\"sequence\"><h3><span class=\"badge {}\">{}</span> {} ({} frame(s))</h3>\n<div class=\"grid\">\n{}</div></section>\n",
+                        label.to_lowercase(),
+                        label,
+                        escape(folder),
+                        cards.len(),
+                        grid
+                    ));
+                }
+            }
+        }
+    }
+    html.push_str("</body></html>\n");
+
+    fs::write(&path, html)?;
+    println!("Contact sheet written to {}", path.display());
+    if !left_out.is_empty() {
+        println!(
+            "{} file(s) outside the day folders are not on the contact sheet:",
+            left_out.len()
+        );
+        for dest in left_out {
+            println!("  {}", dest.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_the_last_chunk() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Many"), "TWFueQ==");
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd, 0xfc]), "//79/A==");
+    }
+
+    #[test]
+    fn escape_covers_markup_and_attributes() {
+        assert_eq!(
+            escape(r#"Tom & Jerry <b>"run"</b>"#),
+            "Tom &amp; Jerry &lt;b&gt;&quot;run&quot;&lt;/b&gt;"
+        );
+        // Already escaped text is escaped again, not passed through
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+    }
+}
@@ -0,0 +1,667 @@
+//! Vendor-specific readers for the EXIF tags that describe bursts and brackets.
+//!
+//! Every manufacturer encodes "this frame is shot N of a bracket" differently, so
+//! `detect_sequences` asks the detector matching the file's `Make` instead of
+//! reading tags directly. Unknown makes fall back to [`GenericDetector`], which
+//! keeps the original Olympus-style string parsing.
+
+use regex::Regex;
+use serde_json::Value;
+use std::sync::LazyLock;
+
+static SPECIAL_MODE_SEQUENCE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"Sequence:\s*(\d+)").expect("Invalid regex for burst sequence"));
+static DRIVE_MODE_SHOT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"Shot\s+(\d+)").expect("Invalid regex for HDR sequence"));
+static PIXEL_SHIFT_SHOT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:[Ss]hot\s+)?(\d+)\s*(?:of|/)\s*\d+")
+        .expect("Invalid regex for pixel shift shot")
+});
+static NIKON_BRACKET_PROGRAM_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\d+)F").expect("Invalid regex for Nikon bracket program"));
+
+/// What the vendor drive-mode tags say about how a frame was shot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveKind {
+    Continuous,
+    Single,
+    Unknown,
+}
+
+pub trait SequenceDetector: Sync {
+    /// Short vendor name, used in reports.
+    fn name(&self) -> &'static str;
+
+    /// Whether this detector understands files whose EXIF `Make` is `make`.
+    fn matches_make(&self, make: &str) -> bool;
+
+    /// 1-based position of the frame inside an exposure bracket, if it is part of one.
+    fn bracket_shot(&self, exif: &Value) -> Option<u32>;
+
+    /// 1-based position of the frame inside a numbered burst, if the camera records one.
+    fn burst_index(&self, exif: &Value) -> Option<u32>;
+
+    /// 1-based position of the frame inside a focus bracket, if it is part of one.
+    fn focus_bracket_shot(&self, _exif: &Value) -> Option<u32> {
+        None
+    }
+
+    /// Whether the frame is an in-camera focus-stacked result.
+    fn is_focus_stacked(&self, _exif: &Value) -> bool {
+        false
+    }
+
+    /// 1-based position of the frame inside an in-camera assisted panorama.
+    fn panorama_shot(&self, _exif: &Value) -> Option<u32> {
+        None
+    }
+
+    /// 1-based position of the frame inside a multi-file pixel-shift capture.
+    fn pixel_shift_shot(&self, _exif: &Value) -> Option<u32> {
+        None
+    }
+
+    /// Whether the file is a single-file high-res / pixel-shift composite.
+    fn is_pixel_shift_composite(&self, _exif: &Value) -> bool {
+        false
+    }
+
+    /// Whether the frame was shot in continuous or single-frame drive.
+    fn drive_kind(&self, exif: &Value) -> DriveKind {
+        generic_drive_kind(exif)
+    }
+}
+
+/// Olympus and OM System bodies: `DriveMode` "AE Auto Bracketing, Shot N",
+/// `DriveMode` "Focus Bracketing, Shot N", `StackedImage` and `SpecialMode` "Sequence: N".
+pub struct OlympusDetector;
+
+/// Canon bodies: `BracketMode` "AEB" with `BracketShotNumber`/`AEBBracketValue`.
+/// Canon does not number burst frames, those are left to the time-gap pass.
+pub struct CanonDetector;
+
+/// Nikon bodies: `ShootingMode` "... Exposure Bracketing" with the shot position
+/// reconstructed from `ExposureBracketValue`, `BracketProgram` and `BracketSequence`.
+pub struct NikonDetector;
+
+/// Sony bodies: `ReleaseMode` "Continuous" / "Exposure Bracketing" with `SequenceNumber`,
+/// and `PixelShiftShots` for pixel shift multi shooting.
+pub struct SonyDetector;
+
+/// Fujifilm bodies: `AEBracketing`/`AutoBracketing` with `SequenceNumber`.
+pub struct FujifilmDetector;
+
+/// Panasonic bodies: `BurstMode` "On" or "Auto Exposure Bracketing (AEB)" with `SequenceNumber`.
+pub struct PanasonicDetector;
+
+/// Pentax / Ricoh bodies: only Pixel Shift Resolution composites are recognised.
+pub struct PentaxDetector;
+
+/// Fallback for makes without a dedicated detector.
+pub struct GenericDetector;
+
+static DETECTORS: [&dyn SequenceDetector; 7] = [
+    &OlympusDetector,
+    &CanonDetector,
+    &NikonDetector,
+    &SonyDetector,
+    &FujifilmDetector,
+    &PanasonicDetector,
+    &PentaxDetector,
+];
+
+/// Picks the detector for a file based on its EXIF `Make`.
+pub fn detector_for(exif: &Value) -> &'static dyn SequenceDetector {
+    let make = tag_str(exif, "Make").unwrap_or_default().to_lowercase();
+    DETECTORS
+        .iter()
+        .copied()
+        .find(|detector| detector.matches_make(&make))
+        .unwrap_or(&GenericDetector)
+}
+
+fn tag_str(exif: &Value, name: &str) -> Option<String> {
+    match exif.get(name)? {
+        Value::String(s) => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn tag_u32(exif: &Value, name: &str) -> Option<u32> {
+    match exif.get(name)? {
+        Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn tag_f64(exif: &Value, name: &str) -> Option<f64> {
+    match exif.get(name)? {
+        Value::Number(n) => n.as_f64(),
+        // Values such as "+1", "-2/3" or "0.7"
+        Value::String(s) => parse_ev(s),
+        _ => None,
+    }
+}
+
+fn parse_ev(value: &str) -> Option<f64> {
+    let value = value.trim().trim_start_matches('+');
+    if let Some((num, den)) = value.split_once('/') {
+        let num: f64 = num.trim().parse().ok()?;
+        let den: f64 = den.trim().parse().ok()?;
+        (den != 0.0).then(|| num / den)
+    } else {
+        value.parse().ok()
+    }
+}
+
+/// Sequence numbers where 0 means "single shot".
+fn nonzero(value: Option<u32>) -> Option<u32> {
+    value.filter(|n| *n > 0)
+}
+
+fn generic_drive_kind(exif: &Value) -> DriveKind {
+    let mut kind = DriveKind::Unknown;
+    for tag in [
+        "DriveMode",
+        "ContinuousDrive",
+        "ShootingMode",
+        "ReleaseMode",
+        "BurstMode",
+    ] {
+        let Some(value) = tag_str(exif, tag) else {
+            continue;
+        };
+        let value = value.to_lowercase();
+        if value.contains("continuous") || value.contains("burst") || value == "on" {
+            return DriveKind::Continuous;
+        }
+        if value.contains("single") || value == "normal" || value == "off" {
+            kind = DriveKind::Single;
+        }
+    }
+    kind
+}
+
+fn drive_mode_shot(exif: &Value) -> Option<u32> {
+    let drive_mode = tag_str(exif, "DriveMode")?;
+    let is_bracket = drive_mode.contains("Bracket")
+        || drive_mode.contains("Bracketing")
+        || drive_mode.contains("HDR");
+    if !is_bracket || drive_mode.contains("Focus") {
+        return None;
+    }
+    let captures = DRIVE_MODE_SHOT_RE.captures(&drive_mode)?;
+    captures.get(1)?.as_str().parse().ok()
+}
+
+fn drive_mode_focus_shot(exif: &Value) -> Option<u32> {
+    let drive_mode = tag_str(exif, "DriveMode")?;
+    if !drive_mode.contains("Focus Bracketing") {
+        return None;
+    }
+    let captures = DRIVE_MODE_SHOT_RE.captures(&drive_mode)?;
+    captures.get(1)?.as_str().parse().ok()
+}
+
+fn is_stacked_image(exif: &Value) -> bool {
+    // "Focus-stacked (8 images)"; other values are "No" or HDR/handheld results
+    tag_str(exif, "StackedImage").is_some_and(|s| s.contains("Focus"))
+}
+
+fn special_mode_sequence(exif: &Value) -> Option<u32> {
+    let special_mode = tag_str(exif, "SpecialMode")?;
+    // Panorama-assist frames number their shots the same way
+    if special_mode.starts_with("Panorama") {
+        return None;
+    }
+    let captures = SPECIAL_MODE_SEQUENCE_RE.captures(&special_mode)?;
+    nonzero(captures.get(1)?.as_str().parse().ok())
+}
+
+fn special_mode_panorama(exif: &Value) -> Option<u32> {
+    // "Panorama, Sequence: 2, Panorama: Left to Right"
+    let special_mode = tag_str(exif, "SpecialMode")?;
+    if !special_mode.starts_with("Panorama") {
+        return None;
+    }
+    let captures = SPECIAL_MODE_SEQUENCE_RE.captures(&special_mode)?;
+    nonzero(captures.get(1)?.as_str().parse().ok())
+}
+
+impl SequenceDetector for OlympusDetector {
+    fn name(&self) -> &'static str {
+        "olympus"
+    }
+
+    fn matches_make(&self, make: &str) -> bool {
+        make.contains("olympus") || make.contains("om digital")
+    }
+
+    fn bracket_shot(&self, exif: &Value) -> Option<u32> {
+        drive_mode_shot(exif)
+    }
+
+    fn burst_index(&self, exif: &Value) -> Option<u32> {
+        special_mode_sequence(exif)
+    }
+
+    fn focus_bracket_shot(&self, exif: &Value) -> Option<u32> {
+        drive_mode_focus_shot(exif)
+    }
+
+    fn is_focus_stacked(&self, exif: &Value) -> bool {
+        is_stacked_image(exif)
+    }
+
+    fn panorama_shot(&self, exif: &Value) -> Option<u32> {
+        special_mode_panorama(exif)
+    }
+}
+
+impl SequenceDetector for CanonDetector {
+    fn name(&self) -> &'static str {
+        "canon"
+    }
+
+    fn matches_make(&self, make: &str) -> bool {
+        make.contains("canon")
+    }
+
+    fn bracket_shot(&self, exif: &Value) -> Option<u32> {
+        let mode = tag_str(exif, "BracketMode")?;
+        if !mode.contains("AEB") {
+            return None;
+        }
+        nonzero(tag_u32(exif, "BracketShotNumber")).or_else(|| {
+            // Older bodies only record the offset; Canon shoots 0, -, + by default
+            let value = tag_f64(exif, "AEBBracketValue")?;
+            Some(if value == 0.0 {
+                1
+            } else if value < 0.0 {
+                2
+            } else {
+                3
+            })
+        })
+    }
+
+    fn burst_index(&self, _exif: &Value) -> Option<u32> {
+        None
+    }
+}
+
+impl SequenceDetector for NikonDetector {
+    fn name(&self) -> &'static str {
+        "nikon"
+    }
+
+    fn matches_make(&self, make: &str) -> bool {
+        make.contains("nikon")
+    }
+
+    fn bracket_shot(&self, exif: &Value) -> Option<u32> {
+        let shooting_mode = tag_str(exif, "ShootingMode")?;
+        if !shooting_mode.contains("Exposure Bracketing") {
+            return None;
+        }
+        let program = tag_str(exif, "BracketProgram")?;
+        let frames: i64 = NIKON_BRACKET_PROGRAM_RE
+            .captures(&program)?
+            .get(1)?
+            .as_str()
+            .parse()
+            .ok()?;
+        let value = tag_f64(exif, "ExposureBracketValue")?;
+        let increment = tag_f64(exif, "BracketIncrementEV")
+            .filter(|step| *step > 0.0)
+            .unwrap_or(1.0);
+        nikon_shot_position(
+            frames,
+            (value / increment).round() as i64,
+            tag_str(exif, "BracketSequence").as_deref(),
+        )
+    }
+
+    fn burst_index(&self, _exif: &Value) -> Option<u32> {
+        None
+    }
+}
+
+/// Turns a bracket offset (in steps) into a 1-based shot position for a
+/// bracket of `frames` frames shot in `sequence` order ("0,-,+" or "-,0,+").
+fn nikon_shot_position(frames: i64, offset: i64, sequence: Option<&str>) -> Option<u32> {
+    let half = frames / 2;
+    if frames < 2 || offset.abs() > half {
+        return None;
+    }
+    let position = match sequence.map(|s| s.replace(' ', "")) {
+        Some(s) if s.starts_with("-,0") => offset + half + 1,
+        // Nikon's default order: metered frame first, then under, then over
+        _ if offset == 0 => 1,
+        _ if offset < 0 => offset + half + 2,
+        _ => offset + half + 1,
+    };
+    u32::try_from(position).ok()
+}
+
+impl SequenceDetector for SonyDetector {
+    fn name(&self) -> &'static str {
+        "sony"
+    }
+
+    fn matches_make(&self, make: &str) -> bool {
+        make.contains("sony")
+    }
+
+    fn bracket_shot(&self, exif: &Value) -> Option<u32> {
+        let release_mode = tag_str(exif, "ReleaseMode")?;
+        if !release_mode.contains("Exposure Bracketing") {
+            return None;
+        }
+        nonzero(tag_u32(exif, "SequenceNumber"))
+    }
+
+    fn burst_index(&self, exif: &Value) -> Option<u32> {
+        let release_mode = tag_str(exif, "ReleaseMode")?;
+        if !release_mode.contains("Continuous") || release_mode.contains("Bracketing") {
+            return None;
+        }
+        nonzero(tag_u32(exif, "SequenceNumber"))
+    }
+
+    fn pixel_shift_shot(&self, exif: &Value) -> Option<u32> {
+        // "Shot 2 of 4" / "2/16"; a plain count is not enough to order the frames
+        let shots = tag_str(exif, "PixelShiftShots")?;
+        let captures = PIXEL_SHIFT_SHOT_RE.captures(&shots)?;
+        nonzero(captures.get(1)?.as_str().parse().ok())
+    }
+}
+
+impl SequenceDetector for FujifilmDetector {
+    fn name(&self) -> &'static str {
+        "fujifilm"
+    }
+
+    fn matches_make(&self, make: &str) -> bool {
+        make.contains("fujifilm")
+    }
+
+    fn bracket_shot(&self, exif: &Value) -> Option<u32> {
+        let bracketing =
+            tag_str(exif, "AEBracketing").or_else(|| tag_str(exif, "AutoBracketing"))?;
+        if bracketing.eq_ignore_ascii_case("off") {
+            return None;
+        }
+        nonzero(tag_u32(exif, "SequenceNumber"))
+    }
+
+    fn burst_index(&self, exif: &Value) -> Option<u32> {
+        if self.bracket_shot(exif).is_some() {
+            return None;
+        }
+        nonzero(tag_u32(exif, "SequenceNumber"))
+    }
+
+    fn drive_kind(&self, exif: &Value) -> DriveKind {
+        // DriveSettings is "Single", "CL", "CH" or "CH Boost" on recent bodies
+        match tag_str(exif, "DriveSettings") {
+            Some(drive) if drive.starts_with("CH") || drive.starts_with("CL") => {
+                DriveKind::Continuous
+            }
+            Some(drive) if drive.contains("Single") => DriveKind::Single,
+            _ => generic_drive_kind(exif),
+        }
+    }
+}
+
+impl SequenceDetector for PanasonicDetector {
+    fn name(&self) -> &'static str {
+        "panasonic"
+    }
+
+    fn matches_make(&self, make: &str) -> bool {
+        make.contains("panasonic")
+    }
+
+    fn bracket_shot(&self, exif: &Value) -> Option<u32> {
+        let burst_mode = tag_str(exif, "BurstMode")?;
+        if !burst_mode.contains("Bracketing") && !burst_mode.contains("AEB") {
+            return None;
+        }
+        nonzero(tag_u32(exif, "SequenceNumber"))
+    }
+
+    fn burst_index(&self, exif: &Value) -> Option<u32> {
+        let burst_mode = tag_str(exif, "BurstMode")?;
+        if burst_mode != "On" && burst_mode != "Unlimited" {
+            return None;
+        }
+        nonzero(tag_u32(exif, "SequenceNumber"))
+    }
+}
+
+impl SequenceDetector for GenericDetector {
+    fn name(&self) -> &'static str {
+        "generic"
+    }
+
+    fn matches_make(&self, _make: &str) -> bool {
+        true
+    }
+
+    fn bracket_shot(&self, exif: &Value) -> Option<u32> {
+        drive_mode_shot(exif)
+    }
+
+    fn burst_index(&self, exif: &Value) -> Option<u32> {
+        special_mode_sequence(exif)
+    }
+
+    fn focus_bracket_shot(&self, exif: &Value) -> Option<u32> {
+        drive_mode_focus_shot(exif)
+    }
+
+    fn is_focus_stacked(&self, exif: &Value) -> bool {
+        is_stacked_image(exif)
+    }
+
+    fn panorama_shot(&self, exif: &Value) -> Option<u32> {
+        special_mode_panorama(exif)
+    }
+}
+
+impl SequenceDetector for PentaxDetector {
+    fn name(&self) -> &'static str {
+        "pentax"
+    }
+
+    fn matches_make(&self, make: &str) -> bool {
+        make.contains("pentax") || make.contains("ricoh")
+    }
+
+    fn bracket_shot(&self, _exif: &Value) -> Option<u32> {
+        None
+    }
+
+    fn burst_index(&self, _exif: &Value) -> Option<u32> {
+        None
+    }
+
+    fn is_pixel_shift_composite(&self, exif: &Value) -> bool {
+        tag_str(exif, "PixelShiftResolution").is_some_and(|s| s.eq_ignore_ascii_case("on"))
+            || tag_str(exif, "DriveMode2").is_some_and(|s| s.contains("Pixel Shift"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame `file` of an `exiftool -j` fixture.
+    fn frame(fixture: &str, file: &str) -> Value {
+        let frames: Vec<Value> = serde_json::from_str(fixture).expect("invalid fixture");
+        frames
+            .into_iter()
+            .find(|frame| frame["SourceFile"] == file)
+            .unwrap_or_else(|| panic!("no frame {} in fixture", file))
+    }
+
+    const OLYMPUS: &str = include_str!("../tests/fixtures/exiftool/olympus.json");
+    const CANON: &str = include_str!("../tests/fixtures/exiftool/canon.json");
+    const NIKON: &str = include_str!("../tests/fixtures/exiftool/nikon.json");
+    const SONY: &str = include_str!("../tests/fixtures/exiftool/sony.json");
+    const FUJIFILM: &str = include_str!("../tests/fixtures/exiftool/fujifilm.json");
+    const PANASONIC: &str = include_str!("../tests/fixtures/exiftool/panasonic.json");
+    const UNKNOWN: &str = include_str!("../tests/fixtures/exiftool/unknown.json");
+
+    #[test]
+    fn detector_is_chosen_by_make() {
+        for (fixture, file, name) in [
+            (OLYMPUS, "P6150101.ORF", "olympus"),
+            (OLYMPUS, "P6150140.ORF", "olympus"),
+            (CANON, "IMG_0101.CR3", "canon"),
+            (NIKON, "DSC_0101.NEF", "nikon"),
+            (SONY, "DSC00102.ARW", "sony"),
+            (FUJIFILM, "DSCF0103.RAF", "fujifilm"),
+            (PANASONIC, "P1000102.RW2", "panasonic"),
+            (UNKNOWN, "B0000101.3FR", "generic"),
+        ] {
+            assert_eq!(detector_for(&frame(fixture, file)).name(), name, "{}", file);
+        }
+    }
+
+    #[test]
+    fn olympus_brackets_bursts_and_stacks() {
+        let hdr = frame(OLYMPUS, "P6150103.ORF");
+        let detector = detector_for(&hdr);
+        assert_eq!(detector.bracket_shot(&hdr), Some(3));
+        assert_eq!(detector.burst_index(&hdr), None);
+        assert_eq!(detector.focus_bracket_shot(&hdr), None);
+
+        let burst = frame(OLYMPUS, "P6150120.ORF");
+        assert_eq!(detector.bracket_shot(&burst), None);
+        assert_eq!(detector.burst_index(&burst), Some(4));
+        assert_eq!(detector.drive_kind(&burst), DriveKind::Continuous);
+
+        let focus = frame(OLYMPUS, "P6150130.ORF");
+        assert_eq!(detector.bracket_shot(&focus), None);
+        assert_eq!(detector.focus_bracket_shot(&focus), Some(2));
+        assert!(!detector.is_focus_stacked(&focus));
+        assert!(detector.is_focus_stacked(&frame(OLYMPUS, "P6150139.JPG")));
+
+        let panorama = frame(OLYMPUS, "P6150140.ORF");
+        assert_eq!(detector.panorama_shot(&panorama), Some(2));
+        assert_eq!(detector.burst_index(&panorama), None);
+    }
+
+    #[test]
+    fn canon_brackets() {
+        let numbered = frame(CANON, "IMG_0101.CR3");
+        let detector = detector_for(&numbered);
+        assert_eq!(detector.bracket_shot(&numbered), Some(2));
+        // Without a shot number the offset gives the position: 0, -, +
+        assert_eq!(
+            detector.bracket_shot(&frame(CANON, "IMG_0201.CR2")),
+            Some(3)
+        );
+
+        let burst = frame(CANON, "IMG_0301.CR3");
+        assert_eq!(detector.bracket_shot(&burst), None);
+        assert_eq!(detector.burst_index(&burst), None);
+        assert_eq!(detector.drive_kind(&burst), DriveKind::Continuous);
+    }
+
+    #[test]
+    fn nikon_brackets() {
+        let detector = detector_for(&frame(NIKON, "DSC_0101.NEF"));
+        let positions: Vec<Option<u32>> = ["DSC_0101.NEF", "DSC_0102.NEF", "DSC_0103.NEF"]
+            .iter()
+            .map(|file| detector.bracket_shot(&frame(NIKON, file)))
+            .collect();
+        assert_eq!(positions, [Some(1), Some(2), Some(3)]);
+        // Under-first order, in 0.7 EV steps
+        assert_eq!(
+            detector.bracket_shot(&frame(NIKON, "DSC_0203.NEF")),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn sony_brackets_bursts_and_pixel_shift() {
+        let hdr = frame(SONY, "DSC00102.ARW");
+        let detector = detector_for(&hdr);
+        assert_eq!(detector.bracket_shot(&hdr), Some(2));
+        assert_eq!(detector.burst_index(&hdr), None);
+
+        let burst = frame(SONY, "DSC00205.ARW");
+        assert_eq!(detector.bracket_shot(&burst), None);
+        assert_eq!(detector.burst_index(&burst), Some(5));
+
+        let pixel_shift = frame(SONY, "DSC00302.ARW");
+        assert_eq!(detector.pixel_shift_shot(&pixel_shift), Some(2));
+        assert_eq!(detector.burst_index(&pixel_shift), None);
+    }
+
+    #[test]
+    fn fujifilm_brackets_and_bursts() {
+        let hdr = frame(FUJIFILM, "DSCF0103.RAF");
+        let detector = detector_for(&hdr);
+        assert_eq!(detector.bracket_shot(&hdr), Some(3));
+        assert_eq!(detector.burst_index(&hdr), None);
+
+        let burst = frame(FUJIFILM, "DSCF0204.RAF");
+        assert_eq!(detector.bracket_shot(&burst), None);
+        assert_eq!(detector.burst_index(&burst), Some(4));
+        assert_eq!(detector.drive_kind(&burst), DriveKind::Continuous);
+    }
+
+    #[test]
+    fn panasonic_brackets_and_bursts() {
+        let hdr = frame(PANASONIC, "P1000102.RW2");
+        let detector = detector_for(&hdr);
+        assert_eq!(detector.bracket_shot(&hdr), Some(2));
+        assert_eq!(detector.burst_index(&hdr), None);
+
+        let burst = frame(PANASONIC, "P1000206.RW2");
+        assert_eq!(detector.bracket_shot(&burst), None);
+        assert_eq!(detector.burst_index(&burst), Some(6));
+    }
+
+    #[test]
+    fn single_shots_are_in_no_sequence() {
+        for (fixture, file) in [
+            (OLYMPUS, "P6150150.ORF"),
+            (CANON, "IMG_0401.CR3"),
+            (NIKON, "DSC_0301.NEF"),
+            (SONY, "DSC00401.ARW"),
+            (FUJIFILM, "DSCF0301.RAF"),
+            (PANASONIC, "P1000301.RW2"),
+            (UNKNOWN, "B0000101.3FR"),
+        ] {
+            let exif = frame(fixture, file);
+            let detector = detector_for(&exif);
+            assert_eq!(detector.bracket_shot(&exif), None, "{}", file);
+            assert_eq!(detector.burst_index(&exif), None, "{}", file);
+            assert_eq!(detector.focus_bracket_shot(&exif), None, "{}", file);
+            assert_eq!(detector.panorama_shot(&exif), None, "{}", file);
+            assert_eq!(detector.pixel_shift_shot(&exif), None, "{}", file);
+            assert_ne!(
+                detector.drive_kind(&exif),
+                DriveKind::Continuous,
+                "{}",
+                file
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_make_reads_olympus_style_tags() {
+        let mut exif = frame(UNKNOWN, "B0000101.3FR");
+        exif["DriveMode"] = Value::from("AE Auto Bracketing, Shot 2");
+        let detector = detector_for(&exif);
+        assert_eq!(detector.name(), "generic");
+        assert_eq!(detector.bracket_shot(&exif), Some(2));
+    }
+}
@@ -0,0 +1,391 @@
+//! Clustering of photos into events (trips, outings, sessions).
+//!
+//! Photos from all cameras are put in capture order and a new event starts wherever
+//! the gap to the previous photo exceeds `--event-gap-hours`, or, with
+//! `--event-distance-km`, wherever consecutive positions are further apart than that.
+//! Events are named after `--event-name` and can be renamed before anything is
+//! copied, from a names file or interactively.
+
+use crate::get_exif_capture_time;
+use crate::places::{Place, distance_km};
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Tokens available in `--event-name`.
+const EVENT_NAME_TOKENS: [&str; 5] = ["date", "time", "n", "city", "country"];
+
+static EVENT_NAME_TOKEN_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{(\w+)\}").expect("Invalid regex for event name token"));
+
+/// Format of event ids, the start of the event's first photo.
+const EVENT_ID_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+pub fn parse_event_name(template: &str) -> Result<String, String> {
+    for captures in EVENT_NAME_TOKEN_RE.captures_iter(template) {
+        let token = &captures[1];
+        if !EVENT_NAME_TOKENS.contains(&token) {
+            return Err(format!(
+                "unknown token {{{}}} (expected one of {})",
+                token,
+                EVENT_NAME_TOKENS.map(|t| format!("{{{}}}", t)).join(", ")
+            ));
+        }
+    }
+    Ok(template.to_string())
+}
+
+#[derive(Debug, Clone)]
+pub struct EventOptions {
+    pub gap: Duration,
+    pub max_distance_km: Option<f64>,
+    pub name_template: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Stable id used in names files: the capture time of the first photo.
+    pub id: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub bases: Vec<String>,
+    /// Most common place among the event's photos.
+    pub place: Option<Place>,
+    pub name: String,
+}
+
+/// Detected events and which event every group belongs to.
+#[derive(Debug, Default)]
+pub struct Events {
+    pub events: Vec<Event>,
+    by_base: HashMap<String, usize>,
+}
+
+impl Events {
+    pub fn event_for(&self, base: &str) -> Option<&Event> {
+        self.by_base.get(base).map(|&index| &self.events[index])
+    }
+}
+
+fn event_name(template: &str, event: &Event, index_in_day: usize) -> String {
+    let name = EVENT_NAME_TOKEN_RE.replace_all(template, |captures: &regex::Captures| {
+        match &captures[1] {
+            "date" => event.start.format("%Y-%m-%d").to_string(),
+            "time" => event.start.format("%H%M").to_string(),
+            "n" => index_in_day.to_string(),
+            "city" => event
+                .place
+                .as_ref()
+                .map(|p| p.city.clone())
+                .unwrap_or_default(),
+            "country" => event
+                .place
+                .as_ref()
+                .map(|p| p.country.clone())
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    });
+    let name = name.replace('/', "-");
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Clusters the groups into events. Groups without a capture time belong to none.
+pub fn detect_events(
+    groups: &HashMap<String, Vec<PathBuf>>,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    positions: &HashMap<String, (f64, f64)>,
+    places: &HashMap<String, Place>,
+    options: &EventOptions,
+) -> Events {
+    let mut timeline: Vec<(DateTime<Utc>, &String)> = groups
+        .keys()
+        .filter_map(|base| {
+            let (_, exif) = exif_cache.get(base)?;
+            Some((get_exif_capture_time(exif)?, base))
+        })
+        .collect();
+    timeline.sort();
+
+    let mut clusters: Vec<Vec<(DateTime<Utc>, &String)>> = Vec::new();
+    let mut last_position: Option<(f64, f64)> = None;
+    for (time, base) in timeline {
+        let position = positions.get(base).copied();
+        let split = match clusters.last().and_then(|c| c.last()) {
+            None => true,
+            Some((previous, _)) => {
+                time - *previous > options.gap
+                    || options.max_distance_km.is_some_and(|max| {
+                        last_position
+                            .zip(position)
+                            .is_some_and(|(a, b)| distance_km(a.0, a.1, b.0, b.1) > max)
+                    })
+            }
+        };
+        if split {
+            clusters.push(Vec::new());
+            last_position = None;
+        }
+        if position.is_some() {
+            last_position = position;
+        }
+        clusters
+            .last_mut()
+            .expect("cluster was just pushed")
+            .push((time, base));
+    }
+
+    let mut events = Events::default();
+    let mut per_day: HashMap<String, usize> = HashMap::new();
+    for cluster in clusters {
+        let mut place_counts: Vec<(&Place, usize)> = Vec::new();
+        for (_, base) in &cluster {
+            if let Some(place) = places.get(*base) {
+                match place_counts.iter_mut().find(|(p, _)| *p == place) {
+                    Some((_, count)) => *count += 1,
+                    None => place_counts.push((place, 1)),
+                }
+            }
+        }
+        // max_by_key keeps the last maximum; reversing keeps the first place seen on a tie
+        let place = place_counts
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .map(|(place, _)| (*place).clone());
+
+        let start = cluster[0].0;
+        let mut event = Event {
+            id: start.format(EVENT_ID_FORMAT).to_string(),
+            start,
+            end: cluster[cluster.len() - 1].0,
+            bases: cluster.iter().map(|(_, base)| (*base).clone()).collect(),
+            place,
+            name: String::new(),
+        };
+        let index_in_day = per_day
+            .entry(start.format("%Y-%m-%d").to_string())
+            .and_modify(|n| *n += 1)
+            .or_insert(1);
+        event.name = event_name(&options.name_template, &event, *index_in_day);
+
+        let index = events.events.len();
+        for base in &event.bases {
+            events.by_base.insert(base.clone(), index);
+        }
+        events.events.push(event);
+    }
+    events
+}
+
+fn describe(event: &Event) -> String {
+    format!(
+        "{} - {} ({} photo group(s))",
+        event.start.format("%Y-%m-%d %H:%M"),
+        event.end.format("%Y-%m-%d %H:%M"),
+        event.bases.len()
+    )
+}
+
+pub fn print_events(events: &Events) {
+    println!("Detected {} event(s):", events.events.len());
+    for event in &events.events {
+        println!("  {} {}", describe(event), event.name);
+    }
+}
+
+/// Applies the names in `path` (`ID<TAB>NAME` lines), or, when the file doesn't exist
+/// yet, writes the detected events there so they can be renamed for the next run.
+/// Returns whether the file was written.
+pub fn apply_names_file(
+    events: &mut Events,
+    path: &Path,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        let mut content = String::from(
+            "# Event names: edit the second column and run the import again.\n# id\tname\tspan\n",
+        );
+        for event in &events.events {
+            content.push_str(&format!(
+                "{}\t{}\t{}\n",
+                event.id,
+                event.name,
+                describe(event)
+            ));
+        }
+        fs::write(path, content)?;
+        println!(
+            "Wrote {} event name(s) to {}",
+            events.events.len(),
+            path.display()
+        );
+        return Ok(true);
+    }
+
+    let content = fs::read_to_string(path)?;
+    let names: HashMap<&str, &str> = content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some((fields.next()?.trim(), fields.next()?.trim()))
+        })
+        .collect();
+    let mut unnamed = 0;
+    for event in &mut events.events {
+        match names.get(event.id.as_str()) {
+            Some(name) => event.name = name.replace('/', "-"),
+            None => unnamed += 1,
+        }
+    }
+    if unnamed > 0 {
+        println!(
+            "{} event(s) not listed in {} keep their generated names.",
+            unnamed,
+            path.display()
+        );
+    }
+    Ok(false)
+}
+
+/// Asks for a new name for every event on the terminal; an empty answer keeps the name.
+pub fn rename_interactively(events: &mut Events) -> Result<(), Box<dyn std::error::Error>> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        println!("Warning: not renaming events, stdin is not a terminal");
+        return Ok(());
+    }
+    let mut lines = stdin.lock().lines();
+    for event in &mut events.events {
+        print!("{} [{}]: ", describe(event), event.name);
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let name = line?;
+        if !name.trim().is_empty() {
+            event.name = name.trim().replace('/', "-");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+    use serde_json::json;
+
+    /// Groups and EXIF cache of a test import.
+    type Import = (
+        HashMap<String, Vec<PathBuf>>,
+        HashMap<String, (PathBuf, Value)>,
+    );
+
+    /// Import of photos taken at `shots` (base, capture time).
+    fn import(shots: &[(&str, &str)]) -> Import {
+        let mut groups = HashMap::new();
+        let mut exif_cache = HashMap::new();
+        for (base, time) in shots {
+            let file = PathBuf::from(format!("{}.ORF", base));
+            groups.insert(base.to_string(), vec![file.clone()]);
+            exif_cache.insert(
+                base.to_string(),
+                (file, json!({ "DateTimeOriginal": time })),
+            );
+        }
+        (groups, exif_cache)
+    }
+
+    fn options() -> EventOptions {
+        EventOptions {
+            gap: Duration::hours(8),
+            max_distance_km: Some(50.0),
+            name_template: "{date} #{n} {city}".to_string(),
+        }
+    }
+
+    fn lisbon() -> Place {
+        Place {
+            city: "Lisbon".to_string(),
+            country: "Portugal".to_string(),
+        }
+    }
+
+    #[test]
+    fn events_split_on_gaps_and_distance() {
+        let (mut groups, exif_cache) = import(&[
+            ("P1", "2024:06:15 09:30:00"),
+            ("P2", "2024:06:15 12:00:00"),
+            ("P3", "2024:06:15 15:00:00"),
+            ("P4", "2024:06:16 01:00:00"),
+            ("P5", "2024:06:16 02:00:00"),
+        ]);
+        groups.insert("NODATE".to_string(), vec![PathBuf::from("NODATE.ORF")]);
+        let positions = HashMap::from([
+            ("P1".to_string(), (38.72, -9.14)),
+            ("P3".to_string(), (38.71, -9.13)),
+            ("P4".to_string(), (38.72, -9.14)),
+            // Porto, 270 km north
+            ("P5".to_string(), (41.15, -8.61)),
+        ]);
+        let places = HashMap::from([("P2".to_string(), lisbon()), ("P3".to_string(), lisbon())]);
+
+        let events = detect_events(&groups, &exif_cache, &positions, &places, &options());
+
+        let bases: Vec<Vec<&str>> = events
+            .events
+            .iter()
+            .map(|e| e.bases.iter().map(String::as_str).collect())
+            .collect();
+        assert_eq!(bases, [vec!["P1", "P2", "P3"], vec!["P4"], vec!["P5"]]);
+        assert_eq!(events.events[0].id, "2024-06-15T09:30:00");
+        assert_eq!(events.events[0].name, "2024-06-15 #1 Lisbon");
+        // Numbered within the day, and no place leaves no trailing space
+        assert_eq!(events.events[1].name, "2024-06-16 #1");
+        assert_eq!(events.events[2].name, "2024-06-16 #2");
+        assert_eq!(events.event_for("P2").unwrap().id, "2024-06-15T09:30:00");
+        assert!(events.event_for("NODATE").is_none());
+    }
+
+    #[test]
+    fn names_file_is_written_then_applied() {
+        let dir = scratch_dir("event-names");
+        let path = dir.join("events.tsv");
+        let (groups, exif_cache) =
+            import(&[("P1", "2024:06:15 09:30:00"), ("P2", "2024:06:16 09:30:00")]);
+        let detect = || {
+            detect_events(
+                &groups,
+                &exif_cache,
+                &HashMap::new(),
+                &HashMap::new(),
+                &options(),
+            )
+        };
+
+        let mut events = detect();
+        assert!(apply_names_file(&mut events, &path).unwrap());
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.contains("2024-06-15T09:30:00\t2024-06-15 #1\t"));
+
+        // Rename the first event, drop the second
+        let edited: String = written
+            .lines()
+            .filter(|line| !line.starts_with("2024-06-16"))
+            .map(|line| line.replace("\t2024-06-15 #1\t", "\tBeach/Day\t") + "\n")
+            .collect();
+        fs::write(&path, edited).unwrap();
+
+        let mut events = detect();
+        assert!(!apply_names_file(&mut events, &path).unwrap());
+        assert_eq!(events.events[0].name, "Beach-Day");
+        assert_eq!(events.events[1].name, "2024-06-16 #1");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,600 @@
+//! Selection of which groups get imported, applied between sequence detection and
+//! planning so sequences are still detected on the complete set of frames. Excluded
+//! files are the exception: they are dropped before anything is read from them.
+
+use crate::associate::Associations;
+use crate::sources::Source;
+use crate::{get_exif_date, is_jpeg_file, is_raw_file};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+static XMP_RATING_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"xmp:Rating(?:="|>)\s*(-?\d+)"#).expect("Invalid regex for XMP rating")
+});
+static XMP_LABEL_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"xmp:Label(?:="([^"]*)"|>([^<]*)<)"#).expect("Invalid regex for XMP label")
+});
+
+/// Tags some makers record the in-camera protect flag in.
+const PROTECT_TAGS: [&str; 2] = ["Protect", "Protected"];
+
+/// Keeper filters based on ratings, labels and the in-camera protect flag.
+#[derive(Debug, Clone, Default)]
+pub struct RatingFilter {
+    pub min_rating: Option<i64>,
+    pub only_protected: bool,
+    /// Accepted labels (case-insensitive); empty accepts everything.
+    pub labels: Vec<String>,
+}
+
+impl RatingFilter {
+    pub fn is_active(&self) -> bool {
+        self.min_rating.is_some() || self.only_protected || !self.labels.is_empty()
+    }
+}
+
+/// Which kind of photo files `--only` keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FileKind {
+    Raw,
+    Jpeg,
+}
+
+impl FileKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            FileKind::Raw => "RAW",
+            FileKind::Jpeg => "JPEG",
+        }
+    }
+}
+
+/// Selection of a trip or a body out of a mixed archive.
+#[derive(Debug, Clone, Default)]
+pub struct SelectionFilter {
+    /// First capture day to import (inclusive).
+    pub since: Option<NaiveDate>,
+    /// Last capture day to import (inclusive).
+    pub until: Option<NaiveDate>,
+    /// Glob matched against the camera `Model` (or "Make Model"), case-insensitive.
+    pub camera: Option<String>,
+    /// Glob matched against the lens name, case-insensitive.
+    pub lens: Option<String>,
+    pub only: Option<FileKind>,
+}
+
+/// A group left out of the import and why.
+#[derive(Debug, Clone)]
+pub struct SkippedGroup {
+    pub base: String,
+    pub reason: String,
+}
+
+/// Rating and label of a group, XMP sidecars taking precedence over the in-camera values.
+#[derive(Debug, Default)]
+struct Keeper {
+    rating: Option<i64>,
+    label: Option<String>,
+    protected: bool,
+}
+
+fn read_keeper(files: &[PathBuf], exif: Option<&Value>, associations: &Associations) -> Keeper {
+    let mut keeper = Keeper::default();
+
+    if let Some(exif) = exif {
+        keeper.rating = match exif.get("Rating") {
+            Some(Value::Number(n)) => n.as_i64(),
+            Some(Value::String(s)) => s.trim().parse().ok(),
+            _ => None,
+        };
+        keeper.label = exif
+            .get("Label")
+            .and_then(|v| v.as_str())
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+    }
+
+    for file in files {
+        let is_xmp = file
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("xmp"));
+        if is_xmp && let Ok(xmp) = fs::read_to_string(file) {
+            if let Some(rating) = xmp_rating(&xmp) {
+                keeper.rating = Some(rating);
+            }
+            if let Some(label) = xmp_label(&xmp) {
+                keeper.label = Some(label);
+            }
+        }
+    }
+
+    // Cameras implement "protect" by setting the read-only attribute of the photo on the
+    // card; sidecars and edited copies being read-only says nothing about the shot
+    keeper.protected = exif.and_then(exif_protected).unwrap_or_else(|| {
+        files.iter().any(|file| {
+            file.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| is_raw_file(n) || is_jpeg_file(n))
+                && !associations.is_derivative(file)
+                && is_read_only(file)
+        })
+    });
+    keeper
+}
+
+/// Protect flag recorded in the metadata, for cameras that write one.
+fn exif_protected(exif: &Value) -> Option<bool> {
+    PROTECT_TAGS.iter().find_map(|tag| match exif.get(*tag)? {
+        Value::Bool(protected) => Some(*protected),
+        Value::Number(n) => n.as_i64().map(|n| n != 0),
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "on" | "yes" | "protected" | "1" => Some(true),
+            "off" | "no" | "not protected" | "0" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+fn xmp_rating(xmp: &str) -> Option<i64> {
+    XMP_RATING_RE.captures(xmp)?.get(1)?.as_str().parse().ok()
+}
+
+fn xmp_label(xmp: &str) -> Option<String> {
+    let captures = XMP_LABEL_RE.captures(xmp)?;
+    let label = captures.get(1).or_else(|| captures.get(2))?.as_str().trim();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+fn is_read_only(file: &Path) -> bool {
+    fs::metadata(file).is_ok_and(|metadata| metadata.permissions().readonly())
+}
+
+/// Why `filter` rejects the group, or `None` when it is kept.
+fn rating_rejection(filter: &RatingFilter, keeper: &Keeper) -> Option<String> {
+    if let Some(min_rating) = filter.min_rating {
+        match keeper.rating {
+            Some(rating) if rating >= min_rating => {}
+            Some(rating) => return Some(format!("rating {} below {}", rating, min_rating)),
+            None => return Some(format!("unrated (minimum rating {})", min_rating)),
+        }
+    }
+    if filter.only_protected && !keeper.protected {
+        return Some("not protected".to_string());
+    }
+    if !filter.labels.is_empty() {
+        match &keeper.label {
+            Some(label) if filter.labels.iter().any(|l| l.eq_ignore_ascii_case(label)) => {}
+            Some(label) => return Some(format!("label '{}' not selected", label)),
+            None => return Some("no label".to_string()),
+        }
+    }
+    None
+}
+
+/// Removes the groups rejected by `filter`, returning what was left out and why.
+pub fn apply_rating_filter(
+    groups: &mut HashMap<String, Vec<PathBuf>>,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    associations: &Associations,
+    filter: &RatingFilter,
+) -> Vec<SkippedGroup> {
+    if !filter.is_active() {
+        return Vec::new();
+    }
+
+    let mut skipped = Vec::new();
+    groups.retain(|base, files| {
+        let keeper = read_keeper(
+            files,
+            exif_cache.get(base).map(|(_, exif)| exif),
+            associations,
+        );
+        match rating_rejection(filter, &keeper) {
+            Some(reason) => {
+                skipped.push(SkippedGroup {
+                    base: base.clone(),
+                    reason,
+                });
+                false
+            }
+            None => true,
+        }
+    });
+    skipped.sort_by(|a, b| a.base.cmp(&b.base));
+    skipped
+}
+
+/// Compiles a glob into a regex. `*` and `?` stay within a path segment and `**`
+/// crosses segments; patterns that can't apply to paths just match any text.
+pub(crate) fn glob_to_regex(pattern: &str) -> Regex {
+    let mut regex = String::from("(?i)^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // "**/" also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("Invalid regex built from glob")
+}
+
+pub(crate) fn tag_string(exif: &Value, name: &str) -> Option<String> {
+    match exif.get(name)? {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Lens name from whichever lens tag the camera writes.
+pub(crate) fn lens_name(exif: &Value) -> Option<String> {
+    ["LensModel", "LensType", "Lens", "LensID"]
+        .iter()
+        .find_map(|tag| tag_string(exif, tag))
+}
+
+/// Whether `filename` is, or is a sidecar of, a photo of `kind`. Sidecars that don't
+/// name the photo format (`NAME.xmp`) belong to both kinds.
+fn belongs_to_kind(filename: &str, kind: FileKind) -> bool {
+    if is_raw_file(filename) {
+        return kind == FileKind::Raw;
+    }
+    if is_jpeg_file(filename) {
+        return kind == FileKind::Jpeg;
+    }
+    let parts: Vec<&str> = filename.split('.').collect();
+    if parts.len() >= 3 {
+        let format = format!("x.{}", parts[parts.len() - 2]);
+        if is_raw_file(&format) {
+            return kind == FileKind::Raw;
+        }
+        if is_jpeg_file(&format) {
+            return kind == FileKind::Jpeg;
+        }
+    }
+    true
+}
+
+fn group_date(files: &[PathBuf], exif: Option<&Value>) -> Option<DateTime<Utc>> {
+    exif.and_then(get_exif_date).or_else(|| {
+        let photo = files.iter().find(|f| {
+            f.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| is_raw_file(n) || is_jpeg_file(n))
+        })?;
+        fs::metadata(photo)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(DateTime::<Utc>::from)
+    })
+}
+
+/// Drops the files matching any of the `patterns` globs, and the groups left without a
+/// photo, returning the groups left out. Patterns with a `/` are matched against the
+/// path below the file's source directory, others against the file name.
+pub fn apply_exclusion(
+    groups: &mut HashMap<String, Vec<PathBuf>>,
+    sources: &[Source],
+    patterns: &[String],
+) -> Vec<SkippedGroup> {
+    if patterns.is_empty() {
+        return Vec::new();
+    }
+    let (path_globs, name_globs): (Vec<&String>, Vec<&String>) =
+        patterns.iter().partition(|p| p.contains('/'));
+    let path_globs: Vec<Regex> = path_globs.iter().map(|g| glob_to_regex(g)).collect();
+    let name_globs: Vec<Regex> = name_globs.iter().map(|g| glob_to_regex(g)).collect();
+    let excluded = |file: &Path| {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        if name_globs.iter().any(|re| re.is_match(&name)) {
+            return true;
+        }
+        let relative = sources
+            .iter()
+            .find_map(|source| file.strip_prefix(&source.dir).ok())
+            .unwrap_or(file);
+        let relative: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+        let relative = relative.join("/");
+        path_globs.iter().any(|re| re.is_match(&relative))
+    };
+
+    let mut skipped = Vec::new();
+    groups.retain(|base, files| {
+        files.retain(|file| !excluded(file));
+        let has_photo = files.iter().any(|file| {
+            file.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| is_raw_file(n) || is_jpeg_file(n))
+        });
+        if !has_photo {
+            skipped.push(SkippedGroup {
+                base: base.clone(),
+                reason: "excluded by pattern".to_string(),
+            });
+        }
+        has_photo
+    });
+    skipped.sort_by(|a, b| a.base.cmp(&b.base));
+    skipped
+}
+
+/// Narrows `groups` down to `filter`, dropping unwanted files first and then whole
+/// groups, returning what was left out and why.
+pub fn apply_selection_filter(
+    groups: &mut HashMap<String, Vec<PathBuf>>,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    filter: &SelectionFilter,
+) -> Vec<SkippedGroup> {
+    let camera = filter.camera.as_deref().map(glob_to_regex);
+    let lens = filter.lens.as_deref().map(glob_to_regex);
+
+    let mut skipped = Vec::new();
+    groups.retain(|base, files| {
+        let exif = exif_cache.get(base).map(|(_, exif)| exif);
+        let reason = (|| {
+            if let Some(kind) = filter.only {
+                let has_photo = files.iter().any(|file| {
+                    file.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+                        (is_raw_file(n) || is_jpeg_file(n)) && belongs_to_kind(n, kind)
+                    })
+                });
+                if !has_photo {
+                    return Some(format!("no {} file", kind.label()));
+                }
+                files.retain(|file| {
+                    file.file_name()
+                        .and_then(|n| n.to_str())
+                        .is_some_and(|n| belongs_to_kind(n, kind))
+                });
+            }
+
+            if filter.since.is_some() || filter.until.is_some() {
+                let Some(day) = group_date(files, exif).map(|d| d.date_naive()) else {
+                    return Some("no capture date".to_string());
+                };
+                if filter.since.is_some_and(|since| day < since) {
+                    return Some(format!("taken {} before --since", day));
+                }
+                if filter.until.is_some_and(|until| day > until) {
+                    return Some(format!("taken {} after --until", day));
+                }
+            }
+
+            if let Some(camera) = &camera {
+                let model = exif.and_then(|e| tag_string(e, "Model"));
+                let make = exif.and_then(|e| tag_string(e, "Make"));
+                let matches = match (&make, &model) {
+                    (_, None) => false,
+                    (Some(make), Some(model)) => {
+                        camera.is_match(model) || camera.is_match(&format!("{} {}", make, model))
+                    }
+                    (None, Some(model)) => camera.is_match(model),
+                };
+                if !matches {
+                    return Some(format!(
+                        "camera {} not selected",
+                        model.unwrap_or_else(|| "unknown".to_string())
+                    ));
+                }
+            }
+
+            if let Some(lens) = &lens {
+                let name = exif.and_then(lens_name);
+                if !name.as_deref().is_some_and(|name| lens.is_match(name)) {
+                    return Some(format!(
+                        "lens {} not selected",
+                        name.unwrap_or_else(|| "unknown".to_string())
+                    ));
+                }
+            }
+
+            None
+        })();
+
+        match reason {
+            Some(reason) => {
+                skipped.push(SkippedGroup {
+                    base: base.clone(),
+                    reason,
+                });
+                false
+            }
+            None => true,
+        }
+    });
+    skipped.sort_by(|a, b| a.base.cmp(&b.base));
+    skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::associate::{AssociationRules, associate};
+    use crate::tests::scratch_dir;
+    use serde_json::json;
+
+    fn set_read_only(file: &Path) {
+        let mut permissions = fs::metadata(file).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(file, permissions).unwrap();
+    }
+
+    #[test]
+    fn protected_comes_from_camera_originals() {
+        let dir = scratch_dir("protected");
+        let files: Vec<PathBuf> = ["P1.ORF", "P1.JPG", "P1.ORF.xmp", "P1-Edit.jpg"]
+            .iter()
+            .map(|name| {
+                let file = dir.join(name);
+                fs::write(&file, name).unwrap();
+                file
+            })
+            .collect();
+        let rules = AssociationRules::new(&[], &[], &[]).unwrap();
+        let (groups, associations) = associate(&files, &rules);
+        let group = &groups["P1"];
+        let protected = |exif: Option<&Value>| read_keeper(group, exif, &associations).protected;
+
+        // A read-only sidecar or edited copy is not a protected shot
+        set_read_only(&files[2]);
+        set_read_only(&files[3]);
+        assert!(!protected(None));
+
+        set_read_only(&files[0]);
+        assert!(protected(None));
+
+        // The camera's own flag wins over the file attribute
+        assert!(!protected(Some(&json!({ "Protect": "Off" }))));
+        assert!(protected(Some(&json!({ "Protected": "Yes" }))));
+        assert!(protected(Some(&json!({ "Rating": 3 }))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn globs_keep_single_stars_within_a_folder() {
+        let glob = glob_to_regex("**/TMP/*.orf");
+        assert!(glob.is_match("TMP/P1.ORF"));
+        assert!(glob.is_match("DCIM/100OLYMP/tmp/P1.orf"));
+        assert!(!glob.is_match("TMP/sub/P1.ORF"));
+        assert!(!glob.is_match("TMPX/P1.ORF"));
+
+        let glob = glob_to_regex("P?.jpg");
+        assert!(glob.is_match("P1.JPG"));
+        assert!(!glob.is_match("P12.jpg"));
+        assert!(!glob.is_match("a/P1.jpg"));
+        // Regex characters are literal
+        assert!(glob_to_regex("IMG (1)+.jpg").is_match("img (1)+.jpg"));
+    }
+
+    fn paths(dir: &Path, names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(|name| dir.join(name)).collect()
+    }
+
+    #[test]
+    fn exclusion_matches_paths_below_the_source_and_names() {
+        let source = Source {
+            label: None,
+            dir: PathBuf::from("/media/TMP/card"),
+        };
+        let mut groups = HashMap::from([
+            (
+                "P1".to_string(),
+                paths(&source.dir, &["P1.ORF", "P1.ORF.xmp"]),
+            ),
+            (
+                "P2".to_string(),
+                paths(&source.dir, &["TMP/P2.ORF", "TMP/P2.xmp"]),
+            ),
+            (
+                "P3".to_string(),
+                paths(&source.dir, &["P3.ORF", "P3.JPG", "P3.tmp"]),
+            ),
+        ]);
+        let skipped = apply_exclusion(
+            &mut groups,
+            std::slice::from_ref(&source),
+            &[
+                "**/TMP/**".to_string(),
+                "*.TMP".to_string(),
+                "*.jpg".to_string(),
+            ],
+        );
+
+        // The source's own "TMP" folder doesn't count, and a group left with just its
+        // sidecar is dropped
+        assert_eq!(groups["P1"].len(), 2);
+        assert_eq!(groups["P3"], paths(&source.dir, &["P3.ORF"]));
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].base, "P2");
+
+        let mut groups = HashMap::from([(
+            "P4".to_string(),
+            paths(&source.dir, &["P4.JPG", "P4.ORF.xmp"]),
+        )]);
+        let skipped = apply_exclusion(&mut groups, &[source], &["*.jpg".to_string()]);
+        assert!(groups.is_empty());
+        assert_eq!(skipped[0].reason, "excluded by pattern");
+    }
+
+    #[test]
+    fn selection_filter_keeps_the_chosen_kind_days_and_cameras() {
+        let dir = Path::new("card");
+        let mut groups = HashMap::from([
+            (
+                "P1".to_string(),
+                paths(dir, &["P1.ORF", "P1.JPG", "P1.JPG.xmp", "P1.xmp"]),
+            ),
+            ("P2".to_string(), paths(dir, &["P2.JPG"])),
+            ("P3".to_string(), paths(dir, &["P3.ORF"])),
+            ("P4".to_string(), paths(dir, &["P4.ORF"])),
+        ]);
+        let exif = |date: &str, model: &str| json!({ "DateTimeOriginal": date, "Make": "OM Digital Solutions", "Model": model });
+        let exif_cache = HashMap::from([
+            (
+                "P1".to_string(),
+                (dir.join("P1.ORF"), exif("2024:05:01 10:00:00", "OM-1")),
+            ),
+            (
+                "P2".to_string(),
+                (dir.join("P2.JPG"), exif("2024:05:02 10:00:00", "OM-1")),
+            ),
+            (
+                "P3".to_string(),
+                (dir.join("P3.ORF"), exif("2024:05:15 10:00:00", "OM-1")),
+            ),
+            (
+                "P4".to_string(),
+                (
+                    dir.join("P4.ORF"),
+                    exif("2024:05:03 10:00:00", "E-M1MarkII"),
+                ),
+            ),
+        ]);
+        let skipped = apply_selection_filter(
+            &mut groups,
+            &exif_cache,
+            &SelectionFilter {
+                since: NaiveDate::from_ymd_opt(2024, 5, 1),
+                until: NaiveDate::from_ymd_opt(2024, 5, 14),
+                camera: Some("om digital* om-?".to_string()),
+                lens: None,
+                only: Some(FileKind::Raw),
+            },
+        );
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups["P1"], paths(dir, &["P1.ORF", "P1.xmp"]));
+        let reasons: Vec<(&str, &str)> = skipped
+            .iter()
+            .map(|s| (s.base.as_str(), s.reason.as_str()))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                ("P2", "no RAW file"),
+                ("P3", "taken 2024-05-15 after --until"),
+                ("P4", "camera E-M1MarkII not selected")
+            ]
+        );
+    }
+}
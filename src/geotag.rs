@@ -0,0 +1,535 @@
+//! Geotagging from GPX track logs recorded alongside cameras without GPS.
+//!
+//! Track points from all `--gpx` files are merged into one timeline. A photo's capture
+//! time (shifted by `--gpx-time-offset` to UTC) is placed on that timeline and its
+//! position interpolated between the surrounding points, as long as they are no more
+//! than `--gpx-max-gap` apart; otherwise the nearest point within that gap is used.
+//! Parsing and interpolation are plain functions over strings and times, so they need
+//! neither exiftool nor any files.
+
+use crate::associate::{Associations, photo_base};
+use crate::xmp::{self, SidecarName};
+use crate::{get_exif_capture_time, is_jpeg_file, is_raw_file};
+use chrono::{DateTime, Duration, Utc};
+use clap::ValueEnum;
+use regex::Regex;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::LazyLock;
+
+static TRKPT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?s)<trkpt\b([^>]*?)(?:/>|>(.*?)</trkpt>)").expect("Invalid regex for trkpt")
+});
+static LAT_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\blat\s*=\s*["']([^"']+)["']"#).expect("Invalid regex for lat"));
+static LON_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"\blon\s*=\s*["']([^"']+)["']"#).expect("Invalid regex for lon"));
+static TIME_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<time>\s*([^<]+?)\s*</time>").expect("Invalid regex for time"));
+static COORDINATE_NUMBER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\d+(?:\.\d+)?").expect("Invalid regex for coordinate number"));
+static ELE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<ele>\s*([^<]+?)\s*</ele>").expect("Invalid regex for ele"));
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub lat: f64,
+    pub lon: f64,
+    pub ele: Option<f64>,
+}
+
+/// Where geotags are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GeotagTarget {
+    /// XMP sidecar next to the copy (an existing sidecar is updated)
+    Sidecar,
+    /// The copied photo file itself
+    Copy,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GeotagOptions {
+    /// Added to the camera clock to get UTC.
+    pub offset: Duration,
+    pub max_gap: Duration,
+    pub target: GeotagTarget,
+    pub sidecar_name: SidecarName,
+}
+
+/// Outcome of geotagging one copied photo, kept for the run report.
+#[derive(Debug, Clone)]
+pub struct GeotagResult {
+    pub file: PathBuf,
+    /// "tagged", "has-gps", "no-time", "no-match" or "failed"
+    pub status: &'static str,
+    pub position: Option<TrackPoint>,
+    /// File the tags went into (the copy or its sidecar).
+    pub written_to: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+impl GeotagResult {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "file": self.file,
+            "status": self.status,
+            "latitude": self.position.map(|p| p.lat),
+            "longitude": self.position.map(|p| p.lon),
+            "elevation": self.position.and_then(|p| p.ele),
+            "written_to": self.written_to,
+            "error": self.error,
+        })
+    }
+}
+
+fn capture_f64(re: &Regex, text: &str) -> Option<f64> {
+    re.captures(text)?.get(1)?.as_str().trim().parse().ok()
+}
+
+/// Track points of a GPX document, in document order. Points without a valid
+/// position or time are skipped.
+pub fn parse_gpx(xml: &str) -> Vec<TrackPoint> {
+    TRKPT_RE
+        .captures_iter(xml)
+        .filter_map(|captures| {
+            let attrs = captures.get(1)?.as_str();
+            let body = captures.get(2).map_or("", |m| m.as_str());
+            let lat = capture_f64(&LAT_RE, attrs)?;
+            let lon = capture_f64(&LON_RE, attrs)?;
+            let time = DateTime::parse_from_rfc3339(TIME_RE.captures(body)?.get(1)?.as_str())
+                .ok()?
+                .with_timezone(&Utc);
+            let ele = capture_f64(&ELE_RE, body);
+            ((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)).then_some(
+                TrackPoint {
+                    time,
+                    lat,
+                    lon,
+                    ele,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Reads and merges the given GPX files into one time-ordered timeline.
+pub fn load_tracks(paths: &[PathBuf]) -> Result<Vec<TrackPoint>, Box<dyn std::error::Error>> {
+    let mut points = Vec::new();
+    for path in paths {
+        let xml = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read GPX file {}: {}", path.display(), e))?;
+        let parsed = parse_gpx(&xml);
+        if parsed.is_empty() {
+            println!("Warning: no timed track points in {}", path.display());
+        }
+        points.extend(parsed);
+    }
+    points.sort_by_key(|p| p.time);
+    points.dedup_by_key(|p| p.time);
+    Ok(points)
+}
+
+/// Position at `time` on a time-ordered track, or `None` when no point is close enough.
+pub fn position_at(
+    track: &[TrackPoint],
+    time: DateTime<Utc>,
+    max_gap: Duration,
+) -> Option<TrackPoint> {
+    let index = track.partition_point(|p| p.time < time);
+    let next = track.get(index);
+    let prev = index.checked_sub(1).and_then(|i| track.get(i));
+
+    match (prev, next) {
+        (_, Some(next)) if next.time == time => Some(*next),
+        (Some(prev), Some(next)) if next.time - prev.time <= max_gap => {
+            let span = (next.time - prev.time).num_milliseconds() as f64;
+            let ratio = (time - prev.time).num_milliseconds() as f64 / span;
+            let lerp = |a: f64, b: f64| a + (b - a) * ratio;
+            Some(TrackPoint {
+                time,
+                lat: lerp(prev.lat, next.lat),
+                lon: lerp(prev.lon, next.lon),
+                ele: prev.ele.zip(next.ele).map(|(a, b)| lerp(a, b)),
+            })
+        }
+        _ => [prev, next]
+            .into_iter()
+            .flatten()
+            .filter(|p| (p.time - time).abs() <= max_gap)
+            .min_by_key(|p| (p.time - time).abs())
+            .map(|p| TrackPoint { time, ..*p }),
+    }
+}
+
+/// Parses an exiftool GPS coordinate: a plain number or `48 deg 8' 12.34" N`.
+/// `reference` ("N"/"S"/"E"/"W" or spelled out) decides the sign when the value has none.
+fn parse_coordinate(value: &Value, reference: Option<&Value>) -> Option<f64> {
+    let text = match value {
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.trim().to_string(),
+        _ => return None,
+    };
+    let numbers: Vec<f64> = COORDINATE_NUMBER_RE
+        .find_iter(&text)
+        .filter_map(|m| m.as_str().parse().ok())
+        .collect();
+    if numbers.is_empty() {
+        return None;
+    }
+    let magnitude = numbers
+        .iter()
+        .zip([1.0, 60.0, 3600.0])
+        .map(|(n, divisor)| n / divisor)
+        .sum::<f64>();
+    let hemisphere = text
+        .chars()
+        .rev()
+        .find(|c| c.is_ascii_alphabetic())
+        .filter(|c| matches!(c, 'N' | 'S' | 'E' | 'W'))
+        .or_else(|| {
+            reference
+                .and_then(|r| r.as_str())
+                .and_then(|r| r.trim().chars().next())
+        });
+    let negative = text.starts_with('-') || matches!(hemisphere, Some('S' | 'W' | 's' | 'w'));
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// Latitude and longitude recorded in the EXIF data, if any.
+pub fn exif_position(exif: &Value) -> Option<(f64, f64)> {
+    let lat = parse_coordinate(exif.get("GPSLatitude")?, exif.get("GPSLatitudeRef"))?;
+    let lon = parse_coordinate(exif.get("GPSLongitude")?, exif.get("GPSLongitudeRef"))?;
+    Some((lat, lon))
+}
+
+/// Where a photo was taken: its own GPS tags, else its position on the track.
+fn photo_position(
+    exif: &Value,
+    track: &[TrackPoint],
+    options: &GeotagOptions,
+) -> Option<(f64, f64)> {
+    exif_position(exif).or_else(|| {
+        let time = get_exif_capture_time(exif)?;
+        let point = position_at(track, time + options.offset, options.max_gap)?;
+        Some((point.lat, point.lon))
+    })
+}
+
+/// Position of every group that has one, from its GPS tags or the track.
+pub fn group_positions(
+    groups: &HashMap<String, Vec<PathBuf>>,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    track: &[TrackPoint],
+    options: &GeotagOptions,
+) -> HashMap<String, (f64, f64)> {
+    groups
+        .keys()
+        .filter_map(|base| {
+            let (_, exif) = exif_cache.get(base)?;
+            Some((base.clone(), photo_position(exif, track, options)?))
+        })
+        .collect()
+}
+
+fn exiftool_gps_args(position: &TrackPoint, target: GeotagTarget) -> Vec<String> {
+    let mut args = vec!["-overwrite_original".to_string(), "-P".to_string()];
+    match target {
+        GeotagTarget::Copy => {
+            args.push(format!("-GPSLatitude={}", position.lat.abs()));
+            args.push(format!(
+                "-GPSLatitudeRef={}",
+                if position.lat < 0.0 { "S" } else { "N" }
+            ));
+            args.push(format!("-GPSLongitude={}", position.lon.abs()));
+            args.push(format!(
+                "-GPSLongitudeRef={}",
+                if position.lon < 0.0 { "W" } else { "E" }
+            ));
+            if let Some(ele) = position.ele {
+                args.push(format!("-GPSAltitude={}", ele.abs()));
+                args.push(format!("-GPSAltitudeRef#={}", u8::from(ele < 0.0)));
+            }
+        }
+        GeotagTarget::Sidecar => {
+            args.push(format!("-XMP-exif:GPSLatitude={}", position.lat));
+            args.push(format!("-XMP-exif:GPSLongitude={}", position.lon));
+            if let Some(ele) = position.ele {
+                args.push(format!("-XMP-exif:GPSAltitude={}", ele.abs()));
+                args.push(format!("-XMP-exif:GPSAltitudeRef#={}", u8::from(ele < 0.0)));
+            }
+        }
+    }
+    args
+}
+
+fn write_position(
+    photo: &Path,
+    position: &TrackPoint,
+    options: &GeotagOptions,
+) -> Result<PathBuf, String> {
+    let destination = match options.target {
+        GeotagTarget::Copy => photo.to_path_buf(),
+        GeotagTarget::Sidecar => xmp::sidecar_for(photo, options.sidecar_name),
+    };
+    let output = Command::new("exiftool")
+        .args(exiftool_gps_args(position, options.target))
+        .arg(&destination)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(destination)
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// Geotags the photos among the copied files, skipping those that already carry GPS.
+pub fn geotag_copies(
+    copy_plan: &[(PathBuf, PathBuf)],
+    associations: &Associations,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    track: &[TrackPoint],
+    options: &GeotagOptions,
+    dry_run: bool,
+) -> Vec<GeotagResult> {
+    let mut results = Vec::new();
+    for (source, dest) in copy_plan {
+        let Some(filename) = source.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !is_raw_file(filename) && !is_jpeg_file(filename) {
+            continue;
+        }
+        let base = associations
+            .base_of(source)
+            .unwrap_or_else(|| photo_base(filename));
+        let exif = exif_cache.get(base).map(|(_, exif)| exif);
+
+        let mut result = GeotagResult {
+            file: dest.clone(),
+            status: "no-time",
+            position: None,
+            written_to: None,
+            error: None,
+        };
+        if exif.is_some_and(|e| e.get("GPSLatitude").is_some()) {
+            result.status = "has-gps";
+        } else if let Some(time) = exif.and_then(get_exif_capture_time) {
+            match position_at(track, time + options.offset, options.max_gap) {
+                None => result.status = "no-match",
+                Some(position) => {
+                    result.position = Some(position);
+                    if dry_run {
+                        println!(
+                            "Would geotag {} at {:.6}, {:.6}",
+                            dest.display(),
+                            position.lat,
+                            position.lon
+                        );
+                        result.status = "tagged";
+                    } else {
+                        match write_position(dest, &position, options) {
+                            Ok(written_to) => {
+                                result.status = "tagged";
+                                result.written_to = Some(written_to);
+                            }
+                            Err(e) => {
+                                result.status = "failed";
+                                result.error = Some(e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        results.push(result);
+    }
+
+    let count = |status: &str| results.iter().filter(|r| r.status == status).count();
+    println!(
+        "Geotagging: {} tagged, {} already had GPS, {} without a track match, {} without capture time, {} failed.",
+        count("tagged"),
+        count("has-gps"),
+        count("no-match"),
+        count("no-time"),
+        count("failed")
+    );
+    for result in results.iter().filter(|r| r.status == "failed") {
+        println!(
+            "  Failed to geotag {}: {}",
+            result.file.display(),
+            result.error.as_deref().unwrap_or("")
+        );
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test">
+  <trk><trkseg>
+    <trkpt lat="38.7000" lon="-9.1400"><ele>10</ele><time>2024-06-15T10:00:00Z</time></trkpt>
+    <trkpt lat='38.7100' lon='-9.1200'>
+      <ele>30</ele>
+      <time>2024-06-15T10:01:40Z</time>
+    </trkpt>
+    <trkpt lat="38.8000" lon="-9.3800"><time>2024-06-15T13:30:00+01:00</time></trkpt>
+    <trkpt lat="38.9000"><time>2024-06-15T10:30:00Z</time></trkpt>
+    <trkpt lat="95.0" lon="-9.3800"><time>2024-06-15T10:40:00Z</time></trkpt>
+    <trkpt lat="38.9000" lon="-9.4000"><time>not a time</time></trkpt>
+    <trkpt lat="38.9000" lon="-9.4000"/>
+    <trkpt lat="38.8000" lon="-9.4000"><time>2024-06-15T12:00:00Z</time></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn track() -> Vec<TrackPoint> {
+        let mut points = parse_gpx(GPX);
+        points.sort_by_key(|p| p.time);
+        points
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_gpx_skips_malformed_points() {
+        let points = parse_gpx(GPX);
+        // Missing lon, out-of-range lat, bad or missing time are dropped
+        assert_eq!(points.len(), 4);
+        assert_eq!(points[0].time, at("2024-06-15T10:00:00Z"));
+        assert_eq!(points[1].ele, Some(30.0));
+        assert_eq!(points[1].lat, 38.71);
+        // Times with an offset are converted to UTC
+        assert_eq!(points[2].time, at("2024-06-15T12:30:00Z"));
+        assert_eq!(points[2].ele, None);
+    }
+
+    #[test]
+    fn exact_track_point() {
+        let point =
+            position_at(&track(), at("2024-06-15T10:01:40Z"), Duration::seconds(300)).unwrap();
+        assert_eq!(
+            (point.lat, point.lon, point.ele),
+            (38.71, -9.12, Some(30.0))
+        );
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let point =
+            position_at(&track(), at("2024-06-15T10:00:25Z"), Duration::seconds(300)).unwrap();
+        assert_near(point.lat, 38.7025);
+        assert_near(point.lon, -9.135);
+        assert_near(point.ele.unwrap(), 15.0);
+        assert_eq!(point.time, at("2024-06-15T10:00:25Z"));
+    }
+
+    #[test]
+    fn gap_larger_than_max_gap_has_no_position() {
+        // 10:01:40 and 12:00:00 are almost two hours apart; 11:00 is an hour from both
+        assert_eq!(
+            position_at(&track(), at("2024-06-15T11:00:00Z"), Duration::seconds(300)),
+            None
+        );
+        // Close to one end of the gap the nearest point is used
+        let point =
+            position_at(&track(), at("2024-06-15T10:03:00Z"), Duration::seconds(300)).unwrap();
+        assert_eq!((point.lat, point.lon), (38.71, -9.12));
+    }
+
+    #[test]
+    fn times_outside_the_track() {
+        let track = track();
+        let max_gap = Duration::seconds(300);
+        assert_eq!(
+            position_at(&track, at("2024-06-15T09:00:00Z"), max_gap),
+            None
+        );
+        assert_eq!(
+            position_at(&track, at("2024-06-15T13:00:00Z"), max_gap),
+            None
+        );
+        let before = position_at(&track, at("2024-06-15T09:58:00Z"), max_gap).unwrap();
+        assert_eq!((before.lat, before.lon), (38.7, -9.14));
+        let after = position_at(&track, at("2024-06-15T12:34:00Z"), max_gap).unwrap();
+        assert_eq!((after.lat, after.lon), (38.8, -9.38));
+    }
+
+    #[test]
+    fn time_offset_is_applied() {
+        let options = GeotagOptions {
+            offset: Duration::seconds(7200),
+            max_gap: Duration::seconds(300),
+            target: GeotagTarget::Sidecar,
+            sidecar_name: SidecarName::Base,
+        };
+        // Camera clock two hours behind UTC: 08:01:40 on the camera is 10:01:40 UTC
+        let exif = json!({ "DateTimeOriginal": "2024:06:15 08:01:40" });
+        assert_eq!(
+            photo_position(&exif, &track(), &options),
+            Some((38.71, -9.12))
+        );
+        let utc = GeotagOptions {
+            offset: Duration::zero(),
+            ..options
+        };
+        assert_eq!(photo_position(&exif, &track(), &utc), None);
+    }
+
+    #[test]
+    fn photo_gps_tags_win_over_the_track() {
+        let options = GeotagOptions {
+            offset: Duration::zero(),
+            max_gap: Duration::seconds(300),
+            target: GeotagTarget::Sidecar,
+            sidecar_name: SidecarName::Base,
+        };
+        let exif = json!({
+            "DateTimeOriginal": "2024:06:15 10:01:40",
+            "GPSLatitude": "41 deg 9' 0.00\" N",
+            "GPSLongitude": "8 deg 37' 12.00\" W",
+        });
+        let (lat, lon) = photo_position(&exif, &track(), &options).unwrap();
+        assert_near(lat, 41.15);
+        assert_near(lon, -8.62);
+    }
+
+    #[test]
+    fn parse_coordinate_formats() {
+        assert_eq!(parse_coordinate(&json!(38.7), None), Some(38.7));
+        assert_eq!(parse_coordinate(&json!("-9.14"), None), Some(-9.14));
+        assert_eq!(
+            parse_coordinate(&json!("9.14"), Some(&json!("West"))),
+            Some(-9.14)
+        );
+        assert_near(
+            parse_coordinate(&json!("33 deg 51' 36.00\" S"), None).unwrap(),
+            -33.86,
+        );
+        assert_near(
+            parse_coordinate(&json!("33 deg 51' 36.00\""), Some(&json!("S"))).unwrap(),
+            -33.86,
+        );
+        assert_eq!(parse_coordinate(&json!("north"), None), None);
+        assert_eq!(parse_coordinate(&json!(true), None), None);
+    }
+}
@@ -0,0 +1,582 @@
+//! External commands run at points of the import.
+//!
+//! Sequence hooks run on detected sequences once the import is done (HDR merging,
+//! focus stacking, panorama stitching...). A hook is configured as `TYPE[:TREE]=COMMAND`,
+//! e.g. `--sequence-hook 'HDR=enfuse -o "$SEQUENCE_NAME.tif" "$@"'`. The command is run
+//! through `sh -c` inside the sequence folder, with the photo files in shot order as
+//! its arguments and the sequence described by `SEQUENCE_*` environment variables,
+//! so anything it writes to the current directory lands alongside the sequence. A
+//! sequence shot RAW+JPEG has a folder in both `RAW/` and `JPEG/`; `TREE` says which
+//! of them the hook runs in, by default only one.
+//!
+//! Lifecycle hooks run before the import, after each copied file and after the whole
+//! import. They are configured as `EVENT[:POLICY]=COMMAND`, e.g.
+//! `--hook 'pre-import:abort=mountpoint -q /mnt/nas'`, and receive a JSON description
+//! of the event on stdin.
+
+use crate::SequenceRecord;
+use crate::manifest;
+use rayon::prelude::*;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+
+/// Which copies of a sequence a hook runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookTree {
+    /// The folder in `RAW/`, or the one in `JPEG/` for sequences without RAW files
+    Preferred,
+    Raw,
+    Jpeg,
+    /// Every folder of the sequence, so twice for RAW+JPEG sequences
+    Both,
+}
+
+impl HookTree {
+    /// Whether a hook runs in a folder of `tree` ("RAW" or "JPEG"), for a sequence
+    /// that has a folder in `RAW/` or not.
+    fn runs_in(self, tree: Option<&str>, sequence_has_raw: bool) -> bool {
+        match (self, tree) {
+            (HookTree::Both, _) | (_, None) => true,
+            (HookTree::Raw, Some(tree)) => tree == "RAW",
+            (HookTree::Jpeg, Some(tree)) => tree == "JPEG",
+            (HookTree::Preferred, Some(tree)) => tree == "RAW" || !sequence_has_raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SequenceHook {
+    /// Sequence label the hook applies to ("HDR", "BURST", "FOCUS", ...).
+    pub label: String,
+    pub tree: HookTree,
+    pub command: String,
+}
+
+impl FromStr for SequenceHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (spec, command) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected TYPE[:TREE]=COMMAND, got '{}'", s))?;
+        let (label, tree) = match spec.split_once(':') {
+            Some((label, tree)) => (label, Some(tree.trim())),
+            None => (spec, None),
+        };
+        let tree = match tree {
+            None => HookTree::Preferred,
+            Some("raw") => HookTree::Raw,
+            Some("jpeg") => HookTree::Jpeg,
+            Some("both") => HookTree::Both,
+            Some(other) => {
+                return Err(format!(
+                    "unknown tree '{}' (expected raw, jpeg or both)",
+                    other
+                ));
+            }
+        };
+        let label = label.trim().to_uppercase();
+        if !crate::SEQUENCE_LABELS.contains(&label.as_str()) {
+            return Err(format!(
+                "unknown sequence type '{}' (expected one of {})",
+                label,
+                crate::SEQUENCE_LABELS.join(", ")
+            ));
+        }
+        if command.trim().is_empty() {
+            return Err(format!("empty command for {} hook", label));
+        }
+        Ok(SequenceHook {
+            label,
+            tree,
+            command: command.to_string(),
+        })
+    }
+}
+
+/// Outcome of one hook invocation, kept for the run report.
+#[derive(Debug, Clone)]
+pub struct HookResult {
+    pub hook: String,
+    pub target: String,
+    pub command: String,
+    /// `None` when the command could not be started or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl HookResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Tree of the output directory a sequence folder is in: "RAW" or "JPEG".
+fn tree_of<'a>(folder: &'a Path, output_dir: &Path) -> Option<&'a str> {
+    folder
+        .strip_prefix(output_dir)
+        .ok()?
+        .iter()
+        .next()?
+        .to_str()
+}
+
+/// Runs every matching hook on the sequence folders of `output_dir` it applies to, at
+/// most `jobs` at a time.
+pub fn run_sequence_hooks(
+    hooks: &[SequenceHook],
+    output_dir: &Path,
+    folders: &[(PathBuf, &SequenceRecord)],
+    jobs: usize,
+    dry_run: bool,
+) -> Result<Vec<HookResult>, Box<dyn std::error::Error>> {
+    let with_raw: HashSet<&str> = folders
+        .iter()
+        .filter(|(folder, _)| tree_of(folder, output_dir) == Some("RAW"))
+        .map(|(_, record)| record.seq_type.folder_name())
+        .collect();
+    let invocations: Vec<(&SequenceHook, &PathBuf, &SequenceRecord)> = folders
+        .iter()
+        .flat_map(|(folder, record)| {
+            let tree = tree_of(folder, output_dir);
+            let has_raw = with_raw.contains(record.seq_type.folder_name());
+            hooks
+                .iter()
+                .filter(move |hook| {
+                    hook.label == record.seq_type.label() && hook.tree.runs_in(tree, has_raw)
+                })
+                .map(move |hook| (hook, folder, *record))
+        })
+        .collect();
+
+    if invocations.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if dry_run {
+        for (hook, folder, _) in &invocations {
+            println!(
+                "Would run {} hook in {}: {}",
+                hook.label,
+                folder.display(),
+                hook.command
+            );
+        }
+        return Ok(Vec::new());
+    }
+
+    println!("Running {} sequence hook(s)...", invocations.len());
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.max(1))
+        .build()?;
+    let results: Vec<HookResult> = pool.install(|| {
+        invocations
+            .par_iter()
+            .map(|(hook, folder, record)| {
+                let files = manifest::frame_files(record, folder);
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(&hook.command)
+                    .arg("sh")
+                    .args(&files)
+                    .current_dir(folder)
+                    .env("SEQUENCE_DIR", folder.as_os_str())
+                    .env("SEQUENCE_NAME", record.seq_type.folder_name())
+                    .env("SEQUENCE_TYPE", record.seq_type.label())
+                    .env("SEQUENCE_CAMERA", &record.camera)
+                    .output();
+                let (exit_code, stdout, stderr) = match output {
+                    Ok(output) => (
+                        output.status.code(),
+                        String::from_utf8_lossy(&output.stdout).to_string(),
+                        String::from_utf8_lossy(&output.stderr).to_string(),
+                    ),
+                    Err(e) => (None, String::new(), e.to_string()),
+                };
+                HookResult {
+                    hook: hook.label.clone(),
+                    target: folder.display().to_string(),
+                    command: hook.command.clone(),
+                    exit_code,
+                    stdout,
+                    stderr,
+                }
+            })
+            .collect()
+    });
+
+    let failed: Vec<&HookResult> = results.iter().filter(|r| !r.success()).collect();
+    println!(
+        "Sequence hooks complete: {} succeeded, {} failed.",
+        results.len() - failed.len(),
+        failed.len()
+    );
+    for result in failed {
+        println!(
+            "  {} hook failed in {} (exit code {}): {}",
+            result.hook,
+            result.target,
+            result
+                .exit_code
+                .map_or("none".to_string(), |code| code.to_string()),
+            result.stderr.trim()
+        );
+    }
+    Ok(results)
+}
+
+/// Points of the import where lifecycle hooks run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    PreImport,
+    PostCopy,
+    PostImport,
+}
+
+impl HookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::PreImport => "pre-import",
+            HookEvent::PostCopy => "post-copy",
+            HookEvent::PostImport => "post-import",
+        }
+    }
+}
+
+/// What a failing lifecycle hook does to the import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Stop the import with an error
+    Abort,
+    /// Print a warning and carry on
+    Warn,
+    /// Carry on silently (the failure is still in the run report)
+    Ignore,
+}
+
+#[derive(Debug, Clone)]
+pub struct LifecycleHook {
+    pub event: HookEvent,
+    pub policy: FailurePolicy,
+    pub command: String,
+}
+
+impl FromStr for LifecycleHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (spec, command) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected EVENT[:POLICY]=COMMAND, got '{}'", s))?;
+        let (event, policy) = match spec.split_once(':') {
+            Some((event, policy)) => (event.trim(), Some(policy.trim())),
+            None => (spec.trim(), None),
+        };
+        let event = match event {
+            "pre-import" => HookEvent::PreImport,
+            "post-copy" => HookEvent::PostCopy,
+            "post-import" => HookEvent::PostImport,
+            other => {
+                return Err(format!(
+                    "unknown hook event '{}' (expected pre-import, post-copy or post-import)",
+                    other
+                ));
+            }
+        };
+        let policy = match policy {
+            None | Some("warn") => FailurePolicy::Warn,
+            Some("abort") => FailurePolicy::Abort,
+            Some("ignore") => FailurePolicy::Ignore,
+            Some(other) => {
+                return Err(format!(
+                    "unknown failure policy '{}' (expected abort, warn or ignore)",
+                    other
+                ));
+            }
+        };
+        if command.trim().is_empty() {
+            return Err(format!("empty command for {} hook", event.name()));
+        }
+        Ok(LifecycleHook {
+            event,
+            policy,
+            command: command.to_string(),
+        })
+    }
+}
+
+/// Runs the hooks registered for `event` one after the other, feeding each the
+/// `payload` on stdin. Fails as soon as a hook with the abort policy fails.
+pub fn run_lifecycle_hooks(
+    hooks: &[LifecycleHook],
+    event: HookEvent,
+    target: &str,
+    payload: &Value,
+    dry_run: bool,
+) -> Result<Vec<HookResult>, Box<dyn std::error::Error>> {
+    let mut results = Vec::new();
+    for hook in hooks.iter().filter(|hook| hook.event == event) {
+        if dry_run {
+            println!("Would run {} hook: {}", event.name(), hook.command);
+            continue;
+        }
+        let result = run_with_stdin(hook, target, payload);
+        if !result.success() {
+            let message = format!(
+                "{} hook '{}' failed for {} (exit code {}): {}",
+                event.name(),
+                hook.command,
+                target,
+                result
+                    .exit_code
+                    .map_or("none".to_string(), |code| code.to_string()),
+                result.stderr.trim()
+            );
+            match hook.policy {
+                FailurePolicy::Abort => return Err(message.into()),
+                FailurePolicy::Warn => println!("Warning: {}", message),
+                FailurePolicy::Ignore => {}
+            }
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+fn run_with_stdin(hook: &LifecycleHook, target: &str, payload: &Value) -> HookResult {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(&hook.command)
+        .env("HOOK_EVENT", hook.event.name())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            // Fed from another thread while the output is read: a hook that writes
+            // before reading all of a large payload would otherwise block on a full pipe
+            let stdin = child.stdin.take();
+            let input = payload.to_string();
+            let writer = thread::spawn(move || {
+                if let Some(mut stdin) = stdin {
+                    // A hook that doesn't read its input closes the pipe early; that's fine
+                    let _ = stdin.write_all(input.as_bytes());
+                }
+            });
+            let output = child.wait_with_output();
+            let _ = writer.join();
+            output
+        });
+    let (exit_code, stdout, stderr) = match output {
+        Ok(output) => (
+            output.status.code(),
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ),
+        Err(e) => (None, String::new(), e.to_string()),
+    };
+    HookResult {
+        hook: hook.event.name().to_string(),
+        target: target.to_string(),
+        command: hook.command.clone(),
+        exit_code,
+        stdout,
+        stderr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch_dir;
+    use crate::{SequenceFrame, SequenceType};
+    use serde_json::json;
+    use std::fs;
+
+    fn hdr_record() -> SequenceRecord {
+        SequenceRecord {
+            seq_type: SequenceType::Hdr("P6150101_HDR".to_string()),
+            rule: "bracket-shot-number",
+            detector: "olympus",
+            camera: "OM-1".to_string(),
+            frames: ["P6150101", "P6150102", "P6150103"]
+                .iter()
+                .map(|base| SequenceFrame {
+                    base: base.to_string(),
+                    capture_time: None,
+                    exposure_compensation: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// The bracket's folders in `RAW/` and `JPEG/` below `output_dir`, frames written
+    /// out of shot order.
+    fn sequence_folders(output_dir: &Path) -> Vec<PathBuf> {
+        [("RAW", "ORF"), ("JPEG", "JPG")]
+            .iter()
+            .map(|(tree, extension)| {
+                let folder = output_dir.join(tree).join("2024/06/15/P6150101_HDR");
+                fs::create_dir_all(&folder).unwrap();
+                for base in ["P6150103", "P6150101", "P6150102"] {
+                    fs::write(folder.join(format!("{}.{}", base, extension)), "").unwrap();
+                }
+                fs::write(folder.join("P6150101.xmp"), "").unwrap();
+                folder
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sequence_hook_runs_script_in_sequence_folder() {
+        let dir = scratch_dir("sequence-hook");
+        let script = dir.join("hook.sh");
+        fs::write(
+            &script,
+            "echo \"$SEQUENCE_TYPE $SEQUENCE_NAME $SEQUENCE_CAMERA $(basename \"$PWD\")\"\n\
+             for frame in \"$@\"; do basename \"$frame\"; done\n\
+             echo failed >&2\n\
+             exit 3\n",
+        )
+        .unwrap();
+        let output_dir = dir.join("out");
+        let record = hdr_record();
+        let folders: Vec<(PathBuf, &SequenceRecord)> = sequence_folders(&output_dir)
+            .into_iter()
+            .map(|folder| (folder, &record))
+            .collect();
+        let hook: SequenceHook = format!("HDR=sh {} \"$@\"", script.display())
+            .parse()
+            .unwrap();
+
+        let results = run_sequence_hooks(&[hook], &output_dir, &folders, 2, false).unwrap();
+        assert_eq!(results.len(), 1);
+        let result = &results[0];
+        assert_eq!(result.hook, "HDR");
+        assert_eq!(result.target, folders[0].0.display().to_string());
+        assert_eq!(result.exit_code, Some(3));
+        assert!(!result.success());
+        assert_eq!(
+            result.stdout,
+            "HDR P6150101_HDR OM-1 P6150101_HDR\nP6150101.ORF\nP6150102.ORF\nP6150103.ORF\n"
+        );
+        assert_eq!(result.stderr, "failed\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sequence_hook_tree_selects_folders() {
+        let dir = scratch_dir("sequence-hook-tree");
+        let output_dir = dir.join("out");
+        let record = hdr_record();
+        let folders: Vec<(PathBuf, &SequenceRecord)> = sequence_folders(&output_dir)
+            .into_iter()
+            .map(|folder| (folder, &record))
+            .collect();
+        let targets = |hook: &str, folders: &[(PathBuf, &SequenceRecord)]| -> Vec<String> {
+            let hook: SequenceHook = hook.parse().unwrap();
+            run_sequence_hooks(&[hook], &output_dir, folders, 1, false)
+                .unwrap()
+                .iter()
+                .map(|result| {
+                    let target = PathBuf::from(&result.target);
+                    tree_of(&target, &output_dir).unwrap().to_string()
+                })
+                .collect()
+        };
+
+        assert_eq!(targets("HDR=true", &folders), ["RAW"]);
+        assert_eq!(targets("HDR:jpeg=true", &folders), ["JPEG"]);
+        let mut both = targets("HDR:both=true", &folders);
+        both.sort();
+        assert_eq!(both, ["JPEG", "RAW"]);
+        // A sequence shot without RAW files is run on in JPEG/
+        assert_eq!(targets("HDR=true", &folders[1..]), ["JPEG"]);
+        assert!(targets("HDR:raw=true", &folders[1..]).is_empty());
+        assert!("HDR:tiff=true".parse::<SequenceHook>().is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn large_payload_is_echoed_back() {
+        let hook: LifecycleHook = "post-import=cat".parse().unwrap();
+        // Well past a pipe buffer, so the hook writes while its input is still coming
+        let payload = json!({ "files": vec!["x".repeat(100); 4096] });
+        let results =
+            run_lifecycle_hooks(&[hook], HookEvent::PostImport, "import", &payload, false).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].success());
+        assert_eq!(results[0].stdout, payload.to_string());
+    }
+
+    /// Runs a failing stub hook with `policy` followed by one that succeeds, returning
+    /// the outcome and what the hooks logged.
+    fn run_failing_hook(policy: &str) -> (Result<Vec<HookResult>, String>, String) {
+        let dir = scratch_dir(&format!("hook-policy-{}", policy));
+        let log = dir.join("log");
+        let script = dir.join("fail.sh");
+        fs::write(
+            &script,
+            format!(
+                "echo \"$HOOK_EVENT $(cat)\" >> '{}'\necho nope >&2\nexit 2\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        let hooks: Vec<LifecycleHook> = [
+            format!("post-copy:{}=sh '{}'", policy, script.display()),
+            format!("post-copy=echo after >> '{}'", log.display()),
+        ]
+        .iter()
+        .map(|hook| hook.parse().unwrap())
+        .collect();
+
+        let result = run_lifecycle_hooks(
+            &hooks,
+            HookEvent::PostCopy,
+            "out/P1.ORF",
+            &json!({ "dest": "out/P1.ORF" }),
+            false,
+        )
+        .map_err(|e| e.to_string());
+        let logged = fs::read_to_string(&log).unwrap_or_default();
+        let _ = fs::remove_dir_all(&dir);
+        (result, logged)
+    }
+
+    #[test]
+    fn abort_policy_stops_at_the_failing_hook() {
+        let (result, logged) = run_failing_hook("abort");
+        let message = result.unwrap_err();
+        assert!(message.starts_with("post-copy hook"), "{}", message);
+        assert!(message.ends_with("failed for out/P1.ORF (exit code 2): nope"));
+        assert_eq!(logged, "post-copy {\"dest\":\"out/P1.ORF\"}\n");
+    }
+
+    #[test]
+    fn warn_policy_carries_on() {
+        let (result, logged) = run_failing_hook("warn");
+        let results = result.unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].exit_code, Some(2));
+        assert_eq!(results[0].stderr, "nope\n");
+        assert!(results[1].success());
+        assert!(logged.ends_with("after\n"));
+    }
+
+    #[test]
+    fn ignore_policy_carries_on_and_keeps_the_result() {
+        let (result, logged) = run_failing_hook("ignore");
+        let results = result.unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(!results[0].success());
+        assert_eq!(results[0].target, "out/P1.ORF");
+        assert!(logged.ends_with("after\n"));
+    }
+}
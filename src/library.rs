@@ -0,0 +1,313 @@
+//! Index of the photos in a library, kept in `.photo_sorter/index.json` in the output
+//! directory.
+//!
+//! Every import adds the photos it copied or moved, with the metadata it already read.
+//! Commands that look at the whole library read the index and only run exiftool on
+//! files it doesn't know yet or that changed since they were indexed, so libraries
+//! imported before the index existed are picked up too.
+
+use crate::associate::file_base;
+use crate::filters::{lens_name, tag_string};
+use crate::manifest::MANIFEST_FILE_NAME;
+use crate::{
+    SequenceType, collect_all_files_recursive, get_exif_capture_time, get_exif_data, is_jpeg_file,
+    is_raw_file,
+};
+use chrono::{DateTime, Utc};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Location of the index, relative to the library.
+pub const INDEX_PATH: &str = ".photo_sorter/index.json";
+
+const INDEX_VERSION: u64 = 1;
+
+/// Format of capture times in the index.
+pub(crate) const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+/// What the index knows about one photo file.
+#[derive(Debug, Clone, Default)]
+pub struct IndexEntry {
+    pub size: u64,
+    /// Modification time in seconds, to tell whether the file changed since.
+    pub mtime: u64,
+    pub captured: Option<DateTime<Utc>>,
+    pub camera: Option<String>,
+    pub serial: Option<String>,
+    pub lens: Option<String>,
+    /// Focal length in millimetres.
+    pub focal_length: Option<f64>,
+    pub iso: Option<u64>,
+    /// Type label and folder of the sequence the photo is in.
+    pub sequence: Option<(String, String)>,
+}
+
+/// Leading number of a value such as "25.0 mm".
+fn leading_number(text: &str) -> Option<f64> {
+    let end = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    text[..end].parse().ok()
+}
+
+fn file_stamp(file: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(file).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    Some((metadata.len(), mtime))
+}
+
+impl IndexEntry {
+    /// Entry for `file` from its EXIF and the sequence it was filed in.
+    pub fn new(file: &Path, exif: Option<&Value>, sequence: Option<(String, String)>) -> Self {
+        let (size, mtime) = file_stamp(file).unwrap_or_default();
+        let mut entry = IndexEntry {
+            size,
+            mtime,
+            sequence,
+            ..Default::default()
+        };
+        if let Some(exif) = exif {
+            entry.captured = get_exif_capture_time(exif);
+            entry.camera = tag_string(exif, "Model");
+            entry.serial = tag_string(exif, "SerialNumber");
+            entry.lens = lens_name(exif);
+            entry.focal_length = tag_string(exif, "FocalLength").and_then(|f| leading_number(&f));
+            entry.iso = tag_string(exif, "ISO")
+                .and_then(|iso| leading_number(&iso))
+                .map(|iso| iso as u64);
+        }
+        entry
+    }
+
+    /// Camera body, told apart by serial number as in sequence manifests.
+    pub fn body(&self) -> Option<String> {
+        let camera = self.camera.as_ref()?;
+        Some(match &self.serial {
+            Some(serial) => format!("{} #{}", camera, serial),
+            None => camera.clone(),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "size": self.size,
+            "mtime": self.mtime,
+            "captured": self.captured.map(|t| t.format(TIME_FORMAT).to_string()),
+            "camera": self.camera,
+            "serial": self.serial,
+            "lens": self.lens,
+            "focal_length": self.focal_length,
+            "iso": self.iso,
+            "sequence_type": self.sequence.as_ref().map(|(label, _)| label),
+            "sequence": self.sequence.as_ref().map(|(_, folder)| folder),
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let text = |key: &str| value.get(key).and_then(Value::as_str).map(str::to_string);
+        Some(IndexEntry {
+            size: value.get("size")?.as_u64()?,
+            mtime: value.get("mtime")?.as_u64()?,
+            captured: text("captured").and_then(|t| {
+                chrono::NaiveDateTime::parse_from_str(&t, TIME_FORMAT)
+                    .ok()
+                    .map(|t| t.and_utc())
+            }),
+            camera: text("camera"),
+            serial: text("serial"),
+            lens: text("lens"),
+            focal_length: value.get("focal_length").and_then(Value::as_f64),
+            iso: value.get("iso").and_then(Value::as_u64),
+            sequence: text("sequence_type").zip(text("sequence")),
+        })
+    }
+}
+
+/// Index entries by path relative to the library.
+#[derive(Debug, Default)]
+pub struct LibraryIndex {
+    pub files: BTreeMap<String, IndexEntry>,
+}
+
+/// Key of a library file in the index.
+fn relative_key(library: &Path, file: &Path) -> Option<String> {
+    let relative = file.strip_prefix(library).ok()?;
+    Some(
+        relative
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+    )
+}
+
+impl LibraryIndex {
+    /// The library's index, empty when it has none or it can't be read.
+    pub fn load(library: &Path) -> Self {
+        let path = library.join(INDEX_PATH);
+        let Ok(content) = fs::read_to_string(&path) else {
+            return LibraryIndex::default();
+        };
+        let value: Value = match serde_json::from_str(&content) {
+            Ok(value) => value,
+            Err(e) => {
+                println!(
+                    "Warning: ignoring unreadable library index {}: {}",
+                    path.display(),
+                    e
+                );
+                return LibraryIndex::default();
+            }
+        };
+        let files = value
+            .get("files")
+            .and_then(Value::as_object)
+            .map(|files| {
+                files
+                    .iter()
+                    .filter_map(|(key, entry)| Some((key.clone(), IndexEntry::from_json(entry)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        LibraryIndex { files }
+    }
+
+    pub fn save(&self, library: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let path = library.join(INDEX_PATH);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let files: Map<String, Value> = self
+            .files
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.to_json()))
+            .collect();
+        let index = json!({ "version": INDEX_VERSION, "files": files });
+        fs::write(path, serde_json::to_string_pretty(&index)?)?;
+        Ok(())
+    }
+
+    /// Records the files an import copied or moved into the library, with the EXIF
+    /// and sequence of their group; `removed` are paths files were moved away from.
+    pub fn record_import(
+        library: &Path,
+        files: &[(&PathBuf, Option<&Value>, Option<&SequenceType>)],
+        removed: &[&PathBuf],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = LibraryIndex::load(library);
+        for file in removed {
+            if let Some(key) = relative_key(library, file) {
+                index.files.remove(&key);
+            }
+        }
+        for (file, exif, sequence) in files {
+            let Some(key) = relative_key(library, file) else {
+                continue;
+            };
+            let sequence = sequence.map(|s| (s.label().to_string(), s.folder_name().to_string()));
+            index
+                .files
+                .insert(key, IndexEntry::new(file, *exif, sequence));
+        }
+        index.save(library)
+    }
+}
+
+/// Photo files of the library, below `RAW/` and `JPEG/`.
+fn library_photos(library: &Path) -> Vec<PathBuf> {
+    ["RAW", "JPEG"]
+        .iter()
+        .flat_map(|tree| collect_all_files_recursive(&library.join(tree)))
+        .filter(|file| {
+            // Thumbnail folders and other tool data are not part of the library
+            !file
+                .iter()
+                .any(|part| part.to_string_lossy().starts_with('.'))
+                && file
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| is_raw_file(n) || is_jpeg_file(n))
+        })
+        .collect()
+}
+
+/// Sequence of the photos in `folder`, from its sequence manifest.
+fn manifest_sequence(folder: &Path) -> Option<(String, String)> {
+    let manifest: Value =
+        serde_json::from_str(&fs::read_to_string(folder.join(MANIFEST_FILE_NAME)).ok()?).ok()?;
+    let label = manifest.get("type")?.as_str()?.to_string();
+    let name = manifest.get("sequence")?.as_str()?.to_string();
+    Some((label, name))
+}
+
+/// The index brought up to date with the library: files it doesn't know or that
+/// changed are read with exiftool, files no longer there are dropped. With `rescan`
+/// every file is read again. The refreshed index is saved and returned with the number
+/// of files read and dropped.
+pub fn refresh(
+    library: &Path,
+    rescan: bool,
+) -> Result<(LibraryIndex, usize, usize), Box<dyn std::error::Error>> {
+    let mut index = if rescan {
+        LibraryIndex::default()
+    } else {
+        LibraryIndex::load(library)
+    };
+    let photos: Vec<(String, PathBuf)> = library_photos(library)
+        .into_iter()
+        .filter_map(|file| Some((relative_key(library, &file)?, file)))
+        .collect();
+
+    let stale: Vec<&(String, PathBuf)> = photos
+        .iter()
+        .filter(|(key, file)| {
+            let entry = index.files.get(key);
+            entry.is_none_or(|entry| file_stamp(file) != Some((entry.size, entry.mtime)))
+        })
+        .collect();
+
+    let pb = ProgressBar::new(stale.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) Indexing library...")
+            .expect("Failed to set progress bar style"),
+    );
+    let scanned: Vec<(String, IndexEntry)> = stale
+        .par_iter()
+        .map(|(key, file)| {
+            let exif = get_exif_data(file).ok();
+            let sequence = file.parent().and_then(manifest_sequence);
+            pb.inc(1);
+            (key.clone(), IndexEntry::new(file, exif.as_ref(), sequence))
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    let present: std::collections::HashSet<&String> = photos.iter().map(|(key, _)| key).collect();
+    let before = index.files.len();
+    index.files.retain(|key, _| present.contains(key));
+    let dropped = before - index.files.len();
+    let read = scanned.len();
+    index.files.extend(scanned);
+    index.save(library)?;
+    Ok((index, read, dropped))
+}
+
+/// Key shared by the RAW and JPEG files of one shot: the path below `RAW/` or `JPEG/`
+/// without extensions.
+pub fn shot_key(key: &str) -> String {
+    let below_tree = key.split_once('/').map_or(key, |(_, rest)| rest);
+    match below_tree.rsplit_once('/') {
+        Some((dir, name)) => format!("{}/{}", dir, file_base(name)),
+        None => file_base(below_tree).to_string(),
+    }
+}
@@ -1,21 +1,70 @@
-use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
-use clap::Parser;
+mod associate;
+mod bodies;
+mod contact_sheet;
+mod detectors;
+mod events;
+mod filters;
+mod geotag;
+mod hooks;
+mod library;
+mod manifest;
+mod mirror;
+mod places;
+mod preview;
+mod rename;
+mod report;
+mod sources;
+mod stats;
+mod thumbnails;
+mod xmp;
+
+use associate::{AssociationKind, AssociationRules, Associations};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use detectors::{DriveKind, detector_for};
+use events::{EventOptions, Events};
+use filters::{FileKind, RatingFilter, SelectionFilter};
+use geotag::{GeotagOptions, GeotagTarget};
+use hooks::{HookEvent, HookResult, LifecycleHook, SequenceHook};
 use indicatif::{ProgressBar, ProgressStyle};
+use mirror::MirrorOptions;
+use places::{Gazetteer, Place};
 use rayon::prelude::*;
 use regex::Regex;
-use serde_json::Value;
+use report::RunReport;
+use serde_json::{Value, json};
+use sources::Source;
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::LazyLock;
+use thumbnails::{ThumbnailOptions, ThumbnailSize, ThumbnailStore};
+use xmp::{SidecarName, XmpOptions};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    override_usage = concat!(
+        "photo_sorter [OPTIONS] <INPUT_DIR>... <OUTPUT_DIR>\n",
+        "       photo_sorter [OPTIONS] --input <[LABEL=]DIR>... <OUTPUT_DIR>\n",
+        "       photo_sorter <COMMAND>"
+    )
+)]
 struct Args {
-    /// Input directory path
-    input_dir: String,
-    /// Output directory path
-    output_dir: String,
+    #[command(subcommand)]
+    command: Option<Commands>,
+    /// Input directory paths (cards, folders) followed by the output directory path
+    #[arg(required = true, value_name = "DIR")]
+    dirs: Vec<String>,
+    /// Input directory, labelled as LABEL=DIR or named after its folder (repeatable)
+    #[arg(long = "input", value_name = "[LABEL=]DIR", value_parser = sources::parse_input)]
+    inputs: Vec<Source>,
     /// Print actions without copying files
     #[arg(long)]
     dry_run: bool,
@@ -28,6 +77,212 @@ struct Args {
     /// Skip files that already exist in the destination directory (instead of erroring)
     #[arg(long = "skip-existing")]
     skip_existing: bool,
+    /// Only import photos rated at least this much (in-camera or XMP sidecar rating)
+    #[arg(long = "min-rating")]
+    min_rating: Option<i64>,
+    /// Only import photos protected in camera (read-only on the card)
+    #[arg(long = "only-protected")]
+    only_protected: bool,
+    /// Only import photos with this label (in-camera or XMP sidecar); may be repeated
+    #[arg(long = "label")]
+    labels: Vec<String>,
+    /// Only import photos taken on or after this day (YYYY-MM-DD)
+    #[arg(long)]
+    since: Option<NaiveDate>,
+    /// Only import photos taken on or before this day (YYYY-MM-DD)
+    #[arg(long)]
+    until: Option<NaiveDate>,
+    /// Only import photos from cameras whose model matches this glob, e.g. "OM-1*"
+    #[arg(long)]
+    camera: Option<String>,
+    /// Only import photos taken with a lens matching this glob, e.g. "*12-40*"
+    #[arg(long)]
+    lens: Option<String>,
+    /// Only import RAW or only JPEG files (with their sidecars)
+    #[arg(long, value_enum)]
+    only: Option<FileKind>,
+    /// Leave out files matching this glob: with a "/" their path below the input
+    /// directory (e.g. "**/TMP/**"), without one their name (e.g. "*.tmp"); may be repeated
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// GPX track log to geotag copied photos from; may be repeated
+    #[arg(long = "gpx")]
+    gpx: Vec<PathBuf>,
+    /// Seconds to add to the camera clock to get UTC (e.g. -7200 for a camera set to CEST);
+    /// also corrects the dates --write-xmp writes
+    #[arg(
+        long = "gpx-time-offset",
+        default_value_t = 0,
+        allow_hyphen_values = true
+    )]
+    gpx_time_offset: i64,
+    /// Maximum time in seconds between track points to interpolate across, and between a
+    /// photo and the nearest point otherwise
+    #[arg(long = "gpx-max-gap", default_value_t = 300)]
+    gpx_max_gap: i64,
+    /// Where geotags are written
+    #[arg(long = "gpx-target", value_enum, default_value_t = GeotagTarget::Sidecar)]
+    gpx_target: GeotagTarget,
+    /// GeoNames cities file (e.g. cities15000.txt) to name places after; a countryInfo.txt
+    /// next to it supplies country names
+    #[arg(long)]
+    places: Option<PathBuf>,
+    /// Add the place name as City/Country and keywords to the XMP sidecar of copied photos
+    #[arg(long = "place-keywords")]
+    place_keywords: bool,
+    /// Name of the day folder, starting with {day}; may also use {city}, {country} and
+    /// {event} (e.g. "{day} {city}")
+    #[arg(long = "day-folder", default_value = "{day}", value_parser = parse_day_folder)]
+    day_folder: String,
+    /// Hours without photos that start a new event (used when --day-folder has {event})
+    #[arg(long = "event-gap-hours", default_value_t = 8)]
+    event_gap_hours: i64,
+    /// Also start a new event when consecutive photos are further apart than this
+    #[arg(long = "event-distance-km")]
+    event_distance_km: Option<f64>,
+    /// Name of detected events, from {date}, {time}, {n} (number within the day), {city}
+    /// and {country}
+    #[arg(long = "event-name", default_value = "{time} {city}", value_parser = events::parse_event_name)]
+    event_name: String,
+    /// File of event names (ID<TAB>NAME); written with the detected events when missing
+    /// (a run that isn't a dry run stops there), applied when present
+    #[arg(long = "event-names")]
+    event_names: Option<PathBuf>,
+    /// Ask for a name for every detected event before importing
+    #[arg(long = "rename-events")]
+    rename_events: bool,
+    /// Create or update an XMP sidecar for every imported RAW file with keywords (camera,
+    /// sequence, event, import run), creator, copyright and capture date
+    #[arg(long = "write-xmp")]
+    write_xmp: bool,
+    /// Creator written to XMP sidecars
+    #[arg(long)]
+    creator: Option<String>,
+    /// Copyright notice written to XMP sidecars
+    #[arg(long)]
+    copyright: Option<String>,
+    /// Naming of new XMP sidecars: NAME.xmp (base) or NAME.ORF.xmp (full); existing
+    /// sidecars are updated whatever their name
+    #[arg(long = "sidecar-name", value_enum, default_value_t = SidecarName::Base)]
+    sidecar_name: SidecarName,
+    /// Extra extension of sidecar files to keep with their photo (xmp, dop, pp3, cos, ...
+    /// are built in); may be repeated
+    #[arg(long = "sidecar-ext")]
+    sidecar_exts: Vec<String>,
+    /// Extra regex for the suffix an editor adds to derivatives (-Edit and " (1)" are
+    /// built in; add "_\d+" or "-\d+" for numbered copies); may be repeated
+    #[arg(long = "derivative-suffix")]
+    derivative_suffixes: Vec<String>,
+    /// Extra glob of editor subfolders holding sidecars, relative to the photos
+    /// (CaptureOne/Settings* is built in); may be repeated
+    #[arg(long = "editor-dir")]
+    editor_dirs: Vec<String>,
+    /// Rename every photo group on import, from {date[:FORMAT]}, {subsec}, {camera},
+    /// {counter} and {original} (e.g. "{date:%Y%m%d_%H%M%S}_{subsec}_{camera}_{counter}");
+    /// sidecars and derivatives keep sharing the photo's new name
+    #[arg(long, value_parser = rename::parse_rename)]
+    rename: Option<String>,
+    /// Record the original file name as PreservedFileName in the XMP sidecar of renamed
+    /// photos
+    #[arg(long = "preserve-filename", requires = "rename")]
+    preserve_filename: bool,
+    /// Write the embedded full-size JPEG preview of every RAW file imported without a
+    /// JPEG into the JPEG tree, with the RAW file's metadata
+    #[arg(long = "extract-previews")]
+    extract_previews: bool,
+    /// Folder of a mirror library with a small, upright JPEG copy of every imported JPEG
+    /// and extracted preview, in the same layout
+    #[arg(long)]
+    mirror: Option<PathBuf>,
+    /// Long edge of mirror copies in pixels
+    #[arg(long = "mirror-size", default_value_t = 2048)]
+    mirror_size: u32,
+    /// JPEG quality of mirror copies (1-100)
+    #[arg(long = "mirror-quality", default_value_t = 85, value_parser = clap::value_parser!(u8).range(1..=100))]
+    mirror_quality: u8,
+    /// Leave GPS position out of mirror copies
+    #[arg(long = "mirror-strip-gps", requires = "mirror")]
+    mirror_strip_gps: bool,
+    /// Pre-generate freedesktop thumbnails of imported photos, in the user's thumbnail
+    /// cache or in a .sh_thumbnails folder next to the photos
+    #[arg(long, value_enum)]
+    thumbnails: Option<ThumbnailStore>,
+    /// Thumbnail sizes to generate, comma-separated
+    #[arg(long = "thumbnail-size", value_enum, value_delimiter = ',', default_values_t = [ThumbnailSize::Normal, ThumbnailSize::Large])]
+    thumbnail_sizes: Vec<ThumbnailSize>,
+    /// Write an HTML contact sheet of the imported photos, by day and sequence, into the
+    /// output directory
+    #[arg(long = "contact-sheet")]
+    contact_sheet: bool,
+    /// Maximum gap in milliseconds between frames of a time-based burst, at least a
+    /// second for frames without sub-second times (0 disables it)
+    #[arg(long = "burst-gap-ms", default_value_t = 500)]
+    burst_gap_ms: u64,
+    /// Minimum number of frames for a time-based burst
+    #[arg(long = "burst-min-frames", default_value_t = 3)]
+    burst_min_frames: usize,
+    /// Maximum gap in seconds between frames of a panorama detected from matching
+    /// exposure settings (0 disables it; vendor panorama tags are always used)
+    #[arg(long = "panorama-gap-secs", default_value_t = 0)]
+    panorama_gap_secs: u64,
+    /// Minimum number of frames for a panorama detected from matching exposure settings
+    #[arg(long = "panorama-min-frames", default_value_t = 3)]
+    panorama_min_frames: usize,
+    /// Command to run on each sequence of a type once the import is done, as
+    /// TYPE[:TREE]=COMMAND (e.g. 'HDR=enfuse -o "$SEQUENCE_NAME.tif" "$@"'); TREE is raw,
+    /// jpeg or both, by default the RAW copy or else the JPEG one; may be repeated
+    #[arg(long = "sequence-hook", value_name = "TYPE[:TREE]=COMMAND")]
+    sequence_hooks: Vec<SequenceHook>,
+    /// Command to run at an import event, as EVENT[:POLICY]=COMMAND with EVENT one of
+    /// pre-import, post-copy, post-import and POLICY one of abort, warn (default), ignore;
+    /// the event is passed as JSON on stdin; may be repeated. A post-copy abort stops
+    /// copying at once, leaving a partial import without a report
+    #[arg(long = "hook", value_name = "EVENT[:POLICY]=COMMAND")]
+    hooks: Vec<LifecycleHook>,
+    /// Maximum number of sequence hooks running at the same time
+    #[arg(long = "hook-jobs", default_value_t = 2)]
+    hook_jobs: usize,
+    /// Write a JSON report of the run to this file
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Don't write a sequence.json manifest into sequence folders
+    #[arg(long = "no-sequence-manifests")]
+    no_sequence_manifests: bool,
+    /// How frames are split into per-camera streams for sequence detection
+    #[arg(long = "camera-key", value_enum, default_value_t = CameraKey::Serial)]
+    camera_key: CameraKey,
+    /// Which date decides the day folder of a sequence's frames
+    #[arg(long = "sequence-date", value_enum, default_value_t = SequenceDate::FirstFrame)]
+    sequence_date: SequenceDate,
+    /// Which members of RAW+JPEG pairs to import; sidecars of a dropped photo follow a
+    /// kept one, or are dropped when they name the dropped file. Derivatives (edited
+    /// copies) are imported with their shot whatever their format
+    #[arg(long = "pair-policy", value_enum, default_value_t = PairPolicy::Both)]
+    pair_policy: PairPolicy,
+    /// Folder suffix for HDR sequences
+    #[arg(long = "hdr-suffix", default_value = "_HDR")]
+    hdr_suffix: String,
+    /// Folder suffix for burst sequences
+    #[arg(long = "burst-suffix", default_value = "_BURST")]
+    burst_suffix: String,
+    /// Folder suffix for focus brackets and stacks
+    #[arg(long = "focus-suffix", default_value = "_FOCUS")]
+    focus_suffix: String,
+    /// Folder suffix for panoramas
+    #[arg(long = "panorama-suffix", default_value = "_PANO")]
+    panorama_suffix: String,
+    /// Folder suffix for pixel-shift / high-res captures
+    #[arg(long = "pixel-shift-suffix", default_value = "_HIRES")]
+    pixel_shift_suffix: String,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Show shots per day, camera, lens, focal length and ISO, and sizes of a library;
+    /// rewrites the library's .photo_sorter/index.json
+    Stats(stats::StatsArgs),
+    /// Show the shutter count history of every camera body imported into a library
+    Bodies(bodies::BodiesArgs),
 }
 
 #[derive(Debug)]
@@ -36,41 +291,265 @@ struct ValidationError {
     reason: String,
 }
 
+/// Tokens available in `--day-folder`.
+const DAY_FOLDER_TOKENS: [&str; 4] = ["day", "city", "country", "event"];
+
+static DAY_FOLDER_TOKEN_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{(\w+)\}").expect("Invalid regex for day folder token"));
+
+fn parse_day_folder(template: &str) -> Result<String, String> {
+    // The day number has to lead so incremental mode can still read the folder back
+    if !template.starts_with("{day}") {
+        return Err("the day folder must start with {day}".to_string());
+    }
+    if template.contains('/') {
+        return Err("the day folder can't contain '/'".to_string());
+    }
+    for captures in DAY_FOLDER_TOKEN_RE.captures_iter(template) {
+        let token = &captures[1];
+        if !DAY_FOLDER_TOKENS.contains(&token) {
+            return Err(format!(
+                "unknown token {{{}}} (expected one of {})",
+                token,
+                DAY_FOLDER_TOKENS.map(|t| format!("{{{}}}", t)).join(", ")
+            ));
+        }
+    }
+    Ok(template.to_string())
+}
+
+/// Day folder for `date`, `place` and `event` after `template`. Tokens without a value
+/// are dropped together with the separators left dangling around them.
+fn day_folder_name(
+    template: &str,
+    date: DateTime<Utc>,
+    place: Option<&Place>,
+    event: Option<&str>,
+) -> String {
+    let name = DAY_FOLDER_TOKEN_RE.replace_all(template, |captures: &regex::Captures| {
+        let value = match &captures[1] {
+            "day" => date.format("%d").to_string(),
+            "city" => place.map(|p| p.city.clone()).unwrap_or_default(),
+            "country" => place.map(|p| p.country.clone()).unwrap_or_default(),
+            "event" => event.unwrap_or_default().to_string(),
+            _ => String::new(),
+        };
+        value.replace('/', "-")
+    });
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    name.trim_end_matches([' ', ',', '-', '_']).to_string()
+}
+
+/// How sequence members that straddle midnight are dated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SequenceDate {
+    /// Every member goes to the day of the sequence's first frame
+    FirstFrame,
+    /// Every member goes to its own capture day
+    PerFrame,
+}
+
+/// Which members of a RAW+JPEG pair are imported, and where.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PairPolicy {
+    /// RAW files to RAW/, JPEG files to JPEG/
+    Both,
+    /// Only RAW files
+    RawOnly,
+    /// Only JPEG files
+    JpegOnly,
+    /// RAW files, and JPEG files of shots without a RAW
+    JpegIfNoRaw,
+    /// Both, with the JPEG of a pair next to its RAW in RAW/
+    RawWithJpegInSameFolder,
+}
+
+impl PairPolicy {
+    /// Whether a camera-original photo of a group is imported.
+    fn keeps(self, filename: &str, group_has_raw: bool) -> bool {
+        match self {
+            PairPolicy::Both | PairPolicy::RawWithJpegInSameFolder => true,
+            PairPolicy::RawOnly => is_raw_file(filename),
+            PairPolicy::JpegOnly => is_jpeg_file(filename),
+            PairPolicy::JpegIfNoRaw => is_raw_file(filename) || !group_has_raw,
+        }
+    }
+}
+
+/// Settings that control how groups are mapped onto destination paths.
+#[derive(Debug, Clone, Copy)]
+struct PlanOptions<'a> {
+    cutoff_date: Option<DateTime<Utc>>,
+    force_override: bool,
+    skip_existing: bool,
+    dry_run: bool,
+    sequence_date: SequenceDate,
+    pair_policy: PairPolicy,
+    day_folder: &'a str,
+    places: &'a HashMap<String, Place>,
+    events: &'a Events,
+    associations: &'a Associations,
+    /// `--rename` template, if files get new names.
+    rename: Option<&'a str>,
+}
+
+/// Result of the validation pass: what to copy, what to move and what was skipped.
+#[derive(Debug, Default)]
+struct CopyPlan {
+    copies: Vec<(PathBuf, PathBuf)>,
+    moves: Vec<(PathBuf, PathBuf)>,
+    skipped_cutoff: usize,
+    skipped_existing: usize,
+    /// Files left out by `--pair-policy`.
+    skipped_pair_policy: usize,
+    /// New base name of every renamed group.
+    renames: HashMap<String, String>,
+    /// Files not copied because an identical file goes to the same place, with that file.
+    duplicates: Vec<(PathBuf, PathBuf)>,
+}
+
+/// What tells two camera bodies apart when splitting an import into streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CameraKey {
+    /// Serial number, falling back to the model when it is missing
+    Serial,
+    /// Camera model
+    Model,
+    /// Source folder of the file
+    Folder,
+}
+
+/// Settings for the sequence detection passes.
+#[derive(Debug, Clone)]
+struct SequenceOptions {
+    camera_key: CameraKey,
+    /// Frames from one body closer than this are a burst candidate; `None` disables the time-gap pass.
+    burst_gap: Option<Duration>,
+    burst_min_frames: usize,
+    /// Same for panoramas detected from matching exposure settings.
+    panorama_gap: Option<Duration>,
+    panorama_min_frames: usize,
+    hdr_suffix: String,
+    burst_suffix: String,
+    focus_suffix: String,
+    panorama_suffix: String,
+    pixel_shift_suffix: String,
+}
+
+/// Per-photo facts gathered from the EXIF cache for sequence detection.
+struct PhotoInfo {
+    base: String,
+    burst_seq_num: u32,
+    hdr_shot_num: Option<u32>,
+    date: DateTime<Utc>,
+    capture_time: Option<DateTime<Utc>>,
+    /// Whether `capture_time` has sub-second precision.
+    subsec_time: bool,
+    camera: String,
+    drive: DriveKind,
+    detector: &'static str,
+    focus_shot_num: Option<u32>,
+    focus_stacked: bool,
+    panorama_shot_num: Option<u32>,
+    pixel_shift_shot_num: Option<u32>,
+    pixel_shift_composite: bool,
+    /// Shutter speed, aperture, ISO and focal length, when all are known.
+    exposure_key: Option<String>,
+    exposure_compensation: Option<String>,
+}
+
+/// One frame of a detected sequence, as recorded in the sequence manifest.
+#[derive(Debug, Clone)]
+struct SequenceFrame {
+    base: String,
+    capture_time: Option<DateTime<Utc>>,
+    exposure_compensation: Option<String>,
+}
+
+/// Everything detection knows about one sequence folder.
+#[derive(Debug, Clone)]
+struct SequenceRecord {
+    seq_type: SequenceType,
+    /// Which detection rule grouped the frames, e.g. "bracket-shot-number".
+    rule: &'static str,
+    detector: &'static str,
+    camera: String,
+    /// Frames in shot order.
+    frames: Vec<SequenceFrame>,
+}
+
 #[derive(Debug, Clone)]
 enum SequenceType {
-    Burst(String), // folder name
-    Hdr(String),   // folder name
+    Burst(String),      // folder name
+    Hdr(String),        // folder name
+    FocusStack(String), // folder name
+    Panorama(String),   // folder name
+    PixelShift(String), // folder name
+}
+
+/// Labels returned by `SequenceType::label`, in reporting order.
+const SEQUENCE_LABELS: [&str; 5] = ["HDR", "BURST", "FOCUS", "PANORAMA", "PIXEL-SHIFT"];
+
+impl SequenceType {
+    fn folder_name(&self) -> &str {
+        match self {
+            SequenceType::Burst(folder_name)
+            | SequenceType::Hdr(folder_name)
+            | SequenceType::FocusStack(folder_name)
+            | SequenceType::Panorama(folder_name)
+            | SequenceType::PixelShift(folder_name) => folder_name,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            SequenceType::Burst(_) => "BURST",
+            SequenceType::Hdr(_) => "HDR",
+            SequenceType::FocusStack(_) => "FOCUS",
+            SequenceType::Panorama(_) => "PANORAMA",
+            SequenceType::PixelShift(_) => "PIXEL-SHIFT",
+        }
+    }
+
+    /// The same kind of sequence in another folder.
+    fn with_folder_name(&self, folder_name: String) -> SequenceType {
+        match self {
+            SequenceType::Burst(_) => SequenceType::Burst(folder_name),
+            SequenceType::Hdr(_) => SequenceType::Hdr(folder_name),
+            SequenceType::FocusStack(_) => SequenceType::FocusStack(folder_name),
+            SequenceType::Panorama(_) => SequenceType::Panorama(folder_name),
+            SequenceType::PixelShift(_) => SequenceType::PixelShift(folder_name),
+        }
+    }
 }
 
-fn check_exiftool_installed() -> Result<(), Box<dyn std::error::Error>> {
+/// Version of the installed exiftool.
+fn check_exiftool_installed() -> Result<String, Box<dyn std::error::Error>> {
     match Command::new("exiftool").arg("-ver").output() {
         Ok(output) => {
             if output.status.success() {
-                let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                println!("Found exiftool version: {}", version);
-                Ok(())
+                Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
             } else {
                 Err("exiftool command failed to execute properly".into())
             }
         }
-        Err(_) => {
-            Err("exiftool is not installed or not found in PATH. 
+        Err(_) => Err("exiftool is not installed or not found in PATH. 
 
 Please install exiftool to use this program:
 - On Ubuntu/Debian: sudo apt install libimage-exiftool-perl
 - On macOS: brew install exiftool
-- On other systems: https://exiftool.org/install.html".into())
-        }
+- On other systems: https://exiftool.org/install.html"
+            .into()),
     }
 }
 
-fn get_exif_data(file_path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
+pub(crate) fn get_exif_data(file_path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
     let output = Command::new("exiftool").arg("-j").arg(file_path).output()?;
     let json: Vec<Value> = serde_json::from_slice(&output.stdout)?;
     Ok(json.into_iter().next().unwrap_or(Value::Null))
 }
 
-fn get_exif_date(exif: &Value) -> Option<DateTime<Utc>> {
+pub(crate) fn get_exif_date(exif: &Value) -> Option<DateTime<Utc>> {
     if let Some(date_str) = exif.get("DateTimeOriginal").and_then(|v| v.as_str()) {
         // Take only the first 19 characters (standard EXIF format: "YYYY:MM:DD HH:MM:SS")
         // This also handles variants with sub-seconds or timezone offsets
@@ -90,38 +569,66 @@ fn get_exif_date(exif: &Value) -> Option<DateTime<Utc>> {
     None
 }
 
-fn get_sequence_info(exif: &Value, re: &Regex) -> u32 {
-    if let Some(special_mode) = exif.get("SpecialMode").and_then(|v| v.as_str()) {
-        if let Some(captures) = re.captures(special_mode) {
-            if let Some(seq_str) = captures.get(1) {
-                if let Ok(seq) = seq_str.as_str().parse::<u32>() {
-                    return seq;
-                }
-            }
-        }
+/// Capture time with sub-second precision taken from `SubSecTimeOriginal` when present.
+pub(crate) fn get_exif_capture_time(exif: &Value) -> Option<DateTime<Utc>> {
+    let date = get_exif_date(exif)?;
+    Some(date + Duration::nanoseconds(get_exif_subsec_nanos(exif).unwrap_or(0)))
+}
+
+/// Fraction of a second from `SubSecTimeOriginal`, in nanoseconds.
+fn get_exif_subsec_nanos(exif: &Value) -> Option<i64> {
+    let subsec = match exif.get("SubSecTimeOriginal")? {
+        Value::String(s) => s.trim().to_string(),
+        Value::Number(n) => n.to_string(),
+        _ => return None,
+    };
+    let digits: String = subsec
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .take(9)
+        .collect();
+    if digits.is_empty() {
+        return None;
     }
-    0
+    // "12" means 0.12s, so pad on the right to nanoseconds
+    format!("{:0<9}", digits).parse().ok()
 }
 
-fn get_hdr_info(exif: &Value, hdr_re: &Regex) -> Option<u32> {
-    if let Some(drive_mode) = exif.get("DriveMode").and_then(|v| v.as_str()) {
-        let is_bracket = drive_mode.contains("Bracket")
-            || drive_mode.contains("Bracketing")
-            || drive_mode.contains("HDR");
-        if is_bracket {
-            if let Some(captures) = hdr_re.captures(drive_mode) {
-                if let Some(shot_str) = captures.get(1) {
-                    if let Ok(shot_num) = shot_str.as_str().parse::<u32>() {
-                        return Some(shot_num);
-                    }
-                }
-            }
-        }
+/// Shutter speed, aperture, ISO and focal length as one comparable string.
+fn get_exposure_key(exif: &Value) -> Option<String> {
+    let parts: Option<Vec<String>> = ["ExposureTime", "FNumber", "ISO", "FocalLength"]
+        .iter()
+        .map(|tag| match exif.get(*tag)? {
+            Value::String(s) => Some(s.trim().to_string()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        })
+        .collect();
+    parts.map(|parts| parts.join(" "))
+}
+
+/// Identifies the camera a photo was taken with, as selected by `--camera-key`.
+fn get_camera_id(exif: &Value, photo_file: &Path, key: CameraKey) -> String {
+    let tag = |name: &str| match exif.get(name) {
+        Some(Value::String(s)) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    };
+    let model = tag("Model").unwrap_or_else(|| "unknown".to_string());
+    match key {
+        CameraKey::Serial => match tag("SerialNumber").or_else(|| tag("InternalSerialNumber")) {
+            Some(serial) => format!("{} #{}", model, serial),
+            None => model,
+        },
+        CameraKey::Model => model,
+        CameraKey::Folder => photo_file
+            .parent()
+            .map(|parent| parent.display().to_string())
+            .unwrap_or_default(),
     }
-    None
 }
 
-fn is_raw_file(filename: &str) -> bool {
+pub(crate) fn is_raw_file(filename: &str) -> bool {
     let ext = filename.to_lowercase();
     ext.ends_with(".cr2")
         || ext.ends_with(".nef")
@@ -129,9 +636,14 @@ fn is_raw_file(filename: &str) -> bool {
         || ext.ends_with(".dng")
         || ext.ends_with(".raw")
         || ext.ends_with(".orf")
+        || ext.ends_with(".ori")
+        || ext.ends_with(".raf")
+        || ext.ends_with(".rw2")
+        || ext.ends_with(".cr3")
+        || ext.ends_with(".3fr")
 }
 
-fn is_jpeg_file(filename: &str) -> bool {
+pub(crate) fn is_jpeg_file(filename: &str) -> bool {
     let ext = filename.to_lowercase();
     ext.ends_with(".jpg") || ext.ends_with(".jpeg")
 }
@@ -156,59 +668,60 @@ fn find_most_recent_file_in_destination(
             .filter_map(|entry| {
                 let entry = entry.ok()?;
                 let path = entry.path();
-                if path.is_dir() {
-                    if let Some(year_name) = path.file_name()?.to_str() {
-                        if let Ok(year) = year_name.parse::<u32>() {
-                            return Some((year, path));
-                        }
-                    }
+                if path.is_dir()
+                    && let Some(year_name) = path.file_name()?.to_str()
+                    && let Ok(year) = year_name.parse::<u32>()
+                {
+                    return Some((year, path));
                 }
                 None
             })
             .collect();
 
         // Sort years in descending order (most recent first)
-        year_dirs.sort_by(|a, b| b.0.cmp(&a.0));
+        year_dirs.sort_by_key(|d| std::cmp::Reverse(d.0));
 
         'outer: for (_year, year_dir) in year_dirs {
             let mut month_dirs: Vec<_> = fs::read_dir(&year_dir)?
                 .filter_map(|entry| {
                     let entry = entry.ok()?;
                     let path = entry.path();
-                    if path.is_dir() {
-                        if let Some(month_name) = path.file_name()?.to_str() {
-                            if let Ok(month) = month_name.parse::<u32>() {
-                                return Some((month, path));
-                            }
-                        }
+                    if path.is_dir()
+                        && let Some(month_name) = path.file_name()?.to_str()
+                        && let Ok(month) = month_name.parse::<u32>()
+                    {
+                        return Some((month, path));
                     }
                     None
                 })
                 .collect();
 
             // Sort months in descending order (most recent first)
-            month_dirs.sort_by(|a, b| b.0.cmp(&a.0));
+            month_dirs.sort_by_key(|d| std::cmp::Reverse(d.0));
 
             for (_month, month_dir) in month_dirs {
                 let mut day_dirs: Vec<_> = fs::read_dir(&month_dir)?
                     .filter_map(|entry| {
                         let entry = entry.ok()?;
                         let path = entry.path();
-                        if path.is_dir() {
-                            if let Some(day_name) = path.file_name()?.to_str() {
-                                if let Ok(day) = day_name.parse::<u32>() {
-                                    return Some((day, path));
-                                }
-                            }
+                        if path.is_dir()
+                            && let Some(day_name) = path.file_name()?.to_str()
+                            && let Ok(day) = day_name
+                                .split(|c: char| !c.is_ascii_digit())
+                                .next()
+                                .unwrap_or_default()
+                                .parse::<u32>()
+                        {
+                            return Some((day, path));
                         }
                         None
                     })
                     .collect();
 
                 // Sort days in descending order (most recent first)
-                day_dirs.sort_by(|a, b| b.0.cmp(&a.0));
+                day_dirs.sort_by_key(|d| std::cmp::Reverse(d.0));
 
-                for (_day, day_dir) in day_dirs {
+                for (index, (day, day_dir)) in day_dirs.iter().enumerate() {
                     // Check all files and subdirectories in this day directory
                     fn check_directory_for_photos(
                         dir: &Path,
@@ -222,34 +735,29 @@ fn find_most_recent_file_in_destination(
                             if path.is_dir() {
                                 // Recursively check subdirectories (for sequence folders)
                                 check_directory_for_photos(&path, most_recent, files_checked)?;
-                            } else if path.is_file() {
-                                if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                                    if is_raw_file(filename) || is_jpeg_file(filename) {
-                                        *files_checked += 1;
-
-                                        // First try to get EXIF date
-                                        if let Ok(exif) = get_exif_data(&path) {
-                                            if let Some(exif_date) = get_exif_date(&exif) {
-                                                if most_recent
-                                                    .map_or(true, |current| exif_date > current)
-                                                {
-                                                    *most_recent = Some(exif_date);
-                                                }
-                                                continue;
-                                            }
-                                        }
-
-                                        // Fall back to modification time
-                                        if let Ok(metadata) = fs::metadata(&path) {
-                                            if let Ok(mtime) = metadata.modified() {
-                                                let mtime_dt = DateTime::<Utc>::from(mtime);
-                                                if most_recent
-                                                    .map_or(true, |current| mtime_dt > current)
-                                                {
-                                                    *most_recent = Some(mtime_dt);
-                                                }
-                                            }
-                                        }
+                            } else if path.is_file()
+                                && let Some(filename) = path.file_name().and_then(|n| n.to_str())
+                                && (is_raw_file(filename) || is_jpeg_file(filename))
+                            {
+                                *files_checked += 1;
+
+                                // First try to get EXIF date
+                                if let Ok(exif) = get_exif_data(&path)
+                                    && let Some(exif_date) = get_exif_date(&exif)
+                                {
+                                    if most_recent.is_none_or(|current| exif_date > current) {
+                                        *most_recent = Some(exif_date);
+                                    }
+                                    continue;
+                                }
+
+                                // Fall back to modification time
+                                if let Ok(metadata) = fs::metadata(&path)
+                                    && let Ok(mtime) = metadata.modified()
+                                {
+                                    let mtime_dt = DateTime::<Utc>::from(mtime);
+                                    if most_recent.is_none_or(|current| mtime_dt > current) {
+                                        *most_recent = Some(mtime_dt);
                                     }
                                 }
                             }
@@ -257,15 +765,15 @@ fn find_most_recent_file_in_destination(
                         Ok(())
                     }
 
-                    check_directory_for_photos(
-                        &day_dir,
-                        &mut most_recent_date,
-                        &mut files_checked,
-                    )?;
+                    check_directory_for_photos(day_dir, &mut most_recent_date, &mut files_checked)?;
 
                     // If we found files in this day and we're going in reverse chronological order,
-                    // we can be confident this is the most recent date
-                    if most_recent_date.is_some() {
+                    // we can be confident this is the most recent date, once every folder of the
+                    // day ("15 Lisbon", "15 Sintra") has been checked
+                    let last_of_day = day_dirs
+                        .get(index + 1)
+                        .is_none_or(|(next_day, _)| next_day != day);
+                    if most_recent_date.is_some() && last_of_day {
                         break 'outer;
                     }
                 }
@@ -288,7 +796,7 @@ fn find_most_recent_file_in_destination(
     Ok(most_recent_date)
 }
 
-fn collect_all_files_recursive(directory: &Path) -> Vec<PathBuf> {
+pub(crate) fn collect_all_files_recursive(directory: &Path) -> Vec<PathBuf> {
     let mut all_files = Vec::new();
 
     fn collect_recursive(dir: &Path, files: &mut Vec<PathBuf>) {
@@ -308,37 +816,41 @@ fn collect_all_files_recursive(directory: &Path) -> Vec<PathBuf> {
     all_files
 }
 
-fn group_files_by_base(directory: &Path) -> HashMap<String, Vec<PathBuf>> {
-    let all_files = collect_all_files_recursive(directory);
-    let mut groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
-
-    let pb = ProgressBar::new(all_files.len() as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) Grouping files...")
-            .expect("Failed to set progress bar style"),
+fn group_files_by_base(
+    all_files: &[PathBuf],
+    rules: &AssociationRules,
+) -> (HashMap<String, Vec<PathBuf>>, Associations) {
+    let (groups, associations) = associate::associate(all_files, rules);
+    println!(
+        "Grouped {} file(s) into {} photo group(s).",
+        all_files.len(),
+        groups.len()
     );
-
-    for file_path in all_files {
-        if let Some(filename) = file_path.file_name().and_then(|n| n.to_str()) {
-            let base = filename.split('.').next().unwrap_or("").to_string();
-            groups.entry(base).or_default().push(file_path);
+    if !associations.orphans.is_empty() {
+        println!(
+            "{} sidecar(s) without a photo next to them:",
+            associations.orphans.len()
+        );
+        for orphan in &associations.orphans {
+            println!("  {}", orphan.display());
         }
-        pb.inc(1);
     }
-
-    pb.finish_with_message("File grouping complete");
-    groups
+    (groups, associations)
 }
 
-fn cache_exif_data(groups: &HashMap<String, Vec<PathBuf>>) -> HashMap<String, (PathBuf, Value)> {
+fn cache_exif_data(
+    groups: &HashMap<String, Vec<PathBuf>>,
+    associations: &Associations,
+) -> HashMap<String, (PathBuf, Value)> {
     let mut representative_files = Vec::new();
     for (base, file_list) in groups {
+        // Derivatives may have lost the maker notes sequence detection relies on
         let photo_files: Vec<PathBuf> = file_list
             .iter()
             .filter(|f| {
                 if let Some(filename) = f.file_name().and_then(|n| n.to_str()) {
-                    is_raw_file(filename) || is_jpeg_file(filename)
+                    (is_raw_file(filename) || is_jpeg_file(filename))
+                        && !associations.is_derivative(f)
                 } else {
                     false
                 }
@@ -388,15 +900,308 @@ fn cache_exif_data(groups: &HashMap<String, Vec<PathBuf>>) -> HashMap<String, (P
     exif_cache
 }
 
+/// Records every member of a finished run under `seq_type`, if the run has more than one photo.
+fn finish_sequence(
+    members: &[String],
+    seq_type: SequenceType,
+    sequences: &mut HashMap<String, SequenceType>,
+) {
+    if members.len() > 1 {
+        for member in members {
+            sequences.insert(member.clone(), seq_type.clone());
+        }
+    }
+}
+
+/// Groups frames whose vendor shot numbers run 1, 2, 3... in capture order.
+/// `attach` marks a frame that closes the current run as its last member
+/// (such as an in-camera merged result).
+fn detect_numbered_sequences(
+    photo_info: &[PhotoInfo],
+    sequences: &mut HashMap<String, SequenceType>,
+    shot_num: impl Fn(&PhotoInfo) -> Option<u32>,
+    attach: impl Fn(&PhotoInfo) -> bool,
+    make_type: impl Fn(&str) -> SequenceType,
+) {
+    let mut current_sequence: Vec<String> = Vec::new();
+    let mut expected_shot = 1u32;
+
+    let finish = |members: &[String], sequences: &mut HashMap<String, SequenceType>| {
+        if let Some(first) = members.first() {
+            finish_sequence(members, make_type(first), sequences);
+        }
+    };
+
+    for info in photo_info {
+        if sequences.contains_key(&info.base) {
+            continue;
+        }
+
+        match shot_num(info) {
+            Some(1) => {
+                finish(&current_sequence, sequences);
+                current_sequence = vec![info.base.clone()];
+                expected_shot = 2;
+            }
+            Some(shot) if shot == expected_shot && !current_sequence.is_empty() => {
+                current_sequence.push(info.base.clone());
+                expected_shot += 1;
+            }
+            _ => {
+                if attach(info) && current_sequence.len() > 1 {
+                    current_sequence.push(info.base.clone());
+                }
+                finish(&current_sequence, sequences);
+                current_sequence.clear();
+                expected_shot = 1;
+            }
+        }
+    }
+
+    finish(&current_sequence, sequences);
+}
+
+/// Splits one camera's frames, in capture order, into runs where consecutive
+/// frames are at most `max_gap` apart and `same_run` holds between neighbours.
+/// Frames already in a sequence, without a capture time or not `eligible` end the run.
+fn detect_time_runs(
+    photo_info: &[PhotoInfo],
+    sequences: &HashMap<String, SequenceType>,
+    max_gap: impl Fn(&PhotoInfo, &PhotoInfo) -> Duration,
+    eligible: impl Fn(&PhotoInfo) -> bool,
+    same_run: impl Fn(&PhotoInfo, &PhotoInfo) -> bool,
+) -> Vec<Vec<String>> {
+    let mut ordered: Vec<&PhotoInfo> = photo_info.iter().collect();
+    ordered.sort_by(|a, b| {
+        a.capture_time
+            .unwrap_or(a.date)
+            .cmp(&b.capture_time.unwrap_or(b.date))
+            .then_with(|| a.base.cmp(&b.base))
+    });
+
+    let mut runs: Vec<Vec<String>> = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut last: Option<(&PhotoInfo, DateTime<Utc>)> = None;
+    for info in ordered {
+        let Some(time) = info.capture_time else {
+            runs.push(std::mem::take(&mut current));
+            last = None;
+            continue;
+        };
+        if sequences.contains_key(&info.base) || !eligible(info) {
+            runs.push(std::mem::take(&mut current));
+            last = None;
+            continue;
+        }
+        if let Some((prev, last_time)) = last
+            && (time - last_time > max_gap(prev, info) || !same_run(prev, info))
+        {
+            runs.push(std::mem::take(&mut current));
+        }
+        current.push(info.base.clone());
+        last = Some((info, time));
+    }
+    runs.push(current);
+    runs.retain(|run| !run.is_empty());
+    runs
+}
+
+/// Exposure brackets: frames whose bracket shot numbers run 1, 2, 3...
+fn detect_hdr_sequences(
+    photo_info: &[PhotoInfo],
+    sequences: &mut HashMap<String, SequenceType>,
+    options: &SequenceOptions,
+) {
+    // First pass: detect HDR sequences
+    detect_numbered_sequences(
+        photo_info,
+        sequences,
+        |info| info.hdr_shot_num,
+        |_| false,
+        |name| SequenceType::Hdr(format!("{}{}", name, options.hdr_suffix)),
+    );
+}
+
+/// Focus brackets, pixel-shift captures and panoramas numbered by the vendor detectors.
+fn detect_vendor_sequences(
+    photo_info: &[PhotoInfo],
+    sequences: &mut HashMap<String, SequenceType>,
+    options: &SequenceOptions,
+) {
+    // Second pass: numbered sequences from the vendor detectors. An in-camera
+    // stacked result written right after the last focus frame joins that sequence.
+    detect_numbered_sequences(
+        photo_info,
+        sequences,
+        |info| info.focus_shot_num,
+        |info| info.focus_stacked,
+        |name| SequenceType::FocusStack(format!("{}{}", name, options.focus_suffix)),
+    );
+    detect_numbered_sequences(
+        photo_info,
+        sequences,
+        |info| info.pixel_shift_shot_num,
+        |_| false,
+        |name| SequenceType::PixelShift(format!("{}{}", name, options.pixel_shift_suffix)),
+    );
+    detect_numbered_sequences(
+        photo_info,
+        sequences,
+        |info| info.panorama_shot_num,
+        |_| false,
+        |name| SequenceType::Panorama(format!("{}{}", name, options.panorama_suffix)),
+    );
+
+    // Single-file high-res composites get a folder of their own
+    for info in photo_info {
+        if info.pixel_shift_composite && !sequences.contains_key(&info.base) {
+            sequences.insert(
+                info.base.clone(),
+                SequenceType::PixelShift(format!("{}{}", info.base, options.pixel_shift_suffix)),
+            );
+        }
+    }
+}
+
+/// Bursts numbered by the camera (only for photos not already in a sequence).
+fn detect_burst_sequences(
+    photo_info: &[PhotoInfo],
+    sequences: &mut HashMap<String, SequenceType>,
+    options: &SequenceOptions,
+) {
+    // Third pass: detect burst sequences; frame number 0 means a single shot
+    detect_numbered_sequences(
+        photo_info,
+        sequences,
+        |info| Some(info.burst_seq_num).filter(|num| *num > 0),
+        |_| false,
+        |name| SequenceType::Burst(format!("{}{}", name, options.burst_suffix)),
+    );
+}
+
+/// Bursts and panoramas recognised from capture times alone.
+fn detect_timed_sequences(
+    photo_info: &[PhotoInfo],
+    sequences: &mut HashMap<String, SequenceType>,
+    options: &SequenceOptions,
+) {
+    // Fourth pass: time-gap bursts for cameras that do not number their sequences.
+    // Frames shot in single-frame mode or without a capture time break the run.
+    // Without sub-seconds, frames a second apart may still be a fraction apart.
+    if let Some(max_gap) = options.burst_gap {
+        let runs = detect_time_runs(
+            photo_info,
+            sequences,
+            |prev, next| {
+                if prev.subsec_time && next.subsec_time {
+                    max_gap
+                } else {
+                    max_gap.max(Duration::seconds(1))
+                }
+            },
+            |info| info.drive != DriveKind::Single,
+            |_, _| true,
+        );
+        for run in runs {
+            if run.len() < options.burst_min_frames.max(2) {
+                continue;
+            }
+            let burst_name = format!("{}{}", run[0], options.burst_suffix);
+            finish_sequence(&run, SequenceType::Burst(burst_name), sequences);
+        }
+    }
+
+    // Fifth pass: panoramas without vendor tags, i.e. runs of frames with identical
+    // exposure settings and focal length shot in quick succession.
+    if let Some(max_gap) = options.panorama_gap {
+        let runs = detect_time_runs(
+            photo_info,
+            sequences,
+            |_, _| max_gap,
+            |info| info.exposure_key.is_some(),
+            |prev, next| prev.exposure_key == next.exposure_key,
+        );
+        for run in runs {
+            if run.len() < options.panorama_min_frames.max(2) {
+                continue;
+            }
+            let panorama_name = format!("{}{}", run[0], options.panorama_suffix);
+            finish_sequence(&run, SequenceType::Panorama(panorama_name), sequences);
+        }
+    }
+}
+
+/// Builds the manifest record of every detected sequence, naming the rule that
+/// grouped it from what the detectors reported for its frames.
+fn sequence_records(
+    streams: &HashMap<String, Vec<PhotoInfo>>,
+    sequences: &HashMap<String, SequenceType>,
+) -> Vec<SequenceRecord> {
+    let mut members: HashMap<&str, Vec<&PhotoInfo>> = HashMap::new();
+    for info in streams.values().flatten() {
+        if let Some(seq_type) = sequences.get(&info.base) {
+            members
+                .entry(seq_type.folder_name())
+                .or_default()
+                .push(info);
+        }
+    }
+
+    let mut records: Vec<SequenceRecord> = members
+        .into_values()
+        .map(|mut infos| {
+            infos.sort_by(|a, b| {
+                a.capture_time
+                    .unwrap_or(a.date)
+                    .cmp(&b.capture_time.unwrap_or(b.date))
+                    .then_with(|| a.base.cmp(&b.base))
+            });
+            let seq_type = sequences[&infos[0].base].clone();
+            let rule = match seq_type {
+                SequenceType::Hdr(_) => "bracket-shot-number",
+                SequenceType::FocusStack(_) => "focus-bracket-shot-number",
+                SequenceType::Burst(_) if infos.iter().any(|i| i.burst_seq_num > 0) => {
+                    "burst-frame-number"
+                }
+                SequenceType::Burst(_) => "capture-time-gap",
+                SequenceType::Panorama(_)
+                    if infos.iter().any(|i| i.panorama_shot_num.is_some()) =>
+                {
+                    "panorama-shot-number"
+                }
+                SequenceType::Panorama(_) => "matching-exposure-time-gap",
+                SequenceType::PixelShift(_) if infos.iter().any(|i| i.pixel_shift_composite) => {
+                    "pixel-shift-composite"
+                }
+                SequenceType::PixelShift(_) => "pixel-shift-shot-number",
+            };
+            SequenceRecord {
+                seq_type,
+                rule,
+                detector: infos[0].detector,
+                camera: infos[0].camera.clone(),
+                frames: infos
+                    .iter()
+                    .map(|info| SequenceFrame {
+                        base: info.base.clone(),
+                        capture_time: info.capture_time,
+                        exposure_compensation: info.exposure_compensation.clone(),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    records.sort_by(|a, b| a.seq_type.folder_name().cmp(b.seq_type.folder_name()));
+    records
+}
+
 fn detect_sequences(
     files: &HashMap<String, Vec<PathBuf>>,
     exif_cache: &HashMap<String, (PathBuf, Value)>,
-) -> HashMap<String, SequenceType> {
-    let burst_re = Regex::new(r"Sequence:\s*(\d+)").expect("Invalid regex for burst sequence");
-    let hdr_re = Regex::new(r"Shot\s+(\d+)").expect("Invalid regex for HDR sequence");
-
+    options: &SequenceOptions,
+) -> (HashMap<String, SequenceType>, Vec<SequenceRecord>) {
     // Collect all photo bases with their sequence numbers and dates
-    let mut photo_info: Vec<(String, u32, Option<u32>, DateTime<Utc>)> = Vec::new();
+    let mut photo_info: Vec<PhotoInfo> = Vec::new();
 
     for (base, file_list) in files {
         let photo_files: Vec<PathBuf> = file_list
@@ -412,21 +1217,68 @@ fn detect_sequences(
             .collect();
 
         if !photo_files.is_empty() {
-            let Some((_, exif)) = exif_cache.get(base) else {
+            let Some((rep_file, exif)) = exif_cache.get(base) else {
                 continue;
             };
-            let burst_seq_num = get_sequence_info(exif, &burst_re);
-            let hdr_shot_num = get_hdr_info(exif, &hdr_re);
+            let detector = detector_for(exif);
+            let burst_seq_num = detector.burst_index(exif).unwrap_or(0);
+            let hdr_shot_num = detector.bracket_shot(exif);
             let date = get_exif_date(exif).unwrap_or_else(|| {
                 // Fallback to modification time if no EXIF date
                 Utc::now()
             });
-            photo_info.push((base.clone(), burst_seq_num, hdr_shot_num, date));
+            photo_info.push(PhotoInfo {
+                base: base.clone(),
+                burst_seq_num,
+                hdr_shot_num,
+                date,
+                capture_time: get_exif_capture_time(exif),
+                subsec_time: get_exif_subsec_nanos(exif).is_some(),
+                camera: get_camera_id(exif, rep_file, options.camera_key),
+                drive: detector.drive_kind(exif),
+                detector: detector.name(),
+                focus_shot_num: detector.focus_bracket_shot(exif),
+                focus_stacked: detector.is_focus_stacked(exif),
+                panorama_shot_num: detector.panorama_shot(exif),
+                pixel_shift_shot_num: detector.pixel_shift_shot(exif),
+                // Olympus High Res Shot leaves the first raw frame as an .ORI next to the composite
+                pixel_shift_composite: detector.is_pixel_shift_composite(exif)
+                    || photo_files.iter().any(|f| {
+                        f.extension()
+                            .and_then(|e| e.to_str())
+                            .is_some_and(|e| e.eq_ignore_ascii_case("ori"))
+                    }),
+                exposure_key: get_exposure_key(exif),
+                exposure_compensation: match exif.get("ExposureCompensation") {
+                    Some(Value::String(s)) => Some(s.trim().to_string()),
+                    Some(Value::Number(n)) => Some(n.to_string()),
+                    _ => None,
+                },
+            });
+        }
+    }
+
+    let mut detector_counts: Vec<(&str, usize)> = Vec::new();
+    for info in &photo_info {
+        match detector_counts
+            .iter_mut()
+            .find(|(name, _)| *name == info.detector)
+        {
+            Some((_, count)) => *count += 1,
+            None => detector_counts.push((info.detector, 1)),
         }
     }
+    detector_counts.sort();
+    if !detector_counts.is_empty() {
+        let summary: Vec<String> = detector_counts
+            .iter()
+            .map(|(name, count)| format!("{} ({})", name, count))
+            .collect();
+        println!("Sequence tags read with: {}", summary.join(", "));
+    }
 
     // Sort by date first, then by name to establish order
-    photo_info.sort_by(|a, b| a.3.cmp(&b.3).then_with(|| a.0.cmp(&b.0)));
+    photo_info.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.base.cmp(&b.base)));
 
     let pb = ProgressBar::new(photo_info.len() as u64);
     pb.set_style(
@@ -437,150 +1289,22 @@ fn detect_sequences(
 
     let mut sequences: HashMap<String, SequenceType> = HashMap::new();
 
-    // First pass: detect HDR sequences
-    let mut current_hdr_sequence: Vec<String> = Vec::new();
-    let mut expected_hdr_shot = 1u32;
-    let mut hdr_sequence_name = String::new();
-
-    for (base, _burst_seq_num, hdr_shot_num, _date) in &photo_info {
-        pb.inc(1);
-
-        if let Some(shot_num) = hdr_shot_num {
-            if *shot_num == 1 {
-                // Start of a new HDR sequence
-                if current_hdr_sequence.len() > 1 {
-                    // Finish previous HDR sequence
-                    for hdr_base in &current_hdr_sequence {
-                        sequences.insert(
-                            hdr_base.clone(),
-                            SequenceType::Hdr(hdr_sequence_name.clone()),
-                        );
-                    }
-                }
-                hdr_sequence_name = format!("{}_HDR", base);
-                current_hdr_sequence = vec![base.clone()];
-                expected_hdr_shot = 2;
-            } else if *shot_num == expected_hdr_shot && !current_hdr_sequence.is_empty() {
-                // Continue current HDR sequence
-                current_hdr_sequence.push(base.clone());
-                expected_hdr_shot += 1;
-            } else {
-                // HDR sequence broken - finish current sequence if it has multiple photos
-                if current_hdr_sequence.len() > 1 {
-                    for hdr_base in &current_hdr_sequence {
-                        sequences.insert(
-                            hdr_base.clone(),
-                            SequenceType::Hdr(hdr_sequence_name.clone()),
-                        );
-                    }
-                }
-
-                // Check if this starts a new HDR sequence
-                if *shot_num == 1 {
-                    hdr_sequence_name = format!("{}_HDR", base);
-                    current_hdr_sequence = vec![base.clone()];
-                    expected_hdr_shot = 2;
-                } else {
-                    current_hdr_sequence.clear();
-                    expected_hdr_shot = 1;
-                }
-            }
-        } else {
-            // Not part of HDR - finish current HDR sequence if any
-            if current_hdr_sequence.len() > 1 {
-                for hdr_base in &current_hdr_sequence {
-                    sequences.insert(
-                        hdr_base.clone(),
-                        SequenceType::Hdr(hdr_sequence_name.clone()),
-                    );
-                }
-            }
-            current_hdr_sequence.clear();
-            expected_hdr_shot = 1;
-        }
+    // Each camera is its own stream so interleaved frames from two bodies shooting
+    // the same event don't break each other's numbering
+    let mut streams: HashMap<String, Vec<PhotoInfo>> = HashMap::new();
+    for info in photo_info {
+        streams.entry(info.camera.clone()).or_default().push(info);
     }
-
-    // Don't forget the last HDR sequence
-    if current_hdr_sequence.len() > 1 {
-        for hdr_base in &current_hdr_sequence {
-            sequences.insert(
-                hdr_base.clone(),
-                SequenceType::Hdr(hdr_sequence_name.clone()),
-            );
-        }
-    }
-
-    // Second pass: detect burst sequences (only for photos not already in HDR sequences)
-    let mut current_burst_sequence: Vec<String> = Vec::new();
-    let mut expected_burst_num = 0u32;
-    let mut burst_sequence_name = String::new();
-
-    for (base, burst_seq_num, _hdr_shot_num, _date) in &photo_info {
-        // Skip if already part of an HDR sequence
-        if sequences.contains_key(base) {
-            continue;
-        }
-
-        if *burst_seq_num == 0 {
-            // Not part of a burst sequence - finish current sequence if any
-            if current_burst_sequence.len() > 1 {
-                for burst_base in &current_burst_sequence {
-                    sequences.insert(
-                        burst_base.clone(),
-                        SequenceType::Burst(burst_sequence_name.clone()),
-                    );
-                }
-            }
-            current_burst_sequence.clear();
-            expected_burst_num = 0;
-        } else if *burst_seq_num == 1 {
-            // Start of a new burst sequence
-            if current_burst_sequence.len() > 1 {
-                for burst_base in &current_burst_sequence {
-                    sequences.insert(
-                        burst_base.clone(),
-                        SequenceType::Burst(burst_sequence_name.clone()),
-                    );
-                }
-            }
-            burst_sequence_name = format!("{}_BURST", base);
-            current_burst_sequence = vec![base.clone()];
-            expected_burst_num = 2;
-        } else if *burst_seq_num == expected_burst_num && !current_burst_sequence.is_empty() {
-            // Continue current burst sequence
-            current_burst_sequence.push(base.clone());
-            expected_burst_num += 1;
-        } else {
-            // Burst sequence broken - finish current sequence if it has multiple photos
-            if current_burst_sequence.len() > 1 {
-                for burst_base in &current_burst_sequence {
-                    sequences.insert(
-                        burst_base.clone(),
-                        SequenceType::Burst(burst_sequence_name.clone()),
-                    );
-                }
-            }
-
-            // Check if this starts a new burst sequence
-            if *burst_seq_num == 1 {
-                burst_sequence_name = format!("{}_BURST", base);
-                current_burst_sequence = vec![base.clone()];
-                expected_burst_num = 2;
-            } else {
-                current_burst_sequence.clear();
-                expected_burst_num = 0;
-            }
-        }
-    }
-
-    // Don't forget the last burst sequence
-    if current_burst_sequence.len() > 1 {
-        for burst_base in &current_burst_sequence {
-            sequences.insert(
-                burst_base.clone(),
-                SequenceType::Burst(burst_sequence_name.clone()),
-            );
-        }
+    let mut cameras: Vec<String> = streams.keys().cloned().collect();
+    cameras.sort();
+
+    for camera in &cameras {
+        let stream = &streams[camera];
+        detect_hdr_sequences(stream, &mut sequences, options);
+        detect_vendor_sequences(stream, &mut sequences, options);
+        detect_burst_sequences(stream, &mut sequences, options);
+        detect_timed_sequences(stream, &mut sequences, options);
+        pb.inc(stream.len() as u64);
     }
 
     pb.finish_with_message(format!(
@@ -589,37 +1313,57 @@ fn detect_sequences(
     ));
 
     // Print detected sequences for debugging
-    let mut hdr_sequences: Vec<String> = Vec::new();
-    let mut burst_sequences: Vec<String> = Vec::new();
-
-    for (_base, seq_type) in &sequences {
-        match seq_type {
-            SequenceType::Hdr(folder_name) => {
-                if !hdr_sequences.contains(folder_name) {
-                    hdr_sequences.push(folder_name.clone());
-                }
-            }
-            SequenceType::Burst(folder_name) => {
-                if !burst_sequences.contains(folder_name) {
-                    burst_sequences.push(folder_name.clone());
-                }
-            }
+    let mut folders_by_label: HashMap<&str, Vec<&str>> = HashMap::new();
+    for seq_type in sequences.values() {
+        let folders = folders_by_label.entry(seq_type.label()).or_default();
+        if !folders.contains(&seq_type.folder_name()) {
+            folders.push(seq_type.folder_name());
         }
     }
 
-    if !hdr_sequences.is_empty() {
-        println!("Detected {} HDR sequences.", hdr_sequences.len());
-    } else {
-        println!("No HDR sequences detected.");
+    for label in SEQUENCE_LABELS {
+        match folders_by_label.get(label) {
+            Some(folders) => println!("Detected {} {} sequences.", folders.len(), label),
+            None => println!("No {} sequences detected.", label),
+        }
     }
 
-    if !burst_sequences.is_empty() {
-        println!("Detected {} BURST sequences.", burst_sequences.len());
-    } else {
-        println!("No BURST sequences detected.");
+    if cameras.len() > 1 {
+        println!("Sequences per camera:");
+        for camera in &cameras {
+            let mut folders_by_label: HashMap<&str, Vec<&str>> = HashMap::new();
+            for info in &streams[camera] {
+                if let Some(seq_type) = sequences.get(&info.base) {
+                    let folders = folders_by_label.entry(seq_type.label()).or_default();
+                    if !folders.contains(&seq_type.folder_name()) {
+                        folders.push(seq_type.folder_name());
+                    }
+                }
+            }
+            let counts: Vec<String> = SEQUENCE_LABELS
+                .iter()
+                .filter_map(|label| {
+                    folders_by_label
+                        .get(label)
+                        .map(|folders| format!("{} {}", folders.len(), label))
+                })
+                .collect();
+            let summary = if counts.is_empty() {
+                "none".to_string()
+            } else {
+                counts.join(", ")
+            };
+            println!(
+                "  {} ({} photos): {}",
+                camera,
+                streams[camera].len(),
+                summary
+            );
+        }
     }
 
-    sequences
+    let records = sequence_records(&streams, &sequences);
+    (sequences, records)
 }
 
 fn determine_target_base(
@@ -627,7 +1371,15 @@ fn determine_target_base(
     raw_dir: &Path,
     jpeg_dir: &Path,
     default_base: &Path,
+    pair_policy: PairPolicy,
+    group_has_raw: bool,
 ) -> PathBuf {
+    // The JPEG of a pair, and what belongs to it, joins the RAW
+    let jpeg_dir = if pair_policy == PairPolicy::RawWithJpegInSameFolder && group_has_raw {
+        raw_dir
+    } else {
+        jpeg_dir
+    };
     if is_raw_file(filename) {
         raw_dir.to_path_buf()
     } else if is_jpeg_file(filename) {
@@ -636,16 +1388,10 @@ fn determine_target_base(
         // For associated files, parse the name
         let parts: Vec<&str> = filename.split('.').collect();
         if parts.len() >= 3 {
-            let format = parts[parts.len() - 2].to_uppercase();
-            if format == "ORF"
-                || format == "CR2"
-                || format == "NEF"
-                || format == "ARW"
-                || format == "DNG"
-                || format == "RAW"
-            {
+            let format = format!("x.{}", parts[parts.len() - 2]);
+            if is_raw_file(&format) {
                 raw_dir.to_path_buf()
-            } else if format == "JPG" || format == "JPEG" {
+            } else if is_jpeg_file(&format) {
                 jpeg_dir.to_path_buf()
             } else {
                 default_base.to_path_buf()
@@ -656,23 +1402,96 @@ fn determine_target_base(
     }
 }
 
+/// Date and base of the first frame of every sequence, keyed by sequence folder name.
+fn sequence_starts(
+    sequences: &HashMap<String, SequenceType>,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+) -> HashMap<String, (DateTime<Utc>, String)> {
+    let mut starts: HashMap<String, (DateTime<Utc>, String)> = HashMap::new();
+    for (base, seq_type) in sequences {
+        let Some(date) = exif_cache
+            .get(base)
+            .and_then(|(_, exif)| get_exif_date(exif))
+        else {
+            continue;
+        };
+        starts
+            .entry(seq_type.folder_name().to_string())
+            .and_modify(|start| {
+                if (date, base) < (start.0, &start.1) {
+                    *start = (date, base.clone());
+                }
+            })
+            .or_insert((date, base.clone()));
+    }
+    starts
+}
+
 fn validate_and_plan_copy(
     output_dir: &Path,
     groups: &HashMap<String, Vec<PathBuf>>,
     sequences: &HashMap<String, SequenceType>,
     exif_cache: &HashMap<String, (PathBuf, Value)>,
-    cutoff_date: Option<DateTime<Utc>>,
-    force_override: bool,
-    skip_existing: bool,
-    dry_run: bool,
-) -> Result<(Vec<(PathBuf, PathBuf)>, Vec<(PathBuf, PathBuf)>, usize, usize), Vec<ValidationError>> {
+    options: &PlanOptions,
+) -> Result<CopyPlan, Vec<ValidationError>> {
+    let PlanOptions {
+        cutoff_date,
+        force_override,
+        skip_existing,
+        dry_run,
+        sequence_date,
+        pair_policy,
+        day_folder,
+        places,
+        events,
+        associations,
+        rename: rename_template,
+    } = *options;
+    // Groups in an event go to the event's first day and share its place
+    let folder_for = |base: &str, date: DateTime<Utc>| match events.event_for(base) {
+        Some(event) => (
+            event.start,
+            day_folder_name(
+                day_folder,
+                event.start,
+                event.place.as_ref(),
+                Some(&event.name),
+            ),
+        ),
+        None => (
+            date,
+            day_folder_name(day_folder, date, places.get(base), None),
+        ),
+    };
+    let sequence_starts = match sequence_date {
+        SequenceDate::FirstFrame => sequence_starts(sequences, exif_cache),
+        SequenceDate::PerFrame => HashMap::new(),
+    };
     let raw_dir = output_dir.join("RAW");
     let jpeg_dir = output_dir.join("JPEG");
     let mut errors = Vec::new();
+
+    // RAW, JPEG and sidecars of a group share one new name, and so do the sequence
+    // folders named after a renamed frame
+    let (renames, collisions) = match rename_template {
+        Some(template) => rename::plan_renames(groups, exif_cache, template),
+        None => (HashMap::new(), Vec::new()),
+    };
+    for (base, name) in collisions {
+        errors.push(ValidationError {
+            file: base,
+            reason: format!(
+                "New name {} is already taken by another photo (add {{counter}} to --rename)",
+                name
+            ),
+        });
+    }
+    let renamed_folders = rename::renamed_sequence_folders(sequences, &renames);
     let mut copy_plan = Vec::new();
     let mut move_plan = Vec::new();
     let mut skipped_cutoff: usize = 0;
     let mut skipped_existing: usize = 0;
+    let mut skipped_pair_policy: usize = 0;
 
     let total_files: u64 = groups.values().map(|fl| fl.len() as u64).sum();
     let pb = ProgressBar::new(total_files);
@@ -683,9 +1502,14 @@ fn validate_and_plan_copy(
     );
 
     for (base, file_list) in groups {
-        // Prefer JPEG for representative, else any photo
-        let photo_file_opt = file_list
+        // Prefer JPEG for representative, else any photo; derivatives only as a last resort
+        let originals: Vec<&PathBuf> = file_list
+            .iter()
+            .filter(|f| !associations.is_derivative(f))
+            .collect();
+        let photo_file_opt = originals
             .iter()
+            .copied()
             .find(|f| {
                 if let Some(filename) = f.file_name().and_then(|n| n.to_str()) {
                     is_jpeg_file(filename)
@@ -767,27 +1591,74 @@ fn validate_and_plan_copy(
         };
 
         // Skip this group if incremental mode is enabled and the date is not newer than cutoff
-        if let Some(cutoff) = cutoff_date {
-            if date <= cutoff {
-                skipped_cutoff += file_list.len();
-                pb.inc(file_list.len() as u64);
-                continue;
-            }
+        if let Some(cutoff) = cutoff_date
+            && date <= cutoff
+        {
+            skipped_cutoff += file_list.len();
+            pb.inc(file_list.len() as u64);
+            continue;
         }
 
-        let year = date.format("%Y").to_string();
-        let month = date.format("%m").to_string();
-        let day = date.format("%d").to_string();
-
         // Check if this base is part of a sequence
-        let seq_folder = sequences.get(base).map(|seq_type| match seq_type {
-            SequenceType::Burst(folder_name) => folder_name.clone(),
-            SequenceType::Hdr(folder_name) => folder_name.clone(),
+        let seq_folder = sequences.get(base).map(|seq_type| {
+            let folder = seq_type.folder_name();
+            renamed_folders
+                .get(folder)
+                .cloned()
+                .unwrap_or_else(|| folder.to_string())
+        });
+
+        // Sequence members share the first frame's day so a bracket started just
+        // before midnight is not split across two day folders; they share its place
+        // and event too
+        let (folder_date, day) = match sequences
+            .get(base)
+            .and_then(|seq_type| sequence_starts.get(seq_type.folder_name()))
+        {
+            Some((start_date, start_base)) => folder_for(start_base, *start_date),
+            None => folder_for(base, date),
+        };
+        let year = folder_date.format("%Y").to_string();
+        let month = folder_date.format("%m").to_string();
+        let (flat_date, flat_day) = folder_for(base, date);
+
+        // Camera originals the pair policy keeps, RAW first
+        let is_photo = |f: &PathBuf| {
+            f.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| is_raw_file(n) || is_jpeg_file(n))
+        };
+        let group_has_raw = originals.iter().any(|f| {
+            f.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(is_raw_file)
+        });
+        let mut kept_photos: Vec<&PathBuf> = originals
+            .iter()
+            .copied()
+            .filter(|f| {
+                is_photo(f)
+                    && f.file_name()
+                        .and_then(|n| n.to_str())
+                        .is_some_and(|n| pair_policy.keeps(n, group_has_raw))
+            })
+            .collect();
+        kept_photos.sort_by_key(|f| {
+            !f.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(is_raw_file)
         });
+        let group_has_photo = file_list.iter().any(is_photo);
 
-        // Default target_base for the group
+        // Default target_base for the group, from a kept photo when the representative
+        // one is dropped
+        let default_photo = if kept_photos.contains(&&photo_file) {
+            &photo_file
+        } else {
+            kept_photos.first().copied().unwrap_or(&photo_file)
+        };
         let default_target_base =
-            if let Some(filename) = photo_file.file_name().and_then(|n| n.to_str()) {
+            if let Some(filename) = default_photo.file_name().and_then(|n| n.to_str()) {
                 if is_raw_file(filename) {
                     &raw_dir
                 } else {
@@ -798,6 +1669,40 @@ fn validate_and_plan_copy(
             };
 
         for file_path in file_list {
+            let original_name = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            // Sidecars and derivatives go next to the photo they belong to. Sidecars of a
+            // photo the pair policy drops follow a kept photo, unless they name the
+            // dropped one (NAME.ORF.xmp); derivatives are kept along with their group
+            let association = associations
+                .get(file_path)
+                .filter(|a| file_list.contains(&a.primary));
+            let placed_as = if is_photo(file_path) && !associations.is_derivative(file_path) {
+                kept_photos.contains(&file_path).then_some(file_path)
+            } else if group_has_photo && kept_photos.is_empty() {
+                None
+            } else {
+                match association {
+                    Some(a) if kept_photos.contains(&&a.primary) => Some(&a.primary),
+                    Some(a) if a.kind == AssociationKind::Derivative => Some(file_path),
+                    Some(_)
+                        if is_raw_file(associate::photo_base(original_name))
+                            || is_jpeg_file(associate::photo_base(original_name)) =>
+                    {
+                        None
+                    }
+                    Some(_) => kept_photos.first().copied(),
+                    None => Some(file_path),
+                }
+            };
+            let Some(placed_as) = placed_as
+                .and_then(|p| p.file_name())
+                .and_then(|n| n.to_str())
+            else {
+                skipped_pair_policy += 1;
+                pb.inc(1);
+                continue;
+            };
+
             // Validate source file exists and is a regular file
             match fs::metadata(file_path) {
                 Ok(metadata) => {
@@ -820,19 +1725,41 @@ fn validate_and_plan_copy(
                 }
             }
 
-            let filename = file_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-            let target_base =
-                determine_target_base(filename, &raw_dir, &jpeg_dir, default_target_base);
+            // Groups told apart from a same-named one are imported under their own name
+            let name_base = associations.name_base(base);
+            let renamed_name = match renames.get(base) {
+                Some(new_base) => Some(rename::renamed_file_name(
+                    original_name,
+                    name_base,
+                    new_base,
+                )),
+                None => (name_base != base)
+                    .then(|| rename::renamed_file_name(original_name, name_base, base)),
+            };
+            let filename = renamed_name.as_deref().unwrap_or(original_name);
+            let subdir = association
+                .and_then(|a| a.subdir.clone())
+                .unwrap_or_default();
+            let target_base = determine_target_base(
+                placed_as,
+                &raw_dir,
+                &jpeg_dir,
+                default_target_base,
+                pair_policy,
+                group_has_raw,
+            );
+            // Files copied before they were recognised as a sequence sit flat in their own day
             let flat_dest = target_base
-                .join(&year)
-                .join(&month)
-                .join(&day)
+                .join(flat_date.format("%Y").to_string())
+                .join(flat_date.format("%m").to_string())
+                .join(&flat_day)
+                .join(&subdir)
                 .join(filename);
             let mut target_path = target_base.join(&year).join(&month).join(&day);
             if let Some(ref seq_folder_name) = seq_folder {
                 target_path = target_path.join(seq_folder_name);
             }
-            let dest = target_path.join(filename);
+            let dest = target_path.join(&subdir).join(filename);
 
             // If this file belongs to a sequence, check whether it was already
             // copied to the flat (non-sequence) destination in a previous run
@@ -885,52 +1812,106 @@ fn validate_and_plan_copy(
     copy_plan.sort_by(|a, b| a.0.cmp(&b.0));
     move_plan.sort_by(|a, b| a.0.cmp(&b.0));
 
+    // Files from different sources or folders can land on the same name: an identical
+    // file is imported once, a different one would overwrite the first
+    let mut duplicates = Vec::new();
+    let mut first_source: HashMap<PathBuf, PathBuf> = HashMap::new();
+    copy_plan.retain(|(source, dest)| {
+        let Some(first) = first_source.get(dest) else {
+            first_source.insert(dest.clone(), source.clone());
+            return true;
+        };
+        match sources::same_content(first, source) {
+            Ok(true) => duplicates.push((source.clone(), first.clone())),
+            Ok(false) => errors.push(ValidationError {
+                file: source.display().to_string(),
+                reason: format!(
+                    "A different file, {}, is copied to the same place: {}",
+                    first.display(),
+                    dest.display()
+                ),
+            }),
+            Err(e) => errors.push(ValidationError {
+                file: source.display().to_string(),
+                reason: format!("Cannot compare with {}: {}", first.display(), e),
+            }),
+        }
+        false
+    });
+
     pb.finish_with_message("File validation complete");
 
     if errors.is_empty() {
-        Ok((copy_plan, move_plan, skipped_cutoff, skipped_existing))
+        Ok(CopyPlan {
+            copies: copy_plan,
+            moves: move_plan,
+            skipped_cutoff,
+            skipped_existing,
+            skipped_pair_policy,
+            renames,
+            duplicates,
+        })
     } else {
         Err(errors)
     }
 }
 
 fn copy_files(
-    copy_plan: Vec<(PathBuf, PathBuf)>,
+    copy_plan: &[(PathBuf, PathBuf)],
+    sources: &[Source],
     dry_run: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+    hooks: &[LifecycleHook],
+) -> Result<Vec<HookResult>, Box<dyn std::error::Error>> {
+    let mut hook_results = Vec::new();
     let pb = ProgressBar::new(copy_plan.len() as u64);
     pb.set_style(
         ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) Copying files...")
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) Copying files{msg}...")
             .expect("Failed to set progress bar style"),
     );
 
     for (source, dest) in copy_plan {
+        if sources.len() > 1
+            && let Some(index) = sources::source_of(sources, source)
+        {
+            pb.set_message(format!(" from {}", sources[index].label()));
+        }
         if dry_run {
             println!("Would copy {} to {}", source.display(), dest.display());
         } else {
             if let Some(parent) = dest.parent() {
                 fs::create_dir_all(parent)?;
             }
-            fs::copy(&source, &dest)?;
+            fs::copy(source, dest)?;
             // Preserve modification time from source file
             // (fs::copy does not preserve mtime on Linux, which causes
             // incremental mode to set the cutoff too high on next run)
-            if let Ok(metadata) = fs::metadata(&source)
+            if let Ok(metadata) = fs::metadata(source)
                 && let Ok(mtime) = metadata.modified()
             {
-                let _ = std::fs::File::open(&dest).and_then(|f| f.set_modified(mtime));
+                let _ = std::fs::File::open(dest).and_then(|f| f.set_modified(mtime));
             }
+            hook_results.extend(hooks::run_lifecycle_hooks(
+                hooks,
+                HookEvent::PostCopy,
+                &dest.display().to_string(),
+                &json!({
+                    "event": HookEvent::PostCopy.name(),
+                    "source": source,
+                    "dest": dest,
+                }),
+                false,
+            )?);
         }
         pb.inc(1);
     }
 
     pb.finish_with_message("File copying complete");
-    Ok(())
+    Ok(hook_results)
 }
 
 fn move_files(
-    move_plan: Vec<(PathBuf, PathBuf)>,
+    move_plan: &[(PathBuf, PathBuf)],
     dry_run: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let pb = ProgressBar::new(move_plan.len() as u64);
@@ -947,7 +1928,7 @@ fn move_files(
             if let Some(parent) = to.parent() {
                 fs::create_dir_all(parent)?;
             }
-            fs::rename(&from, &to)?;
+            fs::rename(from, to)?;
         }
         pb.inc(1);
     }
@@ -958,12 +1939,84 @@ fn move_files(
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    
+
     // Check if exiftool is available before proceeding
-    check_exiftool_installed()?;
-    
-    let input_dir = PathBuf::from(&args.input_dir);
-    let output_dir = PathBuf::from(&args.output_dir);
+    let exiftool_version = check_exiftool_installed()?;
+
+    if let Some(command) = &args.command {
+        return match command {
+            Commands::Stats(stats_args) => stats::run(stats_args, &exiftool_version),
+            Commands::Bodies(bodies_args) => bodies::run(bodies_args),
+        };
+    }
+    println!("Found exiftool version: {}", exiftool_version);
+
+    // The last directory is the output, the ones before it are inputs
+    let Some((output_dir, input_dirs)) = args.dirs.split_last() else {
+        return Err("No output directory given".into());
+    };
+    if input_dirs.is_empty() && args.inputs.is_empty() {
+        return Err(
+            "No input directory given (list it before the output directory, or use --input)".into(),
+        );
+    }
+    if (args.event_names.is_some() || args.rename_events) && !args.day_folder.contains("{event}") {
+        return Err("--event-names and --rename-events need {event} in --day-folder".into());
+    }
+    let sources = sources::label_sources(input_dirs, &args.inputs)?;
+    let input_dir = sources[0].dir.clone();
+    let output_dir = PathBuf::from(output_dir);
+    let source_list: Vec<Value> = sources.iter().map(Source::to_json).collect();
+
+    // Pre-import hooks run in dry runs too, so a veto can be tried out; the payload
+    // tells them it is one
+    let mut hook_results = hooks::run_lifecycle_hooks(
+        &args.hooks,
+        HookEvent::PreImport,
+        &output_dir.display().to_string(),
+        &json!({
+            "event": HookEvent::PreImport.name(),
+            "input_dir": input_dir,
+            "sources": source_list,
+            "output_dir": output_dir,
+            "dry_run": args.dry_run,
+        }),
+        false,
+    )?;
+
+    let track = geotag::load_tracks(&args.gpx)?;
+    if !args.gpx.is_empty() {
+        println!(
+            "Loaded {} track point(s) from {} GPX file(s).",
+            track.len(),
+            args.gpx.len()
+        );
+    }
+    let geotag_options = GeotagOptions {
+        offset: Duration::seconds(args.gpx_time_offset),
+        max_gap: Duration::seconds(args.gpx_max_gap),
+        target: args.gpx_target,
+        sidecar_name: args.sidecar_name,
+    };
+
+    let uses_places = args.place_keywords
+        || args.day_folder.contains("{city}")
+        || args.day_folder.contains("{country}");
+    let gazetteer = match &args.places {
+        Some(path) => {
+            let gazetteer = Gazetteer::load(path)?;
+            println!(
+                "Loaded {} place(s) from {}.",
+                gazetteer.city_count(),
+                path.display()
+            );
+            Some(gazetteer)
+        }
+        None if uses_places => {
+            return Err("--place-keywords, {city} and {country} need a --places file".into());
+        }
+        None => None,
+    };
 
     // Handle incremental mode
     let cutoff_date = if args.incremental {
@@ -987,13 +2040,176 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         None
     };
 
-    let groups = group_files_by_base(&input_dir);
-    let exif_cache = cache_exif_data(&groups);
-    let sequences = detect_sequences(&groups, &exif_cache);
+    let association_rules = AssociationRules::new(
+        &args.sidecar_exts,
+        &args.derivative_suffixes,
+        &args.editor_dirs,
+    )?;
+    let (all_files, file_counts) = sources::collect_files(&sources);
+    let (mut groups, mut associations) = group_files_by_base(&all_files, &association_rules);
+    sources::separate_groups(&mut groups, &mut associations, &sources);
+    let mut filtered = filters::apply_exclusion(&mut groups, &sources, &args.exclude);
+    let exif_cache = cache_exif_data(&groups, &associations);
+    let sequence_options = SequenceOptions {
+        camera_key: args.camera_key,
+        burst_gap: (args.burst_gap_ms > 0)
+            .then(|| Duration::milliseconds(args.burst_gap_ms as i64)),
+        burst_min_frames: args.burst_min_frames,
+        panorama_gap: (args.panorama_gap_secs > 0)
+            .then(|| Duration::seconds(args.panorama_gap_secs as i64)),
+        panorama_min_frames: args.panorama_min_frames,
+        hdr_suffix: args.hdr_suffix.clone(),
+        burst_suffix: args.burst_suffix.clone(),
+        focus_suffix: args.focus_suffix.clone(),
+        panorama_suffix: args.panorama_suffix.clone(),
+        pixel_shift_suffix: args.pixel_shift_suffix.clone(),
+    };
+    let (mut sequences, mut sequence_records) =
+        detect_sequences(&groups, &exif_cache, &sequence_options);
+
+    filtered.extend(filters::apply_selection_filter(
+        &mut groups,
+        &exif_cache,
+        &SelectionFilter {
+            since: args.since,
+            until: args.until,
+            camera: args.camera.clone(),
+            lens: args.lens.clone(),
+            only: args.only,
+        },
+    ));
+    filtered.extend(filters::apply_rating_filter(
+        &mut groups,
+        &exif_cache,
+        &associations,
+        &RatingFilter {
+            min_rating: args.min_rating,
+            only_protected: args.only_protected,
+            labels: args.labels.clone(),
+        },
+    ));
+    if !filtered.is_empty() {
+        let mut reasons: Vec<(&str, usize)> = Vec::new();
+        for skipped in &filtered {
+            match reasons.iter_mut().find(|(r, _)| *r == skipped.reason) {
+                Some((_, count)) => *count += 1,
+                None => reasons.push((&skipped.reason, 1)),
+            }
+        }
+        println!("Filtered out {} photo group(s):", filtered.len());
+        for (reason, count) in reasons {
+            println!("  {}: {}", reason, count);
+        }
+    }
+
+    let positions = geotag::group_positions(&groups, &exif_cache, &track, &geotag_options);
+    let places = match &gazetteer {
+        Some(gazetteer) => places::resolve_places(&positions, gazetteer, groups.len()),
+        None => HashMap::new(),
+    };
+
+    let events = if args.day_folder.contains("{event}") {
+        let mut events = events::detect_events(
+            &groups,
+            &exif_cache,
+            &positions,
+            &places,
+            &EventOptions {
+                gap: Duration::hours(args.event_gap_hours),
+                max_distance_km: args.event_distance_km,
+                name_template: args.event_name.clone(),
+            },
+        );
+        if let Some(path) = &args.event_names
+            && events::apply_names_file(&mut events, path)?
+            && !args.dry_run
+        {
+            // Nothing is copied until the new file has been looked at
+            println!("Edit the event names and run the import again.");
+            return Ok(());
+        }
+        if args.rename_events {
+            events::rename_interactively(&mut events)?;
+        }
+        events::print_events(&events);
+        events
+    } else {
+        Events::default()
+    };
 
-    match validate_and_plan_copy(&output_dir, &groups, &sequences, &exif_cache, cutoff_date, args.force_override, args.skip_existing, args.dry_run) {
-        Ok((copy_plan, move_plan, skipped_cutoff, skipped_existing)) => {
+    match validate_and_plan_copy(
+        &output_dir,
+        &groups,
+        &sequences,
+        &exif_cache,
+        &PlanOptions {
+            cutoff_date,
+            force_override: args.force_override,
+            skip_existing: args.skip_existing,
+            dry_run: args.dry_run,
+            sequence_date: args.sequence_date,
+            pair_policy: args.pair_policy,
+            day_folder: &args.day_folder,
+            places: &places,
+            events: &events,
+            associations: &associations,
+            rename: args.rename.as_deref(),
+        },
+    ) {
+        Ok(CopyPlan {
+            copies: copy_plan,
+            moves: move_plan,
+            skipped_cutoff,
+            skipped_existing,
+            skipped_pair_policy,
+            renames,
+            duplicates,
+        }) => {
+            // Manifests, hooks and sidecars describe the sequences under their new names
+            rename::apply_to_sequences(&mut sequences, &mut sequence_records, &renames);
             let total_new = copy_plan.len() + move_plan.len();
+            let sequence_folders =
+                manifest::sequence_folders(&copy_plan, &move_plan, &sequence_records);
+            let mut report = RunReport {
+                run_id: Utc::now().format("import-%Y%m%dT%H%M%S").to_string(),
+                copied: copy_plan.len(),
+                moved: move_plan.len(),
+                skipped_cutoff,
+                skipped_existing,
+                skipped_pair_policy,
+                filtered,
+                orphans: associations.orphans.clone(),
+                renamed: copy_plan
+                    .iter()
+                    .filter(|(source, dest)| source.file_name() != dest.file_name())
+                    .cloned()
+                    .collect(),
+                sources: sources::summarize(&sources, &file_counts, &copy_plan, &duplicates),
+                duplicates: duplicates.clone(),
+                ..Default::default()
+            };
+            let post_import_payload = json!({
+                "event": HookEvent::PostImport.name(),
+                "run_id": report.run_id,
+                "input_dir": input_dir,
+                "sources": source_list,
+                "output_dir": output_dir,
+                "dry_run": args.dry_run,
+                "copied": copy_plan.len(),
+                "moved": move_plan.len(),
+                "skipped_cutoff": skipped_cutoff,
+                "skipped_existing": skipped_existing,
+                "skipped_pair_policy": skipped_pair_policy,
+                "skipped_duplicates": duplicates.len(),
+                "copies": copy_plan
+                    .iter()
+                    .map(|(source, dest)| json!({ "source": source, "dest": dest }))
+                    .collect::<Vec<Value>>(),
+                "moves": move_plan
+                    .iter()
+                    .map(|(from, to)| json!({ "source": from, "dest": to }))
+                    .collect::<Vec<Value>>(),
+            });
 
             // Print skip summary
             if skipped_cutoff > 0 {
@@ -1008,13 +2224,40 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     skipped_existing
                 );
             }
+            if skipped_pair_policy > 0 {
+                println!(
+                    "Skipped {} file(s) left out by the pair policy.",
+                    skipped_pair_policy
+                );
+            }
+            if !duplicates.is_empty() {
+                println!(
+                    "Skipped {} duplicate file(s) identical to one already planned:",
+                    duplicates.len()
+                );
+                for (duplicate, original) in &duplicates {
+                    println!("  {} (same as {})", duplicate.display(), original.display());
+                }
+            }
+            if sources.len() > 1 {
+                println!("\nSources:");
+                for summary in &report.sources {
+                    println!(
+                        "  {:<16} {:>6} file(s), {} to copy, {} duplicate(s)",
+                        summary.source.label(),
+                        summary.files,
+                        summary.copied,
+                        summary.duplicates
+                    );
+                }
+            }
 
             if !move_plan.is_empty() {
                 println!(
                     "\n{} file(s) found at flat path - will move to sequence folder:",
                     move_plan.len()
                 );
-                move_files(move_plan, args.dry_run)?;
+                move_files(&move_plan, args.dry_run)?;
             }
 
             if cutoff_date.is_some() {
@@ -1023,16 +2266,245 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     copy_plan.len()
                 );
             } else {
-                println!(
-                    "\n{} new file(s) to copy.",
-                    copy_plan.len()
-                );
+                println!("\n{} new file(s) to copy.", copy_plan.len());
             }
 
             if total_new == 0 {
                 println!("No files to process.");
             } else {
-                copy_files(copy_plan, args.dry_run)?;
+                if args.dry_run {
+                    for hook in args.hooks.iter().filter(|h| h.event == HookEvent::PostCopy) {
+                        println!(
+                            "Would run post-copy hook after each copied file: {}",
+                            hook.command
+                        );
+                    }
+                }
+                hook_results.extend(copy_files(&copy_plan, &sources, args.dry_run, &args.hooks)?);
+            }
+
+            if args.extract_previews && !copy_plan.is_empty() {
+                let raw_copies: Vec<&PathBuf> = copy_plan
+                    .iter()
+                    .filter(|(source, _)| {
+                        let Some(filename) = source.file_name().and_then(|n| n.to_str()) else {
+                            return false;
+                        };
+                        let base = associations
+                            .base_of(source)
+                            .unwrap_or_else(|| associate::photo_base(filename));
+                        is_raw_file(filename)
+                            && !groups.get(base).is_some_and(|files| {
+                                preview::imports_jpeg(files, &associations, args.pair_policy)
+                            })
+                    })
+                    .map(|(_, dest)| dest)
+                    .collect();
+                report.derived = preview::extract_previews(
+                    &raw_copies,
+                    &output_dir.join("RAW"),
+                    &output_dir.join("JPEG"),
+                    args.dry_run,
+                );
+            }
+
+            if !track.is_empty() && !copy_plan.is_empty() {
+                report.geotagged = geotag::geotag_copies(
+                    &copy_plan,
+                    &associations,
+                    &exif_cache,
+                    &track,
+                    &geotag_options,
+                    args.dry_run,
+                );
+            }
+
+            if let Some(mirror_dir) = &args.mirror {
+                mirror::follow_moves(&move_plan, &output_dir, mirror_dir, args.dry_run)?;
+                // Small copies are placed from the final destinations, after sequence moves
+                let library_files: Vec<&PathBuf> = copy_plan
+                    .iter()
+                    .chain(&move_plan)
+                    .map(|(_, dest)| dest)
+                    .chain(
+                        report
+                            .derived
+                            .iter()
+                            .filter(|preview| preview.status == "extracted")
+                            .map(|preview| &preview.dest),
+                    )
+                    .collect();
+                report.mirrored = mirror::write_mirror(
+                    &library_files,
+                    &output_dir,
+                    &MirrorOptions {
+                        dir: mirror_dir.clone(),
+                        long_edge: args.mirror_size,
+                        quality: args.mirror_quality,
+                        strip_gps: args.mirror_strip_gps,
+                    },
+                    args.dry_run,
+                );
+            }
+
+            if args.write_xmp && !copy_plan.is_empty() {
+                xmp::write_sidecars(
+                    &copy_plan,
+                    &associations,
+                    &exif_cache,
+                    &sequences,
+                    &events,
+                    &XmpOptions {
+                        creator: args.creator.clone(),
+                        copyright: args.copyright.clone(),
+                        run_id: report.run_id.clone(),
+                        naming: args.sidecar_name,
+                        time_offset: (args.gpx_time_offset != 0)
+                            .then(|| Duration::seconds(args.gpx_time_offset)),
+                    },
+                    args.dry_run,
+                );
+            }
+
+            if args.preserve_filename && !copy_plan.is_empty() {
+                xmp::write_preserved_file_names(&report.renamed, args.sidecar_name, args.dry_run);
+            }
+
+            if args.place_keywords && !copy_plan.is_empty() {
+                places::write_place_keywords(
+                    &copy_plan,
+                    &associations,
+                    &places,
+                    args.sidecar_name,
+                    args.dry_run,
+                );
+            }
+
+            // Last, so the recorded modification times include geotags written to copies
+            if let Some(store) = args.thumbnails {
+                let photos: Vec<(&PathBuf, Option<image::metadata::Orientation>)> = copy_plan
+                    .iter()
+                    .filter_map(|(source, dest)| {
+                        let filename = source.file_name().and_then(|n| n.to_str())?;
+                        if !is_raw_file(filename) && !is_jpeg_file(filename) {
+                            return None;
+                        }
+                        let base = associations
+                            .base_of(source)
+                            .unwrap_or_else(|| associate::photo_base(filename));
+                        let orientation = exif_cache
+                            .get(base)
+                            .and_then(|(_, exif)| thumbnails::orientation_from_exif(exif));
+                        Some((dest, orientation))
+                    })
+                    .chain(
+                        report
+                            .derived
+                            .iter()
+                            .filter(|preview| preview.status == "extracted")
+                            .map(|preview| (&preview.dest, None)),
+                    )
+                    .collect();
+                thumbnails::write_thumbnails(
+                    &photos,
+                    &ThumbnailOptions {
+                        store,
+                        sizes: args.thumbnail_sizes.clone(),
+                    },
+                    args.dry_run,
+                );
+            }
+
+            if args.contact_sheet && total_new > 0 {
+                let planned: Vec<(PathBuf, PathBuf)> =
+                    copy_plan.iter().chain(&move_plan).cloned().collect();
+                contact_sheet::write_contact_sheet(
+                    &planned,
+                    &associations,
+                    &exif_cache,
+                    &sequence_records,
+                    &output_dir,
+                    &report.run_id,
+                    args.dry_run,
+                )?;
+            }
+
+            if !args.no_sequence_manifests {
+                manifest::write_sequence_manifests(&sequence_folders, args.dry_run)?;
+            }
+
+            if !copy_plan.is_empty() {
+                // One shot per group, read from the photo its EXIF came from
+                let mut shots: Vec<(&PathBuf, &Value)> = Vec::new();
+                let mut seen = std::collections::HashSet::new();
+                for (source, _) in &copy_plan {
+                    let Some(filename) = source.file_name().and_then(|n| n.to_str()) else {
+                        continue;
+                    };
+                    if associations.is_derivative(source)
+                        || (!is_raw_file(filename) && !is_jpeg_file(filename))
+                    {
+                        continue;
+                    }
+                    let base = associations
+                        .base_of(source)
+                        .unwrap_or_else(|| associate::photo_base(filename));
+                    if let Some((photo, exif)) = exif_cache.get(base)
+                        && seen.insert(base)
+                    {
+                        shots.push((photo, exif));
+                    }
+                }
+                match bodies::record_import(&output_dir, &report.run_id, &shots, args.dry_run) {
+                    Ok(readings) => report.bodies = readings,
+                    Err(e) => println!("Warning: failed to update the body history: {}", e),
+                }
+            }
+
+            // Previews and other files the run didn't read are indexed by the next `stats`
+            if !args.dry_run && total_new > 0 {
+                let indexed: Vec<(&PathBuf, Option<&Value>, Option<&SequenceType>)> = copy_plan
+                    .iter()
+                    .chain(&move_plan)
+                    .filter_map(|(source, dest)| {
+                        let filename = source.file_name().and_then(|n| n.to_str())?;
+                        if !is_raw_file(filename) && !is_jpeg_file(filename) {
+                            return None;
+                        }
+                        let base = associations
+                            .base_of(source)
+                            .unwrap_or_else(|| associate::photo_base(filename));
+                        let exif = exif_cache.get(base).map(|(_, exif)| exif);
+                        Some((dest, exif, sequences.get(base)))
+                    })
+                    .collect();
+                let moved_from: Vec<&PathBuf> = move_plan.iter().map(|(from, _)| from).collect();
+                if let Err(e) =
+                    library::LibraryIndex::record_import(&output_dir, &indexed, &moved_from)
+                {
+                    println!("Warning: failed to update the library index: {}", e);
+                }
+            }
+
+            hook_results.extend(hooks::run_sequence_hooks(
+                &args.sequence_hooks,
+                &output_dir,
+                &sequence_folders,
+                args.hook_jobs,
+                args.dry_run,
+            )?);
+
+            hook_results.extend(hooks::run_lifecycle_hooks(
+                &args.hooks,
+                HookEvent::PostImport,
+                &output_dir.display().to_string(),
+                &post_import_payload,
+                args.dry_run,
+            )?);
+            report.hooks = hook_results;
+
+            if let Some(report_path) = &args.report {
+                report.write(report_path)?;
             }
         }
         Err(errors) => {
@@ -1050,3 +2522,606 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty scratch directory for a test, below the system temp directory.
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("photo_sorter-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub(crate) fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let file = dir.join(name);
+        fs::write(&file, content).unwrap();
+        file
+    }
+
+    fn plan_copy(
+        output_dir: &Path,
+        groups: &HashMap<String, Vec<PathBuf>>,
+        sequences: &HashMap<String, SequenceType>,
+        exif_cache: &HashMap<String, (PathBuf, Value)>,
+        associations: &Associations,
+        sequence_date: SequenceDate,
+    ) -> CopyPlan {
+        plan_copy_with_policy(
+            output_dir,
+            groups,
+            sequences,
+            exif_cache,
+            associations,
+            sequence_date,
+            PairPolicy::Both,
+        )
+    }
+
+    fn plan_copy_with_policy(
+        output_dir: &Path,
+        groups: &HashMap<String, Vec<PathBuf>>,
+        sequences: &HashMap<String, SequenceType>,
+        exif_cache: &HashMap<String, (PathBuf, Value)>,
+        associations: &Associations,
+        sequence_date: SequenceDate,
+        pair_policy: PairPolicy,
+    ) -> CopyPlan {
+        let places = HashMap::new();
+        let events = Events::default();
+        let options = PlanOptions {
+            cutoff_date: None,
+            force_override: false,
+            skip_existing: false,
+            dry_run: true,
+            sequence_date,
+            pair_policy,
+            day_folder: "{day}",
+            places: &places,
+            events: &events,
+            associations,
+            rename: None,
+        };
+        match validate_and_plan_copy(output_dir, groups, sequences, exif_cache, &options) {
+            Ok(plan) => plan,
+            Err(errors) => panic!("plan failed: {:?}", errors),
+        }
+    }
+
+    fn destination_names(plan: &CopyPlan) -> Vec<String> {
+        let mut names: Vec<String> = plan
+            .copies
+            .iter()
+            .map(|(_, dest)| dest.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn same_camera_name_on_two_cards_is_two_photos() {
+        let dir = scratch_dir("two-cards");
+        let card_a = dir.join("a");
+        let card_b = dir.join("b");
+        let a_photo = write(&card_a.join("DCIM"), "P6150123.ORF", "first photo");
+        let b_photo = write(&card_b.join("DCIM"), "P6150123.ORF", "second photo");
+        write(&card_b.join("DCIM"), "P6150123.ORF.xmp", "<x:xmpmeta/>");
+        write(&card_a.join("DCIM"), "P6150124.ORF", "same photo");
+        let copy = write(&card_b.join("DCIM"), "P6150124.ORF", "same photo");
+        let sources = vec![
+            Source {
+                label: Some("A".to_string()),
+                dir: card_a,
+            },
+            Source {
+                label: Some("B".to_string()),
+                dir: card_b,
+            },
+        ];
+
+        let rules = AssociationRules::new(&[], &[], &[]).unwrap();
+        let (all_files, _) = sources::collect_files(&sources);
+        let (mut groups, mut associations) = group_files_by_base(&all_files, &rules);
+        sources::separate_groups(&mut groups, &mut associations, &sources);
+
+        assert_eq!(groups["P6150123"], vec![a_photo]);
+        assert!(groups["P6150123_B"].contains(&b_photo));
+        assert_eq!(associations.base_of(&b_photo), Some("P6150123_B"));
+        assert_eq!(associations.name_base("P6150123_B"), "P6150123");
+        assert_eq!(groups["P6150124"].len(), 2);
+
+        let plan = plan_copy(
+            &dir.join("out"),
+            &groups,
+            &HashMap::new(),
+            &HashMap::new(),
+            &associations,
+            SequenceDate::FirstFrame,
+        );
+        assert_eq!(
+            destination_names(&plan),
+            [
+                "P6150123.ORF",
+                "P6150123_B.ORF",
+                "P6150123_B.ORF.xmp",
+                "P6150124.ORF"
+            ]
+        );
+        assert_eq!(plan.duplicates.len(), 1);
+        assert_eq!(plan.duplicates[0].0, copy);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn fujifilm_and_panasonic_raws_sort_into_raw() {
+        let dir = scratch_dir("raf-rw2");
+        let card = dir.join("card");
+        write(&card, "DSCF0001.RAF", "raw");
+        write(&card, "DSCF0001.JPG", "jpeg");
+        write(&card, "DSCF0001.RAF.xmp", "<x:xmpmeta/>");
+        write(&card, "P1000001.RW2", "raw");
+        write(&card, "P1000001.xmp", "<x:xmpmeta/>");
+
+        let rules = AssociationRules::new(&[], &[], &[]).unwrap();
+        let (groups, associations) =
+            group_files_by_base(&collect_all_files_recursive(&card), &rules);
+        assert_eq!(groups["DSCF0001"].len(), 3);
+        assert_eq!(groups["P1000001"].len(), 2);
+
+        let output_dir = dir.join("out");
+        let plan = plan_copy(
+            &output_dir,
+            &groups,
+            &HashMap::new(),
+            &HashMap::new(),
+            &associations,
+            SequenceDate::FirstFrame,
+        );
+        let tree_of = |name: &str| {
+            let (_, dest) = plan
+                .copies
+                .iter()
+                .find(|(_, dest)| dest.file_name().unwrap() == name)
+                .unwrap();
+            dest.strip_prefix(&output_dir)
+                .unwrap()
+                .components()
+                .next()
+                .unwrap()
+                .as_os_str()
+                .to_string_lossy()
+                .to_string()
+        };
+        assert_eq!(tree_of("DSCF0001.RAF"), "RAW");
+        assert_eq!(tree_of("DSCF0001.RAF.xmp"), "RAW");
+        assert_eq!(tree_of("DSCF0001.JPG"), "JPEG");
+        assert_eq!(tree_of("P1000001.RW2"), "RAW");
+        assert_eq!(tree_of("P1000001.xmp"), "RAW");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Groups, EXIF cache and sequences of a test import.
+    type Import = (
+        HashMap<String, Vec<PathBuf>>,
+        HashMap<String, (PathBuf, Value)>,
+        HashMap<String, SequenceType>,
+    );
+
+    /// A three-frame bracket written to `dir`, with its EXIF and sequence entries.
+    fn bracket(dir: &Path, times: [&str; 3]) -> Import {
+        let mut groups = HashMap::new();
+        let mut exif_cache = HashMap::new();
+        let mut sequences = HashMap::new();
+        for (index, time) in times.iter().enumerate() {
+            let base = format!("P000000{}", index + 1);
+            let file = write(dir, &format!("{}.ORF", base), &base);
+            let exif = json!({ "DateTimeOriginal": time });
+            groups.insert(base.clone(), vec![file.clone()]);
+            exif_cache.insert(base.clone(), (file, exif));
+            sequences.insert(base, SequenceType::Hdr("P0000001-HDR".to_string()));
+        }
+        (groups, exif_cache, sequences)
+    }
+
+    /// Destination folder of every frame, relative to the output directory.
+    fn frame_folders(plan: &CopyPlan, output_dir: &Path) -> Vec<String> {
+        let mut folders: Vec<(PathBuf, String)> = plan
+            .copies
+            .iter()
+            .map(|(source, dest)| {
+                let folder = dest.parent().unwrap().strip_prefix(output_dir).unwrap();
+                (source.clone(), folder.display().to_string())
+            })
+            .collect();
+        folders.sort();
+        folders.into_iter().map(|(_, folder)| folder).collect()
+    }
+
+    #[test]
+    fn sequence_starts_at_first_frame() {
+        let dir = scratch_dir("sequence-starts");
+        let (_, exif_cache, sequences) = bracket(
+            &dir,
+            [
+                "2024:12:31 23:59:58",
+                "2024:12:31 23:59:59",
+                "2025:01:01 00:00:01",
+            ],
+        );
+        let starts = sequence_starts(&sequences, &exif_cache);
+        let (date, base) = &starts["P0000001-HDR"];
+        assert_eq!(date.to_string(), "2024-12-31 23:59:58 UTC");
+        assert_eq!(base, "P0000001");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn bracket_across_new_year_stays_on_first_day() {
+        let dir = scratch_dir("new-year");
+        let output_dir = dir.join("out");
+        let (groups, exif_cache, sequences) = bracket(
+            &dir.join("in"),
+            [
+                "2024:12:31 23:59:58",
+                "2024:12:31 23:59:59",
+                "2025:01:01 00:00:01",
+            ],
+        );
+        let associations = Associations::default();
+
+        let plan = plan_copy(
+            &output_dir,
+            &groups,
+            &sequences,
+            &exif_cache,
+            &associations,
+            SequenceDate::FirstFrame,
+        );
+        assert_eq!(
+            frame_folders(&plan, &output_dir),
+            ["RAW/2024/12/31/P0000001-HDR"; 3]
+        );
+
+        let plan = plan_copy(
+            &output_dir,
+            &groups,
+            &sequences,
+            &exif_cache,
+            &associations,
+            SequenceDate::PerFrame,
+        );
+        assert_eq!(
+            frame_folders(&plan, &output_dir),
+            [
+                "RAW/2024/12/31/P0000001-HDR",
+                "RAW/2024/12/31/P0000001-HDR",
+                "RAW/2025/01/01/P0000001-HDR"
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn bracket_across_month_end_stays_on_first_day() {
+        let dir = scratch_dir("month-end");
+        let output_dir = dir.join("out");
+        let (groups, exif_cache, sequences) = bracket(
+            &dir.join("in"),
+            [
+                "2024:06:30 23:59:58",
+                "2024:07:01 00:00:00",
+                "2024:07:01 00:00:02",
+            ],
+        );
+        let associations = Associations::default();
+
+        let plan = plan_copy(
+            &output_dir,
+            &groups,
+            &sequences,
+            &exif_cache,
+            &associations,
+            SequenceDate::FirstFrame,
+        );
+        assert_eq!(
+            frame_folders(&plan, &output_dir),
+            ["RAW/2024/06/30/P0000001-HDR"; 3]
+        );
+
+        let plan = plan_copy(
+            &output_dir,
+            &groups,
+            &sequences,
+            &exif_cache,
+            &associations,
+            SequenceDate::PerFrame,
+        );
+        assert_eq!(
+            frame_folders(&plan, &output_dir),
+            [
+                "RAW/2024/06/30/P0000001-HDR",
+                "RAW/2024/07/01/P0000001-HDR",
+                "RAW/2024/07/01/P0000001-HDR"
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn most_recent_file_is_searched_in_every_folder_of_the_day() {
+        let dir = scratch_dir("most-recent");
+        let day = dir.join("RAW/2024/06");
+        let set_mtime = |file: &Path, time: &str| {
+            let time = DateTime::parse_from_rfc3339(time).unwrap();
+            let file = fs::File::options().write(true).open(file).unwrap();
+            file.set_modified(time.into()).unwrap();
+        };
+        // Without EXIF dates the modification times are used
+        let lisbon = write(&day.join("15 Lisbon"), "P6150101.ORF", "");
+        let sintra = write(&day.join("15 Sintra/P6150201_HDR"), "P6150201.ORF", "");
+        let earlier = write(&day.join("14"), "P6140101.ORF", "");
+        set_mtime(&lisbon, "2024-06-15T10:00:00Z");
+        set_mtime(&sintra, "2024-06-15T18:00:00Z");
+        set_mtime(&earlier, "2024-06-14T23:00:00Z");
+
+        let most_recent = find_most_recent_file_in_destination(&dir).unwrap();
+        assert_eq!(
+            most_recent.map(|date| date.to_rfc3339()),
+            Some("2024-06-15T18:00:00+00:00".to_string())
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn sequence_options() -> SequenceOptions {
+        SequenceOptions {
+            camera_key: CameraKey::Serial,
+            burst_gap: None,
+            burst_min_frames: 3,
+            panorama_gap: None,
+            panorama_min_frames: 3,
+            hdr_suffix: "_HDR".to_string(),
+            burst_suffix: "_BURST".to_string(),
+            focus_suffix: "_FOCUS".to_string(),
+            panorama_suffix: "_PANO".to_string(),
+            pixel_shift_suffix: "_HIRES".to_string(),
+        }
+    }
+
+    /// A frame in a stream, with its bracket shot and burst frame numbers.
+    fn frame_info(base: &str, hdr_shot_num: Option<u32>, burst_seq_num: u32) -> PhotoInfo {
+        PhotoInfo {
+            base: base.to_string(),
+            burst_seq_num,
+            hdr_shot_num,
+            date: DateTime::<Utc>::UNIX_EPOCH,
+            capture_time: None,
+            subsec_time: false,
+            camera: "OM-1".to_string(),
+            drive: DriveKind::Unknown,
+            detector: "olympus",
+            focus_shot_num: None,
+            focus_stacked: false,
+            panorama_shot_num: None,
+            pixel_shift_shot_num: None,
+            pixel_shift_composite: false,
+            exposure_key: None,
+            exposure_compensation: None,
+        }
+    }
+
+    fn folders(sequences: &HashMap<String, SequenceType>) -> Vec<(String, String)> {
+        let mut folders: Vec<(String, String)> = sequences
+            .iter()
+            .map(|(base, seq_type)| (base.clone(), seq_type.folder_name().to_string()))
+            .collect();
+        folders.sort();
+        folders
+    }
+
+    #[test]
+    fn numbered_brackets_and_bursts() {
+        let stream = [
+            frame_info("P01", Some(1), 0),
+            frame_info("P02", Some(2), 0),
+            frame_info("P03", Some(3), 0),
+            // A bracket restarting before it is complete, then one broken by a skipped shot
+            frame_info("P04", Some(1), 0),
+            frame_info("P05", Some(1), 0),
+            frame_info("P06", Some(3), 0),
+            frame_info("P07", None, 1),
+            frame_info("P08", None, 2),
+            frame_info("P09", None, 0),
+            // A lone first frame is no sequence
+            frame_info("P10", None, 1),
+        ];
+        let options = sequence_options();
+        let mut sequences = HashMap::new();
+        detect_hdr_sequences(&stream, &mut sequences, &options);
+        detect_burst_sequences(&stream, &mut sequences, &options);
+        assert_eq!(
+            folders(&sequences),
+            [
+                ("P01".to_string(), "P01_HDR".to_string()),
+                ("P02".to_string(), "P01_HDR".to_string()),
+                ("P03".to_string(), "P01_HDR".to_string()),
+                ("P07".to_string(), "P07_BURST".to_string()),
+                ("P08".to_string(), "P07_BURST".to_string()),
+            ]
+        );
+        assert!(matches!(sequences["P02"], SequenceType::Hdr(_)));
+        assert!(matches!(sequences["P08"], SequenceType::Burst(_)));
+    }
+
+    #[test]
+    fn timed_bursts_allow_a_second_without_subseconds() {
+        let start = DateTime::parse_from_rfc3339("2024-06-15T10:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let frame = |base: &str, millis: i64, subsec_time: bool| PhotoInfo {
+            capture_time: Some(start + Duration::milliseconds(millis)),
+            subsec_time,
+            ..frame_info(base, None, 0)
+        };
+        let stream = [
+            frame("P1", 0, false),
+            frame("P2", 1000, false),
+            frame("P3", 2000, false),
+            frame("P4", 10_000, true),
+            frame("P5", 10_300, true),
+            // 0.8s is too long a gap once both frames have sub-seconds
+            frame("P6", 11_100, true),
+            frame("P7", 11_400, true),
+        ];
+        let options = SequenceOptions {
+            burst_gap: Some(Duration::milliseconds(500)),
+            burst_min_frames: 2,
+            ..sequence_options()
+        };
+        let mut sequences = HashMap::new();
+        detect_timed_sequences(&stream, &mut sequences, &options);
+        assert_eq!(
+            folders(&sequences),
+            [
+                ("P1".to_string(), "P1_BURST".to_string()),
+                ("P2".to_string(), "P1_BURST".to_string()),
+                ("P3".to_string(), "P1_BURST".to_string()),
+                ("P4".to_string(), "P4_BURST".to_string()),
+                ("P5".to_string(), "P4_BURST".to_string()),
+                ("P6".to_string(), "P6_BURST".to_string()),
+                ("P7".to_string(), "P6_BURST".to_string()),
+            ]
+        );
+    }
+
+    /// Tree and name of every file a RAW+JPEG shot and a JPEG-only shot are copied to
+    /// under `pair_policy`.
+    fn pair_destinations(name: &str, pair_policy: PairPolicy) -> Vec<String> {
+        let dir = scratch_dir(name);
+        let card = dir.join("card");
+        for file in [
+            "P1.ORF",
+            "P1.JPG",
+            "P1.ORF.dop",
+            "P1.xmp",
+            "P1-Edit.jpg",
+            "P2.JPG",
+            "P2.xmp",
+        ] {
+            write(&card, file, file);
+        }
+        let rules = AssociationRules::new(&[], &[], &[]).unwrap();
+        let (groups, associations) =
+            group_files_by_base(&collect_all_files_recursive(&card), &rules);
+
+        let output_dir = dir.join("out");
+        let plan = plan_copy_with_policy(
+            &output_dir,
+            &groups,
+            &HashMap::new(),
+            &HashMap::new(),
+            &associations,
+            SequenceDate::FirstFrame,
+            pair_policy,
+        );
+        let mut destinations: Vec<String> = plan
+            .copies
+            .iter()
+            .map(|(_, dest)| {
+                let tree = dest
+                    .strip_prefix(&output_dir)
+                    .unwrap()
+                    .iter()
+                    .next()
+                    .unwrap();
+                format!(
+                    "{}/{}",
+                    tree.to_string_lossy(),
+                    dest.file_name().unwrap().to_string_lossy()
+                )
+            })
+            .collect();
+        destinations.sort();
+        let _ = fs::remove_dir_all(&dir);
+        destinations
+    }
+
+    #[test]
+    fn pair_policy_both() {
+        assert_eq!(
+            pair_destinations("pair-both", PairPolicy::Both),
+            [
+                "JPEG/P1.JPG",
+                "JPEG/P2.JPG",
+                "JPEG/P2.xmp",
+                "RAW/P1-Edit.jpg",
+                "RAW/P1.ORF",
+                "RAW/P1.ORF.dop",
+                "RAW/P1.xmp"
+            ]
+        );
+    }
+
+    #[test]
+    fn pair_policy_raw_only() {
+        // The edited JPEG goes with its shot; the JPEG-only shot is left out
+        assert_eq!(
+            pair_destinations("pair-raw-only", PairPolicy::RawOnly),
+            [
+                "RAW/P1-Edit.jpg",
+                "RAW/P1.ORF",
+                "RAW/P1.ORF.dop",
+                "RAW/P1.xmp"
+            ]
+        );
+    }
+
+    #[test]
+    fn pair_policy_jpeg_only() {
+        // The sidecar naming the RAW file is dropped with it, the shared one follows the JPEG
+        assert_eq!(
+            pair_destinations("pair-jpeg-only", PairPolicy::JpegOnly),
+            [
+                "JPEG/P1-Edit.jpg",
+                "JPEG/P1.JPG",
+                "JPEG/P1.xmp",
+                "JPEG/P2.JPG",
+                "JPEG/P2.xmp"
+            ]
+        );
+    }
+
+    #[test]
+    fn pair_policy_jpeg_if_no_raw() {
+        assert_eq!(
+            pair_destinations("pair-jpeg-if-no-raw", PairPolicy::JpegIfNoRaw),
+            [
+                "JPEG/P2.JPG",
+                "JPEG/P2.xmp",
+                "RAW/P1-Edit.jpg",
+                "RAW/P1.ORF",
+                "RAW/P1.ORF.dop",
+                "RAW/P1.xmp"
+            ]
+        );
+    }
+
+    #[test]
+    fn pair_policy_raw_with_jpeg_in_same_folder() {
+        assert_eq!(
+            pair_destinations("pair-same-folder", PairPolicy::RawWithJpegInSameFolder),
+            [
+                "JPEG/P2.JPG",
+                "JPEG/P2.xmp",
+                "RAW/P1-Edit.jpg",
+                "RAW/P1.JPG",
+                "RAW/P1.ORF",
+                "RAW/P1.ORF.dop",
+                "RAW/P1.xmp"
+            ]
+        );
+    }
+}
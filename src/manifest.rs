@@ -0,0 +1,153 @@
+//! `sequence.json` manifests written into every sequence folder, so tools such as
+//! HDR-merge scripts know why the frames were grouped and in which order they belong.
+
+use crate::associate::{file_base, photo_base};
+use crate::{SequenceRecord, is_jpeg_file, is_raw_file};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE_NAME: &str = "sequence.json";
+
+/// Sequence folders that receive files in this run, with the record describing them. A
+/// sequence with RAW and JPEG files has a folder in each tree, both listed.
+pub fn sequence_folders<'a>(
+    copy_plan: &[(PathBuf, PathBuf)],
+    move_plan: &[(PathBuf, PathBuf)],
+    records: &'a [SequenceRecord],
+) -> Vec<(PathBuf, &'a SequenceRecord)> {
+    let by_folder_name: BTreeMap<&str, &SequenceRecord> = records
+        .iter()
+        .map(|record| (record.seq_type.folder_name(), record))
+        .collect();
+
+    let mut folders: BTreeMap<PathBuf, &SequenceRecord> = BTreeMap::new();
+    for (_, dest) in copy_plan.iter().chain(move_plan) {
+        // Files kept in editor subfolders sit deeper than the sequence folder itself
+        let found = dest.ancestors().skip(1).find_map(|folder| {
+            let name = folder.file_name()?.to_str()?;
+            Some((folder, *by_folder_name.get(name)?))
+        });
+        if let Some((folder, record)) = found {
+            folders.insert(folder.to_path_buf(), record);
+        }
+    }
+    folders.into_iter().collect()
+}
+
+/// Manifest content for one sequence folder; `files` lists what the folder holds.
+pub fn manifest_json(record: &SequenceRecord, files: &[String]) -> Value {
+    let frames: Vec<Value> = record
+        .frames
+        .iter()
+        .enumerate()
+        .map(|(index, frame)| {
+            let frame_files: Vec<&String> = files
+                .iter()
+                .filter(|file| file_base(file) == frame.base)
+                .collect();
+            json!({
+                "shot": index + 1,
+                "base": frame.base,
+                "files": frame_files,
+                "capture_time": frame.capture_time.map(|t| t.format("%Y-%m-%dT%H:%M:%S%.3f").to_string()),
+                "exposure_compensation": frame.exposure_compensation,
+            })
+        })
+        .collect();
+
+    json!({
+        "sequence": record.seq_type.folder_name(),
+        "type": record.seq_type.label(),
+        "rule": record.rule,
+        "detector": record.detector,
+        "camera": record.camera,
+        "frames": frames,
+    })
+}
+
+fn folder_files(folder: &Path) -> std::io::Result<Vec<String>> {
+    let mut files: Vec<String> = fs::read_dir(folder)?
+        .flatten()
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+        .filter(|name| name != MANIFEST_FILE_NAME)
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Photo files of the sequence found in `folder`, in shot order.
+pub fn frame_files(record: &SequenceRecord, folder: &Path) -> Vec<PathBuf> {
+    let files = folder_files(folder).unwrap_or_default();
+    record
+        .frames
+        .iter()
+        .flat_map(|frame| {
+            files.iter().filter(move |file| {
+                (is_raw_file(file) || is_jpeg_file(file)) && photo_base(file) == frame.base
+            })
+        })
+        .map(|file| folder.join(file))
+        .collect()
+}
+
+pub fn write_sequence_manifests(
+    folders: &[(PathBuf, &SequenceRecord)],
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (folder, record) in folders {
+        let path = folder.join(MANIFEST_FILE_NAME);
+        if dry_run {
+            println!("Would write manifest {}", path.display());
+            continue;
+        }
+        let manifest = manifest_json(record, &folder_files(folder)?);
+        fs::write(&path, serde_json::to_string_pretty(&manifest)?)?;
+    }
+    if !dry_run && !folders.is_empty() {
+        println!("Wrote {} sequence manifest(s).", folders.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SequenceType;
+
+    fn record(folder: &str) -> SequenceRecord {
+        SequenceRecord {
+            seq_type: SequenceType::Hdr(folder.to_string()),
+            rule: "bracket-shot-number",
+            detector: "generic",
+            camera: "OM-1".to_string(),
+            frames: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn editor_subfolders_count_towards_their_sequence() {
+        let records = [record("P1-HDR"), record("P7-HDR")];
+        let copy = |dest: &str| (PathBuf::from("card/x"), PathBuf::from(dest));
+        let copy_plan = [
+            copy("lib/RAW/2024/06/15/P1-HDR/P1.ORF"),
+            copy("lib/JPEG/2024/06/15/P1-HDR/P1.JPG"),
+            copy("lib/RAW/2024/06/15/P7-HDR/CaptureOne/Settings/P7.ORF.cos"),
+            copy("lib/RAW/2024/06/15/P9.ORF"),
+        ];
+        let folders: Vec<(PathBuf, &str)> = sequence_folders(&copy_plan, &[], &records)
+            .into_iter()
+            .map(|(folder, record)| (folder, record.seq_type.folder_name()))
+            .collect();
+        assert_eq!(
+            folders,
+            [
+                (PathBuf::from("lib/JPEG/2024/06/15/P1-HDR"), "P1-HDR"),
+                (PathBuf::from("lib/RAW/2024/06/15/P1-HDR"), "P1-HDR"),
+                (PathBuf::from("lib/RAW/2024/06/15/P7-HDR"), "P7-HDR"),
+            ]
+        );
+    }
+}
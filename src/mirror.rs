@@ -0,0 +1,253 @@
+//! Small-JPEG mirror of the library for phones and photo frames.
+//!
+//! With `--mirror <DIR>`, every JPEG imported in a run, and every preview extracted
+//! from a RAW file, is resized to `--mirror-size` pixels on the long edge, turned
+//! upright from its EXIF orientation and re-encoded at `--mirror-quality` into the
+//! same `YYYY/MM/DD/...` place under the mirror folder. The original's metadata is
+//! copied onto the small copy, without GPS with `--mirror-strip-gps`.
+
+use crate::is_jpeg_file;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde_json::{Value, json};
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Clone)]
+pub struct MirrorOptions {
+    pub dir: PathBuf,
+    /// Longest side of the small copies, in pixels; smaller photos are not enlarged.
+    pub long_edge: u32,
+    pub quality: u8,
+    pub strip_gps: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MirrorResult {
+    pub source: PathBuf,
+    pub dest: PathBuf,
+    /// "written", "exists" or "failed"
+    pub status: &'static str,
+    pub error: Option<String>,
+}
+
+impl MirrorResult {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "source": self.source,
+            "file": self.dest,
+            "status": self.status,
+            "error": self.error,
+        })
+    }
+}
+
+/// Place of the small copy of a library file: its path below `RAW/` or `JPEG/`, under
+/// the mirror folder.
+pub fn mirror_path(file: &Path, output_dir: &Path, mirror_dir: &Path) -> Option<PathBuf> {
+    let relative = ["JPEG", "RAW"]
+        .iter()
+        .find_map(|tree| file.strip_prefix(output_dir.join(tree)).ok())?;
+    Some(mirror_dir.join(relative).with_extension("jpg"))
+}
+
+/// Moves the small copies of library files that were moved into sequence folders along
+/// with them, so the mirror keeps following the library's final layout.
+pub fn follow_moves(
+    moves: &[(PathBuf, PathBuf)],
+    output_dir: &Path,
+    mirror_dir: &Path,
+    dry_run: bool,
+) -> Result<(), String> {
+    for (from, to) in moves {
+        let (Some(old), Some(new)) = (
+            mirror_path(from, output_dir, mirror_dir),
+            mirror_path(to, output_dir, mirror_dir),
+        ) else {
+            continue;
+        };
+        if !old.exists() || new.exists() {
+            continue;
+        }
+        if dry_run {
+            println!("Would move {} -> {}", old.display(), new.display());
+            continue;
+        }
+        if let Some(parent) = new.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        fs::rename(&old, &new).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn resize(source: &Path, dest: &Path, options: &MirrorOptions) -> Result<(), String> {
+    let mut decoder = ImageReader::open(source)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| e.to_string())?
+        .into_decoder()
+        .map_err(|e| e.to_string())?;
+    let orientation = decoder.orientation().map_err(|e| e.to_string())?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    image.apply_orientation(orientation);
+    if image.width().max(image.height()) > options.long_edge {
+        image = image.resize(options.long_edge, options.long_edge, FilterType::Lanczos3);
+    }
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let file = fs::File::create(dest).map_err(|e| e.to_string())?;
+    image
+        .to_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(
+            BufWriter::new(file),
+            options.quality,
+        ))
+        .map_err(|e| e.to_string())?;
+
+    // The pixels are upright now, so the orientation tag is left behind
+    let mut command = Command::new("exiftool");
+    command
+        .arg("-overwrite_original")
+        .arg("-TagsFromFile")
+        .arg(source)
+        .args([
+            "-all:all",
+            "--Orientation",
+            "--ThumbnailImage",
+            "--PreviewImage",
+        ]);
+    if options.strip_gps {
+        command.args(["--GPS:all", "--XMP-exif:GPS*"]);
+    }
+    let output = command.arg(dest).output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    if let Ok(mtime) = fs::metadata(source).and_then(|m| m.modified()) {
+        let _ = fs::File::open(dest).and_then(|f| f.set_modified(mtime));
+    }
+    Ok(())
+}
+
+/// Writes the small copy of every JPEG among `files` (library paths).
+pub fn write_mirror(
+    files: &[&PathBuf],
+    output_dir: &Path,
+    options: &MirrorOptions,
+    dry_run: bool,
+) -> Vec<MirrorResult> {
+    let jpegs: Vec<(&PathBuf, PathBuf)> = files
+        .iter()
+        .filter(|f| {
+            f.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(is_jpeg_file)
+        })
+        .filter_map(|f| Some((*f, mirror_path(f, output_dir, &options.dir)?)))
+        .collect();
+
+    let pb = ProgressBar::new(jpegs.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) Writing mirror...")
+            .expect("Failed to set progress bar style"),
+    );
+    let mut results: Vec<MirrorResult> = jpegs
+        .into_par_iter()
+        .map(|(source, dest)| {
+            let mut result = MirrorResult {
+                source: source.clone(),
+                dest,
+                status: "written",
+                error: None,
+            };
+            if result.dest.exists() {
+                result.status = "exists";
+            } else if dry_run {
+                println!(
+                    "Would write {}px copy of {} to {}",
+                    options.long_edge,
+                    source.display(),
+                    result.dest.display()
+                );
+            } else if let Err(e) = resize(source, &result.dest, options) {
+                result.status = "failed";
+                result.error = Some(e);
+            }
+            pb.inc(1);
+            result
+        })
+        .collect();
+    pb.finish_with_message("Mirror complete");
+    results.sort_by(|a, b| a.source.cmp(&b.source));
+
+    let count = |status: &str| results.iter().filter(|r| r.status == status).count();
+    println!(
+        "Mirror: {} written, {} already present, {} failed.",
+        count("written"),
+        count("exists"),
+        count("failed")
+    );
+    for result in results.iter().filter(|r| r.status == "failed") {
+        println!(
+            "  Failed to mirror {}: {}",
+            result.source.display(),
+            result.error.as_deref().unwrap_or("")
+        );
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch_dir, write};
+
+    #[test]
+    fn mirror_path_follows_the_library_tree() {
+        let output = Path::new("lib");
+        let mirror = Path::new("phone");
+        assert_eq!(
+            mirror_path(Path::new("lib/RAW/2024/06/15/P1.ORF"), output, mirror),
+            Some(PathBuf::from("phone/2024/06/15/P1.jpg"))
+        );
+        assert_eq!(
+            mirror_path(
+                Path::new("lib/JPEG/2024/06/15/P1_HDR/P1.JPG"),
+                output,
+                mirror
+            ),
+            Some(PathBuf::from("phone/2024/06/15/P1_HDR/P1.jpg"))
+        );
+        assert_eq!(mirror_path(Path::new("other/P1.JPG"), output, mirror), None);
+    }
+
+    #[test]
+    fn small_copies_follow_sequence_moves() {
+        let dir = scratch_dir("mirror-moves");
+        let output = dir.join("lib");
+        let mirror = dir.join("phone");
+        write(&mirror.join("2024/06/15"), "P1.jpg", "small");
+        let moves = [(
+            output.join("JPEG/2024/06/15/P1.JPG"),
+            output.join("JPEG/2024/06/15/P1_HDR/P1.JPG"),
+        )];
+
+        follow_moves(&moves, &output, &mirror, true).unwrap();
+        assert!(mirror.join("2024/06/15/P1.jpg").exists());
+
+        follow_moves(&moves, &output, &mirror, false).unwrap();
+        assert!(!mirror.join("2024/06/15/P1.jpg").exists());
+        assert_eq!(
+            fs::read_to_string(mirror.join("2024/06/15/P1_HDR/P1.jpg")).unwrap(),
+            "small"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Offline reverse geocoding of photo positions into place names.
+//!
+//! Places come from a GeoNames cities dump (`cities500.txt`, `cities15000.txt`, ...)
+//! given with `--places`. Country names are read from a `countryInfo.txt` next to it
+//! when present; otherwise the ISO country code is used. A photo is named after the
+//! nearest populated place within [`MAX_PLACE_DISTANCE_KM`].
+
+use crate::associate::{Associations, photo_base};
+use crate::xmp::{self, SidecarName};
+use crate::{is_jpeg_file, is_raw_file};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Photos further than this from every known place get no place name.
+pub const MAX_PLACE_DISTANCE_KM: f64 = 50.0;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    pub city: String,
+    pub country: String,
+}
+
+#[derive(Debug)]
+struct City {
+    name: String,
+    lat: f64,
+    lon: f64,
+    country_code: String,
+}
+
+/// Cities bucketed by whole degree of latitude and longitude.
+#[derive(Debug, Default)]
+pub struct Gazetteer {
+    cities: Vec<City>,
+    grid: HashMap<(i32, i32), Vec<usize>>,
+    countries: HashMap<String, String>,
+}
+
+fn cell(lat: f64, lon: f64) -> (i32, i32) {
+    (lat.floor() as i32, lon.floor() as i32)
+}
+
+pub(crate) fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+impl Gazetteer {
+    /// Builds a gazetteer from the text of a GeoNames cities dump and, optionally,
+    /// of `countryInfo.txt`.
+    pub fn parse(cities: &str, country_info: Option<&str>) -> Self {
+        let mut gazetteer = Gazetteer::default();
+        for line in cities.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 9 {
+                continue;
+            }
+            let (Ok(lat), Ok(lon)) = (fields[4].parse::<f64>(), fields[5].parse::<f64>()) else {
+                continue;
+            };
+            gazetteer
+                .grid
+                .entry(cell(lat, lon))
+                .or_default()
+                .push(gazetteer.cities.len());
+            gazetteer.cities.push(City {
+                name: fields[1].to_string(),
+                lat,
+                lon,
+                country_code: fields[8].to_string(),
+            });
+        }
+        for line in country_info.unwrap_or_default().lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() > 4 && !fields[0].is_empty() {
+                gazetteer
+                    .countries
+                    .insert(fields[0].to_string(), fields[4].to_string());
+            }
+        }
+        gazetteer
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let cities = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read places file {}: {}", path.display(), e))?;
+        let country_info = path
+            .parent()
+            .map(|dir| dir.join("countryInfo.txt"))
+            .and_then(|file| fs::read_to_string(file).ok());
+        Ok(Self::parse(&cities, country_info.as_deref()))
+    }
+
+    pub fn city_count(&self) -> usize {
+        self.cities.len()
+    }
+
+    /// Nearest place to the position, if one lies within [`MAX_PLACE_DISTANCE_KM`].
+    pub fn lookup(&self, lat: f64, lon: f64) -> Option<Place> {
+        let (row, col) = cell(lat, lon);
+        let city = (row - 1..=row + 1)
+            .flat_map(|r| (col - 1..=col + 1).map(move |c| (r, c)))
+            // Longitude wraps around at the antimeridian
+            .map(|(r, c)| (r, (c + 180).rem_euclid(360) - 180))
+            .filter_map(|key| self.grid.get(&key))
+            .flatten()
+            .map(|&index| {
+                let city = &self.cities[index];
+                (distance_km(lat, lon, city.lat, city.lon), city)
+            })
+            .filter(|(distance, _)| *distance <= MAX_PLACE_DISTANCE_KM)
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, city)| city)?;
+        Some(Place {
+            city: city.name.clone(),
+            country: self
+                .countries
+                .get(&city.country_code)
+                .cloned()
+                .unwrap_or_else(|| city.country_code.clone()),
+        })
+    }
+}
+
+/// Place of every group with a known position.
+pub fn resolve_places(
+    positions: &HashMap<String, (f64, f64)>,
+    gazetteer: &Gazetteer,
+    group_count: usize,
+) -> HashMap<String, Place> {
+    let places: HashMap<String, Place> = positions
+        .iter()
+        .filter_map(|(base, &(lat, lon))| Some((base.clone(), gazetteer.lookup(lat, lon)?)))
+        .collect();
+    println!(
+        "Resolved places for {} of {} photo group(s).",
+        places.len(),
+        group_count
+    );
+    places
+}
+
+/// Adds the place to the XMP sidecar of every copied photo, as `City`/`Country` and as
+/// keywords (without duplicating keywords already there).
+pub fn write_place_keywords(
+    copy_plan: &[(PathBuf, PathBuf)],
+    associations: &Associations,
+    places: &HashMap<String, Place>,
+    naming: SidecarName,
+    dry_run: bool,
+) {
+    let mut written = 0;
+    for (source, dest) in copy_plan {
+        let Some(filename) = source.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !is_raw_file(filename) && !is_jpeg_file(filename) {
+            continue;
+        }
+        let base = associations
+            .base_of(source)
+            .unwrap_or_else(|| photo_base(filename));
+        let Some(place) = places.get(base) else {
+            continue;
+        };
+        let sidecar = xmp::sidecar_for(dest, naming);
+        if dry_run {
+            println!(
+                "Would tag {} with {}, {}",
+                sidecar.display(),
+                place.city,
+                place.country
+            );
+            continue;
+        }
+        let mut args = vec!["-overwrite_original".to_string()];
+        for keyword in [&place.city, &place.country] {
+            args.push(format!("-XMP-dc:Subject-={}", keyword));
+            args.push(format!("-XMP-dc:Subject+={}", keyword));
+        }
+        args.push(format!("-XMP-photoshop:City={}", place.city));
+        args.push(format!("-XMP-photoshop:Country={}", place.country));
+        match Command::new("exiftool").args(&args).arg(&sidecar).output() {
+            Ok(output) if output.status.success() => written += 1,
+            Ok(output) => println!(
+                "Warning: failed to write place keywords to {}: {}",
+                sidecar.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => println!(
+                "Warning: failed to write place keywords to {}: {}",
+                sidecar.display(),
+                e
+            ),
+        }
+    }
+    if !dry_run {
+        println!("Wrote place keywords to {} sidecar(s).", written);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Columns of a GeoNames dump up to the country code, then some of the rest.
+    const CITIES: &str = "\
+2267057\tLisbon\tLisbon\tLisboa\t38.71667\t-9.13333\tP\tPPLC\tPT\t\t14\t\t\t\t517802
+2271071\tAlmada\tAlmada\t\t38.67902\t-9.1569\tP\tPPLA2\tPT\t\t15\t\t\t\t174030
+2521420\tBadajoz\tBadajoz\t\t38.87789\t-6.97061\tP\tPPLA2\tES\t\t57\t\t\t\t150376
+2198148\tWaiyevo\tWaiyevo\t\t-16.79\t179.97\tP\tPPL\tFJ
+broken line without enough fields
+1\tNowhere\tNowhere\t\tnorth\t-9.0\tP\tPPL\tPT
+";
+
+    const COUNTRY_INFO: &str = "\
+#ISO\tISO3\tISO-Numeric\tfips\tCountry
+PT\tPRT\t620\tPO\tPortugal
+ES\tESP\t724\tSP\tSpain
+";
+
+    fn gazetteer() -> Gazetteer {
+        Gazetteer::parse(CITIES, Some(COUNTRY_INFO))
+    }
+
+    fn city(place: Option<Place>) -> Option<String> {
+        place.map(|p| format!("{}, {}", p.city, p.country))
+    }
+
+    #[test]
+    fn distances_are_great_circle_kilometres() {
+        assert!((distance_km(38.0, -9.0, 39.0, -9.0) - 111.19).abs() < 0.01);
+        // Lisbon to Madrid
+        let km = distance_km(38.71667, -9.13333, 40.4165, -3.70256);
+        assert!((km - 503.0).abs() < 2.0, "{}", km);
+        assert_eq!(distance_km(10.0, 179.5, 10.0, 179.5), 0.0);
+    }
+
+    #[test]
+    fn lookup_finds_the_nearest_city() {
+        let gazetteer = gazetteer();
+        assert_eq!(gazetteer.city_count(), 4);
+        assert_eq!(
+            city(gazetteer.lookup(38.70, -9.14)),
+            Some("Lisbon, Portugal".to_string())
+        );
+        assert_eq!(
+            city(gazetteer.lookup(38.67, -9.16)),
+            Some("Almada, Portugal".to_string())
+        );
+    }
+
+    #[test]
+    fn lookup_searches_the_neighbouring_cells() {
+        let gazetteer = gazetteer();
+        // One cell north of Badajoz
+        assert_eq!(cell(39.02, -6.99), (39, -7));
+        assert_eq!(
+            city(gazetteer.lookup(39.02, -6.99)),
+            Some("Badajoz, Spain".to_string())
+        );
+        // Across the antimeridian, with no countryInfo entry for the code
+        assert_eq!(
+            city(gazetteer.lookup(-16.8, -179.95)),
+            Some("Waiyevo, FJ".to_string())
+        );
+    }
+
+    #[test]
+    fn lookup_finds_nothing_beyond_the_maximum_distance() {
+        let gazetteer = gazetteer();
+        // About 47 and 53 km west of Lisbon
+        assert!(gazetteer.lookup(38.71667, -9.68).is_some());
+        assert!(gazetteer.lookup(38.71667, -9.74).is_none());
+        assert!(gazetteer.lookup(38.7, -12.0).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+//! Embedded JPEG previews extracted from RAW files shot without a JPEG.
+//!
+//! Cameras embed a full-size JPEG in most RAW formats (`JpgFromRaw` on Nikon and
+//! Canon, `PreviewImage` on Olympus and Sony). With `--extract-previews`, every RAW
+//! file imported without a JPEG of the same shot gets that preview written to the
+//! matching place in the JPEG tree, with the RAW file's metadata copied onto it, so
+//! the JPEG tree has every shot.
+
+use crate::associate::Associations;
+use crate::{PairPolicy, is_jpeg_file, is_raw_file};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Embedded previews tried in order; the first full JPEG found is used.
+const PREVIEW_TAGS: [&str; 2] = ["JpgFromRaw", "PreviewImage"];
+
+/// Start of every JPEG stream.
+const JPEG_MAGIC: [u8; 2] = [0xFF, 0xD8];
+
+#[derive(Debug, Clone)]
+pub struct PreviewResult {
+    /// The imported RAW file the preview comes from.
+    pub raw: PathBuf,
+    pub dest: PathBuf,
+    /// "extracted", "exists", "no-preview" or "failed"
+    pub status: &'static str,
+    /// Tag the preview was read from.
+    pub tag: Option<&'static str>,
+    pub error: Option<String>,
+}
+
+impl PreviewResult {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "file": self.dest,
+            "derived": true,
+            "derived_from": self.raw,
+            "status": self.status,
+            "tag": self.tag,
+            "error": self.error,
+        })
+    }
+}
+
+/// Whether a group brings its own JPEG into the library, so its RAW needs no preview.
+pub fn imports_jpeg(
+    files: &[PathBuf],
+    associations: &Associations,
+    pair_policy: PairPolicy,
+) -> bool {
+    let names: Vec<&str> = files
+        .iter()
+        .filter(|f| !associations.is_derivative(f))
+        .filter_map(|f| f.file_name().and_then(|n| n.to_str()))
+        .collect();
+    let has_raw = names.iter().any(|n| is_raw_file(n));
+    names
+        .iter()
+        .any(|n| is_jpeg_file(n) && pair_policy.keeps(n, has_raw))
+}
+
+/// Place of the preview of `raw_dest` in the JPEG tree: the same folders and name.
+pub fn preview_path(raw_dest: &Path, raw_dir: &Path, jpeg_dir: &Path) -> Option<PathBuf> {
+    let relative = raw_dest.strip_prefix(raw_dir).ok()?;
+    Some(jpeg_dir.join(relative).with_extension("jpg"))
+}
+
+/// Those of `tags` that `exiftool -j` output lists for the file, in the order given.
+fn listed_tags(json: &[u8], tags: &[&'static str]) -> Vec<&'static str> {
+    let Ok(value) = serde_json::from_slice::<Value>(json) else {
+        return Vec::new();
+    };
+    let Some(listed) = value.get(0) else {
+        return Vec::new();
+    };
+    tags.iter()
+        .copied()
+        .filter(|tag| listed.get(tag).is_some())
+        .collect()
+}
+
+/// The first JPEG embedded in `raw` under one of `tags`, and the tag it was found in.
+/// Which of the tags the file has is read in one go; only those are extracted.
+pub fn embedded_jpeg(
+    raw: &Path,
+    tags: &[&'static str],
+) -> Result<Option<(&'static str, Vec<u8>)>, String> {
+    let listing = Command::new("exiftool")
+        .arg("-j")
+        .args(tags.iter().map(|tag| format!("-{}", tag)))
+        .arg(raw)
+        .output()
+        .map_err(|e| e.to_string())?;
+    for tag in listed_tags(&listing.stdout, tags) {
+        let output = Command::new("exiftool")
+            .arg("-b")
+            .arg(format!("-{}", tag))
+            .arg(raw)
+            .output()
+            .map_err(|e| e.to_string())?;
+        if output.status.success() && output.stdout.starts_with(&JPEG_MAGIC) {
+            return Ok(Some((tag, output.stdout)));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes the preview and returns the tag it came from, or the status and reason it
+/// wasn't written.
+fn extract(raw: &Path, dest: &Path) -> Result<&'static str, (&'static str, String)> {
+    let failed = |e: String| ("failed", e);
+    if let Some((tag, jpeg)) = embedded_jpeg(raw, &PREVIEW_TAGS).map_err(failed)? {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| failed(e.to_string()))?;
+        }
+        fs::write(dest, jpeg).map_err(|e| failed(e.to_string()))?;
+
+        // Capture date, camera, exposure and orientation, without the RAW's own previews
+        let output = Command::new("exiftool")
+            .arg("-overwrite_original")
+            .arg("-TagsFromFile")
+            .arg(raw)
+            .args([
+                "-all:all",
+                "--PreviewImage",
+                "--JpgFromRaw",
+                "--ThumbnailImage",
+            ])
+            .arg(dest)
+            .output()
+            .map_err(|e| failed(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        // Same mtime as the RAW, so incremental runs see the preview as old as its shot
+        if let Ok(mtime) = fs::metadata(raw).and_then(|m| m.modified()) {
+            let _ = fs::File::open(dest).and_then(|f| f.set_modified(mtime));
+        }
+        return Ok(tag);
+    }
+    Err(("no-preview", "no embedded JPEG preview".to_string()))
+}
+
+/// Writes the preview of every copied RAW file whose group imports no JPEG.
+pub fn extract_previews(
+    raw_copies: &[&PathBuf],
+    raw_dir: &Path,
+    jpeg_dir: &Path,
+    dry_run: bool,
+) -> Vec<PreviewResult> {
+    let pb = ProgressBar::new(raw_copies.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) Extracting previews...")
+            .expect("Failed to set progress bar style"),
+    );
+
+    let mut results: Vec<PreviewResult> = raw_copies
+        .par_iter()
+        .filter_map(|raw| {
+            let dest = preview_path(raw, raw_dir, jpeg_dir)?;
+            let mut result = PreviewResult {
+                raw: (*raw).clone(),
+                dest,
+                status: "extracted",
+                tag: None,
+                error: None,
+            };
+            if result.dest.exists() {
+                result.status = "exists";
+            } else if dry_run {
+                println!(
+                    "Would extract preview of {} to {}",
+                    raw.display(),
+                    result.dest.display()
+                );
+            } else {
+                match extract(raw, &result.dest) {
+                    Ok(tag) => result.tag = Some(tag),
+                    Err((status, e)) => {
+                        result.status = status;
+                        result.error = Some(e);
+                    }
+                }
+            }
+            pb.inc(1);
+            Some(result)
+        })
+        .collect();
+    pb.finish_with_message("Preview extraction complete");
+    results.sort_by(|a, b| a.raw.cmp(&b.raw));
+
+    let count = |status: &str| results.iter().filter(|r| r.status == status).count();
+    println!(
+        "Previews: {} extracted, {} already present, {} without a preview, {} failed.",
+        count("extracted"),
+        count("exists"),
+        count("no-preview"),
+        count("failed")
+    );
+    for result in results.iter().filter(|r| r.status == "failed") {
+        println!(
+            "  Failed to extract preview of {}: {}",
+            result.raw.display(),
+            result.error.as_deref().unwrap_or("")
+        );
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_are_tried_in_tag_order() {
+        let listing = br#"[{
+  "SourceFile": "P6150123.ORF",
+  "PreviewImage": "(Binary data 1843200 bytes, use -b option to extract)",
+  "JpgFromRaw": "(Binary data 6291456 bytes, use -b option to extract)"
+}]"#;
+        assert_eq!(
+            listed_tags(listing, &PREVIEW_TAGS),
+            ["JpgFromRaw", "PreviewImage"]
+        );
+        let listing = br#"[{ "SourceFile": "P6150123.ORF" }]"#;
+        assert!(listed_tags(listing, &PREVIEW_TAGS).is_empty());
+        assert!(listed_tags(b"", &PREVIEW_TAGS).is_empty());
+    }
+
+    #[test]
+    fn preview_mirrors_the_raw_path() {
+        let raw_dir = Path::new("out/RAW");
+        let jpeg_dir = Path::new("out/JPEG");
+        assert_eq!(
+            preview_path(
+                Path::new("out/RAW/2024/06/15/P1_HDR/P1.ORF"),
+                raw_dir,
+                jpeg_dir
+            ),
+            Some(PathBuf::from("out/JPEG/2024/06/15/P1_HDR/P1.jpg"))
+        );
+        assert_eq!(
+            preview_path(Path::new("elsewhere/P1.ORF"), raw_dir, jpeg_dir),
+            None
+        );
+    }
+}
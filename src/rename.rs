@@ -0,0 +1,299 @@
+//! Renaming of photo groups on import with a `--rename` template.
+//!
+//! The template produces a new base name per group, e.g.
+//! `{date:%Y%m%d_%H%M%S}_{subsec}_{camera}_{counter}`. Every file of the group keeps
+//! what followed the old base name (`.ORF`, `.ORF.xmp`, `-Edit.jpg`), so RAW, JPEG and
+//! sidecars keep sharing a name.
+
+use crate::{SequenceRecord, SequenceType, get_exif_capture_time, is_jpeg_file, is_raw_file};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+/// Tokens available in `--rename`.
+const RENAME_TOKENS: [&str; 5] = ["date", "subsec", "camera", "counter", "original"];
+
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+static RENAME_TOKEN_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{(\w+)(?::([^}]*))?\}").expect("Invalid regex for rename token")
+});
+
+/// Stand-in for `{counter}` until groups with the same name are numbered.
+const COUNTER_MARK: &str = "\u{0}";
+
+pub fn parse_rename(template: &str) -> Result<String, String> {
+    if template.contains('/') {
+        return Err("the name template can't contain '/'".to_string());
+    }
+    for captures in RENAME_TOKEN_RE.captures_iter(template) {
+        let token = &captures[1];
+        if !RENAME_TOKENS.contains(&token) {
+            return Err(format!(
+                "unknown token {{{}}} (expected one of {})",
+                token,
+                RENAME_TOKENS.map(|t| format!("{{{}}}", t)).join(", ")
+            ));
+        }
+        // chrono panics when formatting with an invalid specifier, so catch it here
+        if token == "date"
+            && let Some(format) = captures.get(2)
+            && StrftimeItems::new(format.as_str()).any(|item| item == Item::Error)
+        {
+            return Err(format!("invalid date format '{}'", format.as_str()));
+        }
+    }
+    Ok(template.to_string())
+}
+
+fn camera_name(exif: Option<&Value>) -> String {
+    let model = exif
+        .and_then(|e| e.get("Model"))
+        .and_then(|m| m.as_str())
+        .unwrap_or("unknown");
+    model
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// New base name of a group, with `{counter}` left as [`COUNTER_MARK`].
+fn render(template: &str, base: &str, time: DateTime<Utc>, exif: Option<&Value>) -> String {
+    RENAME_TOKEN_RE
+        .replace_all(template, |captures: &regex::Captures| match &captures[1] {
+            "date" => time
+                .format(captures.get(2).map_or(DEFAULT_DATE_FORMAT, |f| f.as_str()))
+                .to_string()
+                .replace('/', "-"),
+            "subsec" => time.format("%3f").to_string(),
+            "camera" => camera_name(exif),
+            "counter" => COUNTER_MARK.to_string(),
+            "original" => base.to_string(),
+            _ => String::new(),
+        })
+        .to_string()
+}
+
+/// Capture time the new name is built from: EXIF, else the photo's modification time.
+fn group_time(files: &[PathBuf], exif: Option<&Value>) -> Option<DateTime<Utc>> {
+    exif.and_then(get_exif_capture_time).or_else(|| {
+        let photo = files.iter().find(|f| {
+            f.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| is_raw_file(n) || is_jpeg_file(n))
+        })?;
+        fs::metadata(photo)
+            .and_then(|m| m.modified())
+            .ok()
+            .map(DateTime::<Utc>::from)
+    })
+}
+
+/// New base name of every group with a photo, and the groups whose name collides with
+/// another's (only possible without `{counter}`). Counters number all groups of the run
+/// that share a name, from 001, before any are skipped, and ignore the destination: they
+/// only break ties within a run, so the same card always gets the same names.
+pub fn plan_renames(
+    groups: &HashMap<String, Vec<PathBuf>>,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    template: &str,
+) -> (HashMap<String, String>, Vec<(String, String)>) {
+    let mut by_name: HashMap<String, Vec<(DateTime<Utc>, &String)>> = HashMap::new();
+    for (base, files) in groups {
+        let exif = exif_cache.get(base).map(|(_, exif)| exif);
+        let Some(time) = group_time(files, exif) else {
+            continue;
+        };
+        by_name
+            .entry(render(template, base, time, exif))
+            .or_default()
+            .push((time, base));
+    }
+
+    let mut renames = HashMap::new();
+    let mut collisions = Vec::new();
+    for (name, mut bases) in by_name {
+        bases.sort();
+        if !name.contains(COUNTER_MARK) && bases.len() > 1 {
+            for (_, base) in &bases[1..] {
+                collisions.push(((*base).clone(), name.clone()));
+            }
+        }
+        for (index, (_, base)) in bases.iter().enumerate() {
+            let new_base = name.replace(COUNTER_MARK, &format!("{:03}", index + 1));
+            renames.insert((*base).clone(), new_base);
+        }
+    }
+    (renames, collisions)
+}
+
+/// `filename` of a file in group `base`, renamed to `new_base`. Files whose name doesn't
+/// start with the group's base keep it.
+pub fn renamed_file_name(filename: &str, base: &str, new_base: &str) -> String {
+    match filename.strip_prefix(base) {
+        Some(rest) => format!("{}{}", new_base, rest),
+        None => filename.to_string(),
+    }
+}
+
+/// New names of sequence folders, which start with the base of one of their frames.
+pub fn renamed_sequence_folders(
+    sequences: &HashMap<String, SequenceType>,
+    renames: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut folders: HashMap<String, (usize, String)> = HashMap::new();
+    for (base, seq_type) in sequences {
+        let folder = seq_type.folder_name();
+        let Some(new_base) = renames.get(base) else {
+            continue;
+        };
+        if !folder.starts_with(base.as_str()) {
+            continue;
+        }
+        // The longest matching base wins, so P1000 doesn't rename P10001_HDR
+        let renamed = renamed_file_name(folder, base, new_base);
+        let entry = folders
+            .entry(folder.to_string())
+            .or_insert((base.len(), renamed.clone()));
+        if base.len() > entry.0 {
+            *entry = (base.len(), renamed);
+        }
+    }
+    folders
+        .into_iter()
+        .map(|(folder, (_, renamed))| (folder, renamed))
+        .collect()
+}
+
+/// Sequences and their records under the new names, for manifests, hooks and sidecars.
+pub fn apply_to_sequences(
+    sequences: &mut HashMap<String, SequenceType>,
+    records: &mut [SequenceRecord],
+    renames: &HashMap<String, String>,
+) {
+    let folders = renamed_sequence_folders(sequences, renames);
+    let rename_type = |seq_type: &mut SequenceType| {
+        if let Some(folder) = folders.get(seq_type.folder_name()) {
+            *seq_type = seq_type.with_folder_name(folder.clone());
+        }
+    };
+    sequences.values_mut().for_each(rename_type);
+    for record in records {
+        rename_type(&mut record.seq_type);
+        for frame in &mut record.frames {
+            if let Some(new_base) = renames.get(&frame.base) {
+                frame.base = new_base.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parse_rename_rejects_bad_templates() {
+        assert!(parse_rename("{date:%Y%m%d}_{subsec}_{camera}_{counter}").is_ok());
+        assert!(parse_rename("{original}").is_ok());
+        assert!(parse_rename("{date}/{counter}").is_err());
+        assert!(parse_rename("{lens}").is_err());
+        assert!(parse_rename("{date:%Q}").is_err());
+        assert!(parse_rename("{date:%Y%}").is_err());
+    }
+
+    #[test]
+    fn render_fills_tokens() {
+        let exif = json!({ "Model": "E-M1 Mark II" });
+        let time = at("2024-06-15T10:30:12.250Z");
+        assert_eq!(
+            render(
+                "{date}_{subsec}_{camera}_{original}",
+                "P6150123",
+                time,
+                Some(&exif)
+            ),
+            "20240615_103012_250_E-M1-Mark-II_P6150123"
+        );
+        // Slashes in a date format would make folders
+        assert_eq!(render("{date:%d/%m}", "P1", time, None), "15-06");
+        assert_eq!(render("{camera}", "P1", time, None), "unknown");
+        assert_eq!(
+            render("{date:%Y}_{counter}", "P1", time, None),
+            format!("2024_{}", COUNTER_MARK)
+        );
+    }
+
+    fn group(names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| PathBuf::from("/card").join(name))
+            .collect()
+    }
+
+    fn exif_at(file: &str, time: &str) -> (PathBuf, Value) {
+        (PathBuf::from(file), json!({ "DateTimeOriginal": time }))
+    }
+
+    #[test]
+    fn plan_renames_numbers_and_reports_collisions() {
+        let groups = HashMap::from([
+            ("P1".to_string(), group(&["P1.ORF"])),
+            ("P2".to_string(), group(&["P2.ORF"])),
+            ("P3".to_string(), group(&["P3.ORF"])),
+        ]);
+        let exif_cache = HashMap::from([
+            ("P1".to_string(), exif_at("P1.ORF", "2024:06:15 10:00:02")),
+            ("P2".to_string(), exif_at("P2.ORF", "2024:06:15 10:00:01")),
+            ("P3".to_string(), exif_at("P3.ORF", "2024:06:16 09:00:00")),
+        ]);
+
+        let (renames, collisions) = plan_renames(&groups, &exif_cache, "{date:%Y%m%d}_{counter}");
+        assert!(collisions.is_empty());
+        // Counters follow capture order
+        assert_eq!(renames["P2"], "20240615_001");
+        assert_eq!(renames["P1"], "20240615_002");
+        assert_eq!(renames["P3"], "20240616_001");
+
+        let (_, collisions) = plan_renames(&groups, &exif_cache, "{date:%Y%m%d}");
+        assert_eq!(collisions, [("P1".to_string(), "20240615".to_string())]);
+    }
+
+    #[test]
+    fn group_files_share_the_new_stem() {
+        let files = group(&["P1.ORF", "P1.JPG", "P1.ORF.xmp", "P1-Edit.jpg"]);
+        let groups = HashMap::from([("P1".to_string(), files.clone())]);
+        let exif_cache =
+            HashMap::from([("P1".to_string(), exif_at("P1.JPG", "2024:06:15 10:00:00"))]);
+        let (renames, _) = plan_renames(&groups, &exif_cache, "{date}_{original}");
+        let names: Vec<String> = files
+            .iter()
+            .map(|file| {
+                let name = file.file_name().unwrap().to_str().unwrap();
+                renamed_file_name(name, "P1", &renames["P1"])
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "20240615_100000_P1.ORF",
+                "20240615_100000_P1.JPG",
+                "20240615_100000_P1.ORF.xmp",
+                "20240615_100000_P1-Edit.jpg"
+            ]
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Machine-readable summary of an import run, written with `--report <FILE>`.
+
+use crate::bodies::Reading;
+use crate::filters::SkippedGroup;
+use crate::geotag::GeotagResult;
+use crate::hooks::HookResult;
+use crate::mirror::MirrorResult;
+use crate::preview::PreviewResult;
+use crate::sources::SourceSummary;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub run_id: String,
+    pub copied: usize,
+    pub moved: usize,
+    pub skipped_cutoff: usize,
+    pub skipped_existing: usize,
+    pub skipped_pair_policy: usize,
+    pub filtered: Vec<SkippedGroup>,
+    /// Sidecars found without a photo next to them.
+    pub orphans: Vec<PathBuf>,
+    /// Copies whose name changed with `--rename`: original file and new destination.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    pub hooks: Vec<HookResult>,
+    pub geotagged: Vec<GeotagResult>,
+    /// JPEG previews extracted from RAW files.
+    pub derived: Vec<PreviewResult>,
+    /// Small copies written to the `--mirror` library.
+    pub mirrored: Vec<MirrorResult>,
+    /// Shutter count readings of the bodies the run imported from.
+    pub bodies: Vec<(String, Reading)>,
+    /// Input directories with their counts.
+    pub sources: Vec<SourceSummary>,
+    /// Files not copied as an identical file went to the same place: the file and that one.
+    pub duplicates: Vec<(PathBuf, PathBuf)>,
+}
+
+impl RunReport {
+    pub fn to_json(&self) -> Value {
+        let hooks: Vec<Value> = self
+            .hooks
+            .iter()
+            .map(|result| {
+                json!({
+                    "hook": result.hook,
+                    "target": result.target,
+                    "command": result.command,
+                    "exit_code": result.exit_code,
+                    "success": result.success(),
+                    "stdout": result.stdout,
+                    "stderr": result.stderr,
+                })
+            })
+            .collect();
+
+        let filtered: Vec<Value> = self
+            .filtered
+            .iter()
+            .map(|skipped| json!({ "group": skipped.base, "reason": skipped.reason }))
+            .collect();
+
+        let renamed: Vec<Value> = self
+            .renamed
+            .iter()
+            .map(|(source, dest)| {
+                json!({
+                    "original": source.file_name().map(|n| n.to_string_lossy()),
+                    "source": source,
+                    "dest": dest,
+                })
+            })
+            .collect();
+        let bodies: Vec<Value> = self
+            .bodies
+            .iter()
+            .map(|(body, reading)| {
+                let mut reading = reading.to_json();
+                reading["body"] = json!(body);
+                reading
+            })
+            .collect();
+
+        json!({
+            "run_id": self.run_id,
+            "sources": self.sources.iter().map(SourceSummary::to_json).collect::<Vec<Value>>(),
+            "copied": self.copied,
+            "moved": self.moved,
+            "skipped_cutoff": self.skipped_cutoff,
+            "skipped_existing": self.skipped_existing,
+            "skipped_pair_policy": self.skipped_pair_policy,
+            "duplicates": self
+                .duplicates
+                .iter()
+                .map(|(file, same_as)| json!({ "file": file, "same_as": same_as }))
+                .collect::<Vec<Value>>(),
+            "filtered": filtered,
+            "orphans": self.orphans,
+            "renamed": renamed,
+            "hooks": hooks,
+            "geotagged": self.geotagged.iter().map(GeotagResult::to_json).collect::<Vec<Value>>(),
+            "derived": self.derived.iter().map(PreviewResult::to_json).collect::<Vec<Value>>(),
+            "mirrored": self.mirrored.iter().map(MirrorResult::to_json).collect::<Vec<Value>>(),
+            "bodies": bodies,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, serde_json::to_string_pretty(&self.to_json())?)?;
+        println!("Run report written to {}", path.display());
+        Ok(())
+    }
+}
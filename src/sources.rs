@@ -0,0 +1,336 @@
+//! Input directories of an import run.
+//!
+//! A run can read several cards or folders at once, given as positional arguments or
+//! with `--input [LABEL=]DIR`. Every input is a labelled source; their files are
+//! grouped, checked and planned together, so a file that two sources would copy to
+//! the same place is caught before anything is copied: an identical copy is skipped
+//! as a duplicate, a different photo with the same name is imported under a name with
+//! its source's label appended.
+
+use crate::associate::Associations;
+use crate::{collect_all_files_recursive, is_jpeg_file, is_raw_file};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct Source {
+    /// Name the source goes by in the output; the folder name unless given.
+    pub label: Option<String>,
+    pub dir: PathBuf,
+}
+
+impl Source {
+    pub fn label(&self) -> &str {
+        self.label.as_deref().unwrap_or_default()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "label": self.label, "dir": self.dir })
+    }
+}
+
+/// Parses `--input`: a directory, or `LABEL=DIR`.
+pub fn parse_input(value: &str) -> Result<Source, String> {
+    let (label, dir) = match value.split_once('=') {
+        Some((label, dir)) if !label.is_empty() && !label.contains(['/', '\\']) => {
+            (Some(label.to_string()), dir)
+        }
+        _ => (None, value),
+    };
+    if dir.is_empty() {
+        return Err("the input directory is empty".to_string());
+    }
+    Ok(Source {
+        label,
+        dir: PathBuf::from(dir),
+    })
+}
+
+/// Sources of a run: positional inputs, then `--input` ones. Unlabelled sources are
+/// named after their folder, numbered when two folders share a name.
+pub fn label_sources(
+    positional: &[String],
+    inputs: &[Source],
+) -> Result<Vec<Source>, Box<dyn std::error::Error>> {
+    let mut sources: Vec<Source> = positional
+        .iter()
+        .map(|dir| Source {
+            label: None,
+            dir: PathBuf::from(dir),
+        })
+        .chain(inputs.iter().cloned())
+        .collect();
+    for source in &sources {
+        if !source.dir.is_dir() {
+            return Err(format!("Input {} is not a directory", source.dir.display()).into());
+        }
+    }
+
+    let mut taken: HashMap<String, usize> = sources
+        .iter()
+        .filter_map(|s| Some((s.label.clone()?, 1)))
+        .collect();
+    for source in &mut sources {
+        if source.label.is_some() {
+            continue;
+        }
+        let name = fs::canonicalize(&source.dir)
+            .ok()
+            .and_then(|dir| dir.file_name().map(|n| n.to_string_lossy().to_string()))
+            .unwrap_or_else(|| source.dir.display().to_string());
+        let count = taken.entry(name.clone()).or_default();
+        *count += 1;
+        source.label = Some(if *count == 1 {
+            name
+        } else {
+            format!("{}#{}", name, count)
+        });
+    }
+    Ok(sources)
+}
+
+/// Index of the source `file` was collected from: the first one it is in.
+pub fn source_of(sources: &[Source], file: &Path) -> Option<usize> {
+    sources
+        .iter()
+        .position(|source| file.starts_with(&source.dir))
+}
+
+/// Every file of every source, and how many came from each.
+pub fn collect_files(sources: &[Source]) -> (Vec<PathBuf>, Vec<usize>) {
+    let mut all_files = Vec::new();
+    let mut counts = Vec::new();
+    let mut seen = HashSet::new();
+    for source in sources {
+        // A source inside another one, or the same folder given twice under different
+        // paths, adds nothing the first one doesn't have
+        let files: Vec<PathBuf> = collect_all_files_recursive(&source.dir)
+            .into_iter()
+            .filter(|file| seen.insert(fs::canonicalize(file).unwrap_or_else(|_| file.clone())))
+            .collect();
+        if sources.len() > 1 {
+            println!(
+                "Source {} ({}): {} file(s).",
+                source.label(),
+                source.dir.display(),
+                files.len()
+            );
+        }
+        counts.push(files.len());
+        all_files.extend(files);
+    }
+    (all_files, counts)
+}
+
+/// Whether every camera original of `part` has a byte-identical file of the same name
+/// in `first`.
+fn same_photos(first: &[PathBuf], part: &[PathBuf], associations: &Associations) -> bool {
+    part.iter()
+        .filter(|file| {
+            file.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| is_raw_file(n) || is_jpeg_file(n))
+                && !associations.is_derivative(file)
+        })
+        .all(|file| {
+            first.iter().any(|other| {
+                other.file_name() == file.file_name() && same_content(other, file).unwrap_or(false)
+            })
+        })
+}
+
+/// Label as it goes into file names: anything but letters, digits, `-` and `_` becomes
+/// `-`.
+fn file_label(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Splits groups with files from several sources, since cameras reuse file names:
+/// `P6150123.ORF` on two cards is two photos unless the files are identical. A
+/// source's files that are copies of the first source's stay in the group, to be
+/// skipped as duplicates; other ones become a group of their own, named after the
+/// base and the source's label, which their files are imported under. A number is
+/// added when that name is already a group's.
+pub fn separate_groups(
+    groups: &mut HashMap<String, Vec<PathBuf>>,
+    associations: &mut Associations,
+    sources: &[Source],
+) {
+    if sources.len() < 2 {
+        return;
+    }
+    let bases: Vec<String> = groups.keys().cloned().collect();
+    for base in bases {
+        let mut parts: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+        for file in &groups[&base] {
+            let index = source_of(sources, file).unwrap_or_default();
+            parts.entry(index).or_default().push(file.clone());
+        }
+        if parts.len() < 2 {
+            continue;
+        }
+        let mut parts = parts.into_iter();
+        let Some((_, first)) = parts.next() else {
+            continue;
+        };
+        let mut kept = first.clone();
+        for (index, part) in parts {
+            if same_photos(&first, &part, associations) {
+                kept.extend(part);
+                continue;
+            }
+            let label = file_label(sources[index].label());
+            let mut group = format!("{}_{}", base, label);
+            let mut number = 2;
+            while groups.contains_key(&group) {
+                group = format!("{}_{}-{}", base, label, number);
+                number += 1;
+            }
+            associations.rename_group(&part, &base, &group);
+            groups.insert(group, part);
+        }
+        groups.insert(base, kept);
+    }
+}
+
+/// Whether two files have the same bytes.
+pub fn same_content(a: &Path, b: &Path) -> std::io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let mut a = BufReader::new(fs::File::open(a)?);
+    let mut b = BufReader::new(fs::File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+    loop {
+        let read = a.read(&mut buf_a)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Files, copies and duplicates of one source in a run.
+#[derive(Debug, Clone)]
+pub struct SourceSummary {
+    pub source: Source,
+    pub files: usize,
+    pub copied: usize,
+    pub duplicates: usize,
+}
+
+impl SourceSummary {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "label": self.source.label,
+            "dir": self.source.dir,
+            "files": self.files,
+            "copied": self.copied,
+            "duplicates": self.duplicates,
+        })
+    }
+}
+
+/// Per-source counts of the final plan.
+pub fn summarize(
+    sources: &[Source],
+    file_counts: &[usize],
+    copies: &[(PathBuf, PathBuf)],
+    duplicates: &[(PathBuf, PathBuf)],
+) -> Vec<SourceSummary> {
+    let count = |files: &mut dyn Iterator<Item = &PathBuf>| {
+        let mut counts = vec![0; sources.len()];
+        for file in files {
+            if let Some(index) = source_of(sources, file) {
+                counts[index] += 1;
+            }
+        }
+        counts
+    };
+    let copied = count(&mut copies.iter().map(|(source, _)| source));
+    let duplicated = count(&mut duplicates.iter().map(|(source, _)| source));
+    sources
+        .iter()
+        .enumerate()
+        .map(|(index, source)| SourceSummary {
+            source: source.clone(),
+            files: file_counts.get(index).copied().unwrap_or_default(),
+            copied: copied[index],
+            duplicates: duplicated[index],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::associate::{AssociationRules, associate};
+    use crate::tests::{scratch_dir, write};
+
+    #[test]
+    fn same_folder_under_two_paths_is_collected_once() {
+        let dir = scratch_dir("sources-canonical");
+        let card = dir.join("card");
+        write(&card, "P1.ORF", "raw");
+        let sources = [
+            Source {
+                label: Some("a".to_string()),
+                dir: card.clone(),
+            },
+            Source {
+                label: Some("b".to_string()),
+                dir: card.join("..").join("card"),
+            },
+        ];
+        let (files, counts) = collect_files(&sources);
+        assert_eq!(files, [card.join("P1.ORF")]);
+        assert_eq!(counts, [1, 0]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn separated_groups_get_free_file_safe_names() {
+        let dir = scratch_dir("sources-separate");
+        let first = dir.join("a");
+        let second = dir.join("b");
+        write(&first, "P1.ORF", "first");
+        // A photo already named like the separated group would be
+        write(&first, "P1_my-card-2.ORF", "other");
+        let moved = write(&second, "P1.ORF", "second");
+        let sources = [
+            Source {
+                label: Some("a".to_string()),
+                dir: first,
+            },
+            Source {
+                label: Some("my card#2".to_string()),
+                dir: second,
+            },
+        ];
+        let (files, _) = collect_files(&sources);
+        let rules = AssociationRules::new(&[], &[], &[]).unwrap();
+        let (mut groups, mut associations) = associate(&files, &rules);
+        separate_groups(&mut groups, &mut associations, &sources);
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups["P1_my-card-2-2"][0], moved);
+        assert_eq!(associations.base_of(&moved), Some("P1_my-card-2-2"));
+        assert_eq!(groups["P1_my-card-2"].len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
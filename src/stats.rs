@@ -0,0 +1,450 @@
+//! `photo_sorter stats <library>`: what is in a library and how it was shot.
+//!
+//! Counts are per shot, so a RAW file and its JPEG count once; file counts and sizes
+//! are per file. The numbers come from the library index, which is brought up to date
+//! and written back first, so a library imported before the index existed is scanned
+//! once.
+
+use crate::library::{self, IndexEntry, shot_key};
+use crate::{SEQUENCE_LABELS, is_raw_file};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(clap::Args)]
+pub struct StatsArgs {
+    /// Library (output directory of earlier imports)
+    library: PathBuf,
+    /// Print the statistics as JSON
+    #[arg(long)]
+    json: bool,
+    /// Read every photo's metadata again, discarding the library index instead of
+    /// trusting it
+    #[arg(long)]
+    rescan: bool,
+}
+
+/// Upper bounds of the focal length buckets, in millimetres.
+const FOCAL_BUCKETS: [(f64, &str); 8] = [
+    (16.0, "<16 mm"),
+    (24.0, "16-24 mm"),
+    (35.0, "24-35 mm"),
+    (50.0, "35-50 mm"),
+    (85.0, "50-85 mm"),
+    (135.0, "85-135 mm"),
+    (200.0, "135-200 mm"),
+    (400.0, "200-400 mm"),
+];
+const FOCAL_OVER: &str = "400+ mm";
+
+/// ISO buckets: every full stop from 100 to 12800.
+const ISO_BUCKETS: [(u64, &str); 8] = [
+    (100, "<=100"),
+    (200, "200"),
+    (400, "400"),
+    (800, "800"),
+    (1600, "1600"),
+    (3200, "3200"),
+    (6400, "6400"),
+    (12800, "12800"),
+];
+const ISO_OVER_FROM: u64 = 25600;
+const ISO_OVER: &str = "25600+";
+
+const UNKNOWN: &str = "unknown";
+
+fn focal_bucket(focal_length: f64) -> &'static str {
+    FOCAL_BUCKETS
+        .iter()
+        .find(|(bound, _)| focal_length < *bound)
+        .map_or(FOCAL_OVER, |(_, name)| name)
+}
+
+/// The stop an ISO value is closest to from below, so ISO 250 counts as 200.
+fn iso_bucket(iso: u64) -> &'static str {
+    if iso >= ISO_OVER_FROM {
+        return ISO_OVER;
+    }
+    ISO_BUCKETS
+        .iter()
+        .rev()
+        .find(|(stop, _)| iso >= *stop)
+        .map_or(ISO_BUCKETS[0].1, |(_, name)| name)
+}
+
+/// Files and bytes of one kind.
+#[derive(Default)]
+struct Files {
+    count: usize,
+    bytes: u64,
+}
+
+impl Files {
+    fn add(&mut self, entry: &IndexEntry) {
+        self.count += 1;
+        self.bytes += entry.size;
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "files": self.count, "bytes": self.bytes })
+    }
+}
+
+/// Sequences of one type.
+#[derive(Default)]
+struct Sequences {
+    /// Shots of every sequence folder.
+    frames: Vec<usize>,
+    bytes: u64,
+}
+
+impl Sequences {
+    fn to_json(&self) -> Value {
+        json!({
+            "count": self.frames.len(),
+            "frames": self.frames.iter().sum::<usize>(),
+            "min_frames": self.frames.iter().min(),
+            "max_frames": self.frames.iter().max(),
+            "bytes": self.bytes,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    shots: usize,
+    per_year: BTreeMap<String, usize>,
+    per_month: BTreeMap<String, usize>,
+    per_day: BTreeMap<String, usize>,
+    bodies: BTreeMap<String, usize>,
+    lenses: BTreeMap<String, usize>,
+    focal_lengths: BTreeMap<&'static str, usize>,
+    isos: BTreeMap<&'static str, usize>,
+    raw: Files,
+    jpeg: Files,
+    sequences: BTreeMap<&'static str, Sequences>,
+    bytes_per_year: BTreeMap<String, u64>,
+}
+
+fn date_key(time: Option<DateTime<Utc>>, format: &str) -> String {
+    time.map_or_else(|| UNKNOWN.to_string(), |t| t.format(format).to_string())
+}
+
+impl Stats {
+    fn collect(index: &library::LibraryIndex) -> Self {
+        let mut stats = Stats::default();
+        let mut shots: BTreeMap<String, Vec<(&String, &IndexEntry)>> = BTreeMap::new();
+        for (key, entry) in &index.files {
+            shots.entry(shot_key(key)).or_default().push((key, entry));
+        }
+
+        // Sequence folders are told apart by their place, so RAW and JPEG halves merge
+        let mut sequence_folders: BTreeMap<(&str, String), (usize, u64)> = BTreeMap::new();
+        for (shot, files) in &shots {
+            stats.shots += 1;
+            // The RAW file speaks for the shot, as it carries the most metadata
+            let (_, entry) = files
+                .iter()
+                .find(|(key, _)| is_raw_file(key))
+                .unwrap_or(&files[0]);
+            let captured = files.iter().find_map(|(_, e)| e.captured);
+            *stats.per_year.entry(date_key(captured, "%Y")).or_default() += 1;
+            *stats
+                .per_month
+                .entry(date_key(captured, "%Y-%m"))
+                .or_default() += 1;
+            *stats
+                .per_day
+                .entry(date_key(captured, "%Y-%m-%d"))
+                .or_default() += 1;
+            *stats
+                .bodies
+                .entry(entry.body().unwrap_or_else(|| UNKNOWN.to_string()))
+                .or_default() += 1;
+            *stats
+                .lenses
+                .entry(entry.lens.clone().unwrap_or_else(|| UNKNOWN.to_string()))
+                .or_default() += 1;
+            *stats
+                .focal_lengths
+                .entry(entry.focal_length.map_or(UNKNOWN, focal_bucket))
+                .or_default() += 1;
+            *stats
+                .isos
+                .entry(entry.iso.map_or(UNKNOWN, iso_bucket))
+                .or_default() += 1;
+
+            let bytes: u64 = files.iter().map(|(_, e)| e.size).sum();
+            *stats
+                .bytes_per_year
+                .entry(date_key(captured, "%Y"))
+                .or_default() += bytes;
+            for (key, entry) in files {
+                if is_raw_file(key) {
+                    stats.raw.add(entry);
+                } else {
+                    stats.jpeg.add(entry);
+                }
+            }
+
+            if let Some((label, _)) = &entry.sequence
+                && let Some(label) = SEQUENCE_LABELS.iter().find(|l| *l == label)
+            {
+                let folder = shot.rsplit_once('/').map_or("", |(dir, _)| dir);
+                let sequence = sequence_folders
+                    .entry((label, folder.to_string()))
+                    .or_default();
+                sequence.0 += 1;
+                sequence.1 += bytes;
+            }
+        }
+        for ((label, _), (frames, bytes)) in sequence_folders {
+            let sequences = stats.sequences.entry(label).or_default();
+            sequences.frames.push(frames);
+            sequences.bytes += bytes;
+        }
+        stats
+    }
+
+    fn to_json(&self) -> Value {
+        let counts = |map: &BTreeMap<String, usize>| {
+            Value::Object(map.iter().map(|(k, v)| (k.clone(), json!(v))).collect())
+        };
+        let buckets = |names: &[&str], over: &str, map: &BTreeMap<&'static str, usize>| {
+            Value::Object(
+                names
+                    .iter()
+                    .chain([&over, &UNKNOWN])
+                    .filter_map(|name| Some((name.to_string(), json!(map.get(name)?))))
+                    .collect::<Map<_, _>>(),
+            )
+        };
+        let focal_names: Vec<&str> = FOCAL_BUCKETS.iter().map(|(_, n)| *n).collect();
+        let iso_names: Vec<&str> = ISO_BUCKETS.iter().map(|(_, n)| *n).collect();
+        json!({
+            "shots": self.shots,
+            "per_year": counts(&self.per_year),
+            "per_month": counts(&self.per_month),
+            "per_day": counts(&self.per_day),
+            "bodies": counts(&self.bodies),
+            "lenses": counts(&self.lenses),
+            "focal_lengths": buckets(&focal_names, FOCAL_OVER, &self.focal_lengths),
+            "iso": buckets(&iso_names, ISO_OVER, &self.isos),
+            "raw": self.raw.to_json(),
+            "jpeg": self.jpeg.to_json(),
+            "sequences": Value::Object(
+                self.sequences
+                    .iter()
+                    .map(|(label, s)| (label.to_string(), s.to_json()))
+                    .collect(),
+            ),
+            "bytes_per_year": Value::Object(
+                self.bytes_per_year
+                    .iter()
+                    .map(|(year, bytes)| (year.clone(), json!(bytes)))
+                    .collect(),
+            ),
+        })
+    }
+
+    fn print_table(&self) {
+        let section = |title: &str, rows: Vec<(String, String)>| {
+            println!("\n{}", title);
+            let width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+            for (key, value) in rows {
+                println!("  {:<width$}  {:>10}", key, value, width = width);
+            }
+        };
+        let counts = |map: &BTreeMap<String, usize>| -> Vec<(String, String)> {
+            map.iter()
+                .map(|(k, v)| (k.clone(), v.to_string()))
+                .collect()
+        };
+        let buckets = |names: &[&str], over: &str, map: &BTreeMap<&'static str, usize>| {
+            names
+                .iter()
+                .chain([&over, &UNKNOWN])
+                .filter_map(|name| Some((name.to_string(), map.get(name)?.to_string())))
+                .collect()
+        };
+        let by_count = |map: &BTreeMap<String, usize>| -> Vec<(String, String)> {
+            let mut rows: Vec<(&String, &usize)> = map.iter().collect();
+            rows.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            rows.into_iter()
+                .map(|(k, v)| (k.clone(), v.to_string()))
+                .collect()
+        };
+
+        println!(
+            "{} shot(s): {} RAW file(s) ({}), {} JPEG file(s) ({})",
+            self.shots,
+            self.raw.count,
+            human_bytes(self.raw.bytes),
+            self.jpeg.count,
+            human_bytes(self.jpeg.bytes)
+        );
+        section("Shots per year", counts(&self.per_year));
+        section("Shots per month", counts(&self.per_month));
+        section("Shots per day", counts(&self.per_day));
+        section("Camera bodies", by_count(&self.bodies));
+        section("Lenses", by_count(&self.lenses));
+        let focal_names: Vec<&str> = FOCAL_BUCKETS.iter().map(|(_, n)| *n).collect();
+        section(
+            "Focal lengths",
+            buckets(&focal_names, FOCAL_OVER, &self.focal_lengths),
+        );
+        let iso_names: Vec<&str> = ISO_BUCKETS.iter().map(|(_, n)| *n).collect();
+        section("ISO", buckets(&iso_names, ISO_OVER, &self.isos));
+        if !self.sequences.is_empty() {
+            println!("\nSequences");
+            for label in SEQUENCE_LABELS {
+                if let Some(s) = self.sequences.get(label) {
+                    println!(
+                        "  {:<12} {:>5} sequence(s), {} frame(s) ({}-{} each), {}",
+                        label,
+                        s.frames.len(),
+                        s.frames.iter().sum::<usize>(),
+                        s.frames.iter().min().unwrap_or(&0),
+                        s.frames.iter().max().unwrap_or(&0),
+                        human_bytes(s.bytes)
+                    );
+                }
+            }
+        }
+        section(
+            "Bytes per year",
+            self.bytes_per_year
+                .iter()
+                .map(|(year, bytes)| (year.clone(), human_bytes(*bytes)))
+                .collect(),
+        );
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn run(args: &StatsArgs, exiftool_version: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !args.library.is_dir() {
+        return Err(format!("Library {} is not a directory", args.library.display()).into());
+    }
+    // Nothing but the JSON goes to stdout with --json
+    if !args.json {
+        println!("Found exiftool version: {}", exiftool_version);
+    }
+    let (index, read, dropped) = library::refresh(&args.library, args.rescan)?;
+    let stats = Stats::collect(&index);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats.to_json())?);
+    } else {
+        println!(
+            "Library index: {} photo file(s), {} read from the files, {} removed.",
+            index.files.len(),
+            read,
+            dropped
+        );
+        stats.print_table();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn raw_and_jpeg_share_a_shot_key() {
+        assert_eq!(shot_key("RAW/2024/06/15/P1.ORF"), "2024/06/15/P1");
+        assert_eq!(shot_key("JPEG/2024/06/15/P1.JPG"), "2024/06/15/P1");
+        assert_eq!(
+            shot_key("RAW/2024/06/15/P1_HDR/2024.06.15_001.ORF"),
+            "2024/06/15/P1_HDR/2024.06.15_001"
+        );
+        assert_eq!(shot_key("P1.ORF"), "P1");
+    }
+
+    #[test]
+    fn buckets_count_from_below() {
+        assert_eq!(focal_bucket(12.0), "<16 mm");
+        assert_eq!(focal_bucket(16.0), "16-24 mm");
+        assert_eq!(focal_bucket(399.9), "200-400 mm");
+        assert_eq!(focal_bucket(400.0), "400+ mm");
+
+        assert_eq!(iso_bucket(64), "<=100");
+        assert_eq!(iso_bucket(250), "200");
+        assert_eq!(iso_bucket(6400), "6400");
+        assert_eq!(iso_bucket(8000), "6400");
+        assert_eq!(iso_bucket(20000), "12800");
+        assert_eq!(iso_bucket(25600), "25600+");
+        assert_eq!(iso_bucket(102400), "25600+");
+    }
+
+    fn entry(size: u64, day: u32, iso: Option<u64>, sequence: Option<&str>) -> IndexEntry {
+        IndexEntry {
+            size,
+            captured: NaiveDate::from_ymd_opt(2024, 6, day)
+                .and_then(|d| d.and_hms_opt(10, 0, 0))
+                .map(|t| t.and_utc()),
+            camera: Some("OM-1".to_string()),
+            serial: Some("BHP1".to_string()),
+            iso,
+            sequence: sequence.map(|label| (label.to_string(), String::new())),
+            ..IndexEntry::default()
+        }
+    }
+
+    #[test]
+    fn collect_counts_shots_once_and_files_each() {
+        let mut index = library::LibraryIndex::default();
+        for (key, entry) in [
+            ("RAW/2024/06/15/P1.ORF", entry(20, 15, Some(200), None)),
+            // The JPEG's ISO is not counted again
+            ("JPEG/2024/06/15/P1.JPG", entry(5, 15, Some(3200), None)),
+            ("JPEG/2024/06/16/P2.JPG", entry(5, 16, None, None)),
+            (
+                "RAW/2024/06/16/P3_HDR/P3.ORF",
+                entry(20, 16, Some(200), Some("HDR")),
+            ),
+            (
+                "RAW/2024/06/16/P3_HDR/P4.ORF",
+                entry(20, 16, Some(200), Some("HDR")),
+            ),
+            (
+                "JPEG/2024/06/16/P3_HDR/P3.JPG",
+                entry(5, 16, Some(200), Some("HDR")),
+            ),
+        ] {
+            index.files.insert(key.to_string(), entry);
+        }
+        let stats = Stats::collect(&index);
+
+        assert_eq!(stats.shots, 4);
+        assert_eq!(stats.per_day["2024-06-15"], 1);
+        assert_eq!(stats.per_day["2024-06-16"], 3);
+        assert_eq!(stats.bodies["OM-1 #BHP1"], 4);
+        assert_eq!(stats.isos["200"], 3);
+        assert_eq!(stats.isos[UNKNOWN], 1);
+        assert!(!stats.isos.contains_key("3200"));
+        assert_eq!((stats.raw.count, stats.raw.bytes), (3, 60));
+        assert_eq!((stats.jpeg.count, stats.jpeg.bytes), (3, 15));
+        assert_eq!(stats.bytes_per_year["2024"], 75);
+
+        let hdr = &stats.sequences["HDR"];
+        assert_eq!(hdr.frames, [2]);
+        assert_eq!(hdr.bytes, 45);
+    }
+}
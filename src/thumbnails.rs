@@ -0,0 +1,303 @@
+//! Thumbnails of imported photos, pre-generated where file managers look for them.
+//!
+//! Thumbnails follow the freedesktop.org Thumbnail Managing Standard: PNG files named
+//! after the MD5 of the photo's URI (or, in a shared repository, of its file name),
+//! carrying `Thumb::URI` and `Thumb::MTime` so a file manager can tell they are up to
+//! date. They go either into the user's cache (`~/.cache/thumbnails/`) or into a
+//! `.sh_thumbnails/` folder next to the photos, which every client of a network share
+//! can use. RAW files are thumbnailed from their embedded preview.
+
+use crate::is_raw_file;
+use crate::preview::embedded_jpeg;
+use clap::ValueEnum;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Embedded previews thumbnails are made from, smallest usable first.
+const THUMBNAIL_SOURCE_TAGS: [&str; 2] = ["PreviewImage", "JpgFromRaw"];
+
+/// Folder of a shared thumbnail repository, next to the photos.
+const SHARED_DIR: &str = ".sh_thumbnails";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ThumbnailStore {
+    /// The user's thumbnail cache ($XDG_CACHE_HOME/thumbnails)
+    Cache,
+    /// A .sh_thumbnails folder in every day or sequence folder
+    Shared,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ThumbnailSize {
+    /// 128 pixels
+    Normal,
+    /// 256 pixels
+    Large,
+    /// 512 pixels
+    XLarge,
+    /// 1024 pixels
+    XxLarge,
+}
+
+impl ThumbnailSize {
+    fn folder_name(self) -> &'static str {
+        match self {
+            ThumbnailSize::Normal => "normal",
+            ThumbnailSize::Large => "large",
+            ThumbnailSize::XLarge => "x-large",
+            ThumbnailSize::XxLarge => "xx-large",
+        }
+    }
+
+    fn pixels(self) -> u32 {
+        match self {
+            ThumbnailSize::Normal => 128,
+            ThumbnailSize::Large => 256,
+            ThumbnailSize::XLarge => 512,
+            ThumbnailSize::XxLarge => 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThumbnailOptions {
+    pub store: ThumbnailStore,
+    pub sizes: Vec<ThumbnailSize>,
+}
+
+/// Orientation of a photo as exiftool prints it ("Rotate 90 CW") or as its number.
+pub fn orientation_from_exif(exif: &Value) -> Option<Orientation> {
+    let value = exif.get("Orientation")?;
+    let number = match value.as_u64() {
+        Some(number) => number as u8,
+        None => match value.as_str()? {
+            "Horizontal (normal)" => 1,
+            "Mirror horizontal" => 2,
+            "Rotate 180" => 3,
+            "Mirror vertical" => 4,
+            "Mirror horizontal and rotate 270 CW" => 5,
+            "Rotate 90 CW" => 6,
+            "Mirror horizontal and rotate 90 CW" => 7,
+            "Rotate 270 CW" => 8,
+            _ => return None,
+        },
+    };
+    Orientation::from_exif(number)
+}
+
+/// `path` with every byte but unreserved URI characters and `/` percent-escaped.
+pub(crate) fn escape_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.!~*'()/".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+/// `file://` URI of an absolute path, escaped as the standard requires.
+fn file_uri(path: &Path) -> String {
+    format!("file://{}", escape_path(path))
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("thumbnails"))
+}
+
+/// Thumbnail file of `photo` (an absolute path) and the URI recorded in it.
+fn thumbnail_path(
+    photo: &Path,
+    store: ThumbnailStore,
+    size: ThumbnailSize,
+) -> Option<(PathBuf, String)> {
+    match store {
+        ThumbnailStore::Cache => {
+            let uri = file_uri(photo);
+            let name = format!("{:x}.png", md5::compute(&uri));
+            Some((cache_dir()?.join(size.folder_name()).join(name), uri))
+        }
+        ThumbnailStore::Shared => {
+            // Shared repositories key thumbnails by file name, so they survive the
+            // share being mounted elsewhere
+            let filename = photo.file_name()?.to_string_lossy().to_string();
+            let name = format!("{:x}.png", md5::compute(&filename));
+            let dir = photo.parent()?.join(SHARED_DIR).join(size.folder_name());
+            Some((dir.join(name), filename))
+        }
+    }
+}
+
+/// The photo, upright: JPEG files from their own orientation, RAW files from the
+/// embedded preview turned by the RAW's orientation.
+pub(crate) fn load(photo: &Path, orientation: Option<Orientation>) -> Result<DynamicImage, String> {
+    let filename = photo.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let image = if is_raw_file(filename) {
+        let (_, jpeg) = embedded_jpeg(photo, &THUMBNAIL_SOURCE_TAGS)?
+            .ok_or_else(|| "no embedded JPEG preview".to_string())?;
+        let mut image = image::load_from_memory(&jpeg).map_err(|e| e.to_string())?;
+        if let Some(orientation) = orientation {
+            image.apply_orientation(orientation);
+        }
+        image
+    } else {
+        let mut decoder = ImageReader::open(photo)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| e.to_string())?
+            .into_decoder()
+            .map_err(|e| e.to_string())?;
+        let orientation = decoder.orientation().map_err(|e| e.to_string())?;
+        let mut image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+        image.apply_orientation(orientation);
+        image
+    };
+    Ok(image)
+}
+
+fn write_png(
+    path: &Path,
+    image: &DynamicImage,
+    uri: &str,
+    mtime: u64,
+    size: u64,
+    private: bool,
+) -> Result<(), String> {
+    let dir = path.parent().ok_or("thumbnail path has no folder")?;
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o700));
+    }
+
+    let rgb = image.to_rgb8();
+    let mut png_bytes = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png_bytes, rgb.width(), rgb.height());
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        for (key, value) in [
+            ("Thumb::URI", uri.to_string()),
+            ("Thumb::MTime", mtime.to_string()),
+            ("Thumb::Size", size.to_string()),
+            ("Software", "photo_sorter".to_string()),
+        ] {
+            encoder
+                .add_text_chunk(key.to_string(), value)
+                .map_err(|e| e.to_string())?;
+        }
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer
+            .write_image_data(rgb.as_raw())
+            .map_err(|e| e.to_string())?;
+    }
+
+    // Written next to its final name and renamed, so readers never see half a file
+    let temp = path.with_extension(format!("png.{}", std::process::id()));
+    fs::write(&temp, png_bytes).map_err(|e| e.to_string())?;
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(&temp, fs::Permissions::from_mode(0o600));
+    }
+    fs::rename(&temp, path).map_err(|e| e.to_string())
+}
+
+/// Writes every requested thumbnail of one photo; returns how many were written.
+fn thumbnail(
+    photo: &Path,
+    orientation: Option<Orientation>,
+    options: &ThumbnailOptions,
+) -> Result<usize, String> {
+    let photo = fs::canonicalize(photo).map_err(|e| e.to_string())?;
+    let metadata = fs::metadata(&photo).map_err(|e| e.to_string())?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+
+    let mut image = load(&photo, orientation)?;
+    let mut sizes = options.sizes.clone();
+    // Largest first, so every size is scaled down from the one before
+    sizes.sort_by_key(|size| std::cmp::Reverse(size.pixels()));
+    sizes.dedup();
+    let mut written = 0;
+    for size in sizes {
+        let Some((path, uri)) = thumbnail_path(&photo, options.store, size) else {
+            return Err("no thumbnail cache folder (HOME is not set)".to_string());
+        };
+        if image.width().max(image.height()) > size.pixels() {
+            image = image.thumbnail(size.pixels(), size.pixels());
+        }
+        write_png(
+            &path,
+            &image,
+            &uri,
+            mtime,
+            metadata.len(),
+            options.store == ThumbnailStore::Cache,
+        )?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Writes the thumbnails of the imported photos (library paths, with the orientation
+/// of RAW files).
+pub fn write_thumbnails(
+    photos: &[(&PathBuf, Option<Orientation>)],
+    options: &ThumbnailOptions,
+    dry_run: bool,
+) {
+    if dry_run {
+        println!(
+            "Would write {} thumbnail(s) for {} photo(s).",
+            photos.len() * options.sizes.len(),
+            photos.len()
+        );
+        return;
+    }
+
+    let pb = ProgressBar::new(photos.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta}) Writing thumbnails...")
+            .expect("Failed to set progress bar style"),
+    );
+    let results: Vec<(&PathBuf, Result<usize, String>)> = photos
+        .par_iter()
+        .map(|(photo, orientation)| {
+            let result = thumbnail(photo, *orientation, options);
+            pb.inc(1);
+            (*photo, result)
+        })
+        .collect();
+    pb.finish_with_message("Thumbnails complete");
+
+    let written: usize = results.iter().filter_map(|(_, r)| r.as_ref().ok()).sum();
+    let failed: Vec<&(&PathBuf, Result<usize, String>)> =
+        results.iter().filter(|(_, r)| r.is_err()).collect();
+    println!(
+        "Thumbnails: {} written, {} photo(s) failed.",
+        written,
+        failed.len()
+    );
+    for (photo, result) in failed {
+        if let Err(e) = result {
+            println!("  Failed to thumbnail {}: {}", photo.display(), e);
+        }
+    }
+}
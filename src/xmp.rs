@@ -0,0 +1,337 @@
+//! XMP sidecars written next to imported RAW files.
+//!
+//! Sidecars follow the two naming conventions `determine_target_base` already sorts:
+//! `NAME.ORF.xmp` (darktable, digiKam) and `NAME.xmp` (Lightroom, Capture One). An
+//! existing sidecar in either form is updated in place; otherwise a new one is created
+//! in the form chosen with `--sidecar-name`.
+
+use crate::associate::{Associations, photo_base};
+use crate::events::Events;
+use crate::{SequenceType, get_exif_capture_time, is_jpeg_file, is_raw_file};
+use chrono::{DateTime, Duration, Utc};
+use clap::ValueEnum;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Naming of newly created sidecars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SidecarName {
+    /// NAME.xmp
+    Base,
+    /// NAME.ORF.xmp
+    Full,
+}
+
+/// Sidecar for `photo`: an existing `NAME.EXT.xmp` or `NAME.xmp` next to it, otherwise
+/// a new one named after `naming`.
+pub fn sidecar_for(photo: &Path, naming: SidecarName) -> PathBuf {
+    let mut full = photo.as_os_str().to_owned();
+    full.push(".xmp");
+    let full = PathBuf::from(full);
+    let base = photo.with_extension("xmp");
+    if full.exists() {
+        full
+    } else if base.exists() {
+        base
+    } else {
+        match naming {
+            SidecarName::Base => base,
+            SidecarName::Full => full,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct XmpOptions {
+    pub creator: Option<String>,
+    pub copyright: Option<String>,
+    /// Identifies the import run in the keywords of every sidecar it writes.
+    pub run_id: String,
+    pub naming: SidecarName,
+    /// Correction from the camera clock to UTC (`--gpx-time-offset`); when given, EXIF
+    /// dates are corrected and written as UTC.
+    pub time_offset: Option<Duration>,
+}
+
+/// Keywords describing one imported photo.
+fn keywords(
+    base: &str,
+    exif: Option<&Value>,
+    sequences: &HashMap<String, SequenceType>,
+    events: &Events,
+    run_id: &str,
+) -> Vec<String> {
+    let mut keywords = Vec::new();
+    if let Some(model) = exif
+        .and_then(|e| e.get("Model"))
+        .and_then(|m| m.as_str())
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+    {
+        keywords.push(model.to_string());
+    }
+    if let Some(seq_type) = sequences.get(base) {
+        keywords.push(seq_type.label().to_string());
+        keywords.push(seq_type.folder_name().to_string());
+    }
+    if let Some(event) = events.event_for(base)
+        && !event.name.is_empty()
+    {
+        keywords.push(event.name.clone());
+    }
+    keywords.push(run_id.to_string());
+    keywords
+}
+
+/// A capture date as written to the sidecar, with a zone when it is known to be UTC.
+#[derive(Debug, Clone, Copy)]
+enum SidecarDate {
+    CameraClock(DateTime<Utc>),
+    Utc(DateTime<Utc>),
+}
+
+/// The date the import went by: EXIF when valid, corrected to UTC when the camera
+/// clock's offset is known, else the file's mtime.
+fn sidecar_date(
+    exif: Option<&Value>,
+    source: &Path,
+    time_offset: Option<Duration>,
+) -> Option<SidecarDate> {
+    exif.and_then(get_exif_capture_time)
+        .map(|date| match time_offset {
+            Some(offset) => SidecarDate::Utc(date + offset),
+            None => SidecarDate::CameraClock(date),
+        })
+        .or_else(|| {
+            fs::metadata(source)
+                .and_then(|m| m.modified())
+                .ok()
+                .map(|mtime| SidecarDate::Utc(DateTime::<Utc>::from(mtime)))
+        })
+}
+
+fn sidecar_args(
+    keywords: &[String],
+    date: Option<SidecarDate>,
+    options: &XmpOptions,
+) -> Vec<String> {
+    let mut args = vec!["-overwrite_original".to_string()];
+    for keyword in keywords {
+        // Removing first keeps a keyword from being listed twice on a re-import
+        args.push(format!("-XMP-dc:Subject-={}", keyword));
+        args.push(format!("-XMP-dc:Subject+={}", keyword));
+    }
+    if let Some(creator) = &options.creator {
+        args.push(format!("-XMP-dc:Creator={}", creator));
+    }
+    if let Some(copyright) = &options.copyright {
+        args.push(format!("-XMP-dc:Rights={}", copyright));
+    }
+    if let Some(date) = date {
+        let date = match date {
+            SidecarDate::CameraClock(date) => date.format("%Y:%m:%d %H:%M:%S%.3f").to_string(),
+            SidecarDate::Utc(date) => date.format("%Y:%m:%d %H:%M:%S%.3f+00:00").to_string(),
+        };
+        args.push(format!("-XMP-exif:DateTimeOriginal={}", date));
+        args.push(format!("-XMP-photoshop:DateCreated={}", date));
+    }
+    args
+}
+
+/// Creates or updates the sidecar of every copied RAW file.
+pub fn write_sidecars(
+    copy_plan: &[(PathBuf, PathBuf)],
+    associations: &Associations,
+    exif_cache: &HashMap<String, (PathBuf, Value)>,
+    sequences: &HashMap<String, SequenceType>,
+    events: &Events,
+    options: &XmpOptions,
+    dry_run: bool,
+) {
+    let mut written = 0;
+    let mut failed = 0;
+    for (source, dest) in copy_plan {
+        let Some(filename) = source.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !is_raw_file(filename) {
+            continue;
+        }
+        let base = associations
+            .base_of(source)
+            .unwrap_or_else(|| photo_base(filename));
+        let exif = exif_cache.get(base).map(|(_, exif)| exif);
+        let sidecar = sidecar_for(dest, options.naming);
+        if dry_run {
+            println!("Would write XMP sidecar {}", sidecar.display());
+            continue;
+        }
+
+        let args = sidecar_args(
+            &keywords(base, exif, sequences, events, &options.run_id),
+            sidecar_date(exif, source, options.time_offset),
+            options,
+        );
+        match Command::new("exiftool").args(&args).arg(&sidecar).output() {
+            Ok(output) if output.status.success() => written += 1,
+            Ok(output) => {
+                failed += 1;
+                println!(
+                    "Warning: failed to write XMP sidecar {}: {}",
+                    sidecar.display(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!(
+                    "Warning: failed to write XMP sidecar {}: {}",
+                    sidecar.display(),
+                    e
+                );
+            }
+        }
+    }
+    if !dry_run {
+        println!("XMP sidecars: {} written, {} failed.", written, failed);
+    }
+}
+
+fn preserved_name_args(filename: &str) -> [String; 2] {
+    [
+        "-overwrite_original".to_string(),
+        format!("-XMP-xmpMM:PreservedFileName={}", filename),
+    ]
+}
+
+/// Records the original name of every renamed photo as `PreservedFileName` in its
+/// sidecar. A sidecar shared by a RAW and a JPEG file keeps the RAW file's name.
+pub fn write_preserved_file_names(
+    renamed: &[(PathBuf, PathBuf)],
+    naming: SidecarName,
+    dry_run: bool,
+) {
+    let mut photos: Vec<(&PathBuf, &PathBuf, &str)> = renamed
+        .iter()
+        .filter_map(|(source, dest)| {
+            let filename = source.file_name()?.to_str()?;
+            (is_raw_file(filename) || is_jpeg_file(filename)).then_some((source, dest, filename))
+        })
+        .collect();
+    photos.sort_by_key(|(_, _, filename)| !is_raw_file(filename));
+
+    let mut done: HashSet<PathBuf> = HashSet::new();
+    let mut written = 0;
+    for (_, dest, filename) in photos {
+        let sidecar = sidecar_for(dest, naming);
+        if !done.insert(sidecar.clone()) {
+            continue;
+        }
+        if dry_run {
+            println!(
+                "Would record original name {} in {}",
+                filename,
+                sidecar.display()
+            );
+            continue;
+        }
+        match Command::new("exiftool")
+            .args(preserved_name_args(filename))
+            .arg(&sidecar)
+            .output()
+        {
+            Ok(output) if output.status.success() => written += 1,
+            Ok(output) => println!(
+                "Warning: failed to record original name in {}: {}",
+                sidecar.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => println!(
+                "Warning: failed to record original name in {}: {}",
+                sidecar.display(),
+                e
+            ),
+        }
+    }
+    if !dry_run {
+        println!("Recorded original names in {} sidecar(s).", written);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    fn options(time_offset: Option<Duration>) -> XmpOptions {
+        XmpOptions {
+            creator: Some("Jo Doe".to_string()),
+            copyright: None,
+            run_id: "import-20240615T183000".to_string(),
+            naming: SidecarName::Base,
+            time_offset,
+        }
+    }
+
+    fn date() -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2024, 6, 15)
+            .and_then(|d| d.and_hms_milli_opt(10, 30, 0, 120))
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn keywords_are_removed_before_being_added() {
+        let keywords = ["OM-1".to_string(), "HDR".to_string()];
+        let args = sidecar_args(&keywords, None, &options(None));
+        assert_eq!(
+            args,
+            [
+                "-overwrite_original",
+                "-XMP-dc:Subject-=OM-1",
+                "-XMP-dc:Subject+=OM-1",
+                "-XMP-dc:Subject-=HDR",
+                "-XMP-dc:Subject+=HDR",
+                "-XMP-dc:Creator=Jo Doe",
+            ]
+        );
+    }
+
+    #[test]
+    fn dates_are_written_as_shot_or_in_utc() {
+        let args = sidecar_args(&[], Some(SidecarDate::CameraClock(date())), &options(None));
+        assert_eq!(
+            &args[2..],
+            [
+                "-XMP-exif:DateTimeOriginal=2024:06:15 10:30:00.120",
+                "-XMP-photoshop:DateCreated=2024:06:15 10:30:00.120",
+            ]
+        );
+        let exif = json!({
+            "DateTimeOriginal": "2024:06:15 10:30:00",
+            "SubSecTimeOriginal": "12",
+        });
+        let offset = Some(Duration::hours(-2));
+        let corrected = sidecar_date(Some(&exif), Path::new("missing.ORF"), offset);
+        let args = sidecar_args(&[], corrected, &options(offset));
+        assert_eq!(
+            args[2],
+            "-XMP-exif:DateTimeOriginal=2024:06:15 08:30:00.120+00:00"
+        );
+    }
+
+    #[test]
+    fn preserved_file_name_is_the_original_name() {
+        assert_eq!(
+            preserved_name_args("P6150123.ORF"),
+            [
+                "-overwrite_original",
+                "-XMP-xmpMM:PreservedFileName=P6150123.ORF"
+            ]
+        );
+    }
+}